@@ -0,0 +1,231 @@
+/* Attack tables
+ *
+ * Which squares a piece attacks from a given square. Step attacks (everything
+ * a piece does one square at a time) are computed once into a table, sliding
+ * attacks (lance, bishop, rook and the slides of horse and dragon) are walked
+ * along their rays and stop at the first occupied square, which is included.
+ *
+ * Directions are (file delta, rank delta) from black's point of view, where
+ * forward is towards rank a (rank delta -1); white's are turned around.
+ */
+
+use crate::bitboard::Bitboard;
+use crate::position::Position;
+use shogi_core::{Color, Piece, PieceKind, Square};
+use std::sync::OnceLock;
+
+
+// a list of (file delta, rank delta)
+type Directions = &'static [(i8, i8)];
+
+
+const GOLD_STEPS: &[(i8, i8)] = &[(-1, -1), (0, -1), (1, -1), (-1, 0), (1, 0), (0, 1)];
+const SILVER_STEPS: &[(i8, i8)] = &[(-1, -1), (0, -1), (1, -1), (-1, 1), (1, 1)];
+const KNIGHT_STEPS: &[(i8, i8)] = &[(-1, -2), (1, -2)];
+const PAWN_STEPS: &[(i8, i8)] = &[(0, -1)];
+const ORTHOGONAL: &[(i8, i8)] = &[(0, -1), (-1, 0), (1, 0), (0, 1)];
+const DIAGONAL: &[(i8, i8)] = &[(-1, -1), (1, -1), (-1, 1), (1, 1)];
+const KING_STEPS: &[(i8, i8)] = &[(-1, -1), (0, -1), (1, -1), (-1, 0), (1, 0), (-1, 1), (0, 1), (1, 1)];
+const FORWARD: &[(i8, i8)] = &[(0, -1)];
+const NONE: &[(i8, i8)] = &[];
+
+
+// (steps, slides) of a piece kind, from black's point of view
+fn movement(kind: PieceKind) -> (Directions, Directions) {
+    match kind {
+        PieceKind::Pawn => (PAWN_STEPS, NONE),
+        PieceKind::Lance => (NONE, FORWARD),
+        PieceKind::Knight => (KNIGHT_STEPS, NONE),
+        PieceKind::Silver => (SILVER_STEPS, NONE),
+        PieceKind::Gold | PieceKind::ProPawn | PieceKind::ProLance | PieceKind::ProKnight | PieceKind::ProSilver => (GOLD_STEPS, NONE),
+        PieceKind::Bishop => (NONE, DIAGONAL),
+        PieceKind::Rook => (NONE, ORTHOGONAL),
+        PieceKind::King => (KING_STEPS, NONE),
+        PieceKind::ProBishop => (ORTHOGONAL, DIAGONAL),
+        PieceKind::ProRook => (DIAGONAL, ORTHOGONAL),
+    }
+}
+
+
+// rank deltas are given for black, white moves the other way
+fn oriented(color: Color, (file_delta, rank_delta): (i8, i8)) -> (i8, i8) {
+    if color == Color::Black { (file_delta, rank_delta) } else { (file_delta, -rank_delta) }
+}
+
+
+// Piece::as_u8 runs 1..=14 (black) and 17..=30 (white)
+const PIECE_SLOTS: usize = 31;
+
+
+// step attacks of every piece on every square, indexed [piece][square]
+fn step_table() -> &'static Vec<[Bitboard; 81]> {
+    static TABLE: OnceLock<Vec<[Bitboard; 81]>> = OnceLock::new();
+    TABLE.get_or_init(|| {
+        let mut table = vec![[Bitboard::EMPTY; 81]; PIECE_SLOTS];
+        for color in Color::all() {
+            for kind in PieceKind::all() {
+                let piece = Piece::new(kind, color);
+                let (steps, _) = movement(kind);
+                for square in Square::all() {
+                    for &step in steps {
+                        let (file_delta, rank_delta) = oriented(color, step);
+                        if let Some(to) = square.shift(file_delta, rank_delta) {
+                            table[piece.as_u8() as usize][square.array_index()].set(to);
+                        }
+                    }
+                }
+            }
+        }
+        table
+    })
+}
+
+
+// squares reached sliding from `square` in one direction, up to and including the first blocker
+fn slide(square: Square, (file_delta, rank_delta): (i8, i8), occupied: Bitboard) -> Bitboard {
+    let mut result = Bitboard::EMPTY;
+    let mut current = square;
+    while let Some(next) = current.shift(file_delta, rank_delta) {
+        result.set(next);
+        if occupied.contains(next) {
+            break;
+        }
+        current = next;
+    }
+    result
+}
+
+
+// squares attacked by `piece` standing on `square` with the board occupancy `occupied`
+pub fn piece_attacks(piece: Piece, square: Square, occupied: Bitboard) -> Bitboard {
+    let mut result = step_table()[piece.as_u8() as usize][square.array_index()];
+    let (_, slides) = movement(piece.piece_kind());
+    for &direction in slides {
+        result |= slide(square, oriented(piece.color(), direction), occupied);
+    }
+    result
+}
+
+
+// squares strictly between two squares on a line, empty if they aren't on one
+pub fn between(a: Square, b: Square) -> Bitboard {
+
+    let file_delta = b.file() as i8 - a.file() as i8;
+    let rank_delta = b.rank() as i8 - a.rank() as i8;
+    if file_delta != 0 && rank_delta != 0 && file_delta.abs() != rank_delta.abs() {
+        return Bitboard::EMPTY;
+    }
+
+    let step = (file_delta.signum(), rank_delta.signum());
+    let mut result = Bitboard::EMPTY;
+    let mut current = a;
+    while let Some(next) = current.shift(step.0, step.1) {
+        if next == b {
+            break;
+        }
+        result.set(next);
+        current = next;
+    }
+    result
+}
+
+
+// Pieces of `color` attacking `square`. Moves are point symmetric between the colours,
+// so a piece attacks `square` exactly when the same piece of the other colour standing
+// on `square` would attack it back.
+pub fn attackers_to(pos: &Position, square: Square, color: Color, occupied: Bitboard) -> Bitboard {
+    let mut result = Bitboard::EMPTY;
+    for kind in PieceKind::all() {
+        let pieces = pos.piece_bitboard(Piece::new(kind, color)) & occupied;
+        if !pieces.is_empty() {
+            result |= piece_attacks(Piece::new(kind, color.flip()), square, occupied) & pieces;
+        }
+    }
+    result
+}
+
+
+// every square attacked by at least one piece of `color`
+pub fn attack_map(pos: &Position, color: Color) -> Bitboard {
+    let occupied = pos.occupied();
+    let mut result = Bitboard::EMPTY;
+    for square in pos.color_bitboard(color) {
+        if let Some(piece) = pos.piece_at(square) {
+            result |= piece_attacks(piece, square, occupied);
+        }
+    }
+    result
+}
+
+
+// how many pieces of `color` attack each square, indexed by Square::array_index
+pub fn attack_counts(pos: &Position, color: Color) -> [u8; 81] {
+    let occupied = pos.occupied();
+    let mut result = [0; 81];
+    for square in pos.color_bitboard(color) {
+        if let Some(piece) = pos.piece_at(square) {
+            for target in piece_attacks(piece, square, occupied) {
+                result[target.array_index()] += 1;
+            }
+        }
+    }
+    result
+}
+
+
+// Pieces of `color` that are the only thing between their own king and an enemy
+// slider aiming at it, so moving them off the line would expose the king.
+pub fn pinned_pieces(pos: &Position, color: Color) -> Bitboard {
+
+    let Some(king) = pos.king_square(color) else { return Bitboard::EMPTY };
+    let occupied = pos.occupied();
+    let mut result = Bitboard::EMPTY;
+
+    for kind in [PieceKind::Lance, PieceKind::Bishop, PieceKind::Rook, PieceKind::ProBishop, PieceKind::ProRook] {
+        let slider = Piece::new(kind, color.flip());
+        for square in pos.piece_bitboard(slider) {
+            // would it hit the king on an empty board?
+            if !piece_attacks(slider, square, Bitboard::EMPTY).contains(king) {
+                continue;
+            }
+            let blockers = between(square, king) & occupied;
+            if blockers.count() == 1 {
+                result |= blockers & pos.color_bitboard(color);
+            }
+        }
+    }
+    result
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attackers_agree_with_attack_maps() {
+        let pos = Position::from_sfen("l6nl/5+P1gk/2np1S3/p1p4Pp/3P2Sp1/1PPb2P1P/P5GS1/R8/LN4bKL w RGgsn5p 1");
+        for color in Color::all() {
+            let counts = pos.attack_counts(color);
+            let map = pos.attack_map(color);
+            for square in Square::all() {
+                let attackers = pos.attackers_to(square, color);
+                assert_eq!(attackers.count(), counts[square.array_index()] as u32);
+                assert_eq!(!attackers.is_empty(), map.contains(square));
+            }
+        }
+    }
+
+    #[test]
+    fn checkers_and_pins() {
+        let pos = Position::from_sfen("4k4/9/9/9/4r4/9/9/9/4K4 b - 1");
+        assert!(pos.is_in_check());
+        assert_eq!(pos.checkers(), Bitboard::from_square(Square::SQ_5E));
+
+        // the silver on 5e is pinned by the rook, the gold on 7g is not
+        let pos = Position::from_sfen("4k4/4r4/9/1b7/4S4/9/2G6/9/4K4 b - 1");
+        assert!(!pos.is_in_check());
+        assert_eq!(pos.pinned_pieces(Color::Black), Bitboard::from_square(Square::SQ_5E));
+        assert_eq!(pos.pinned_pieces(Color::White), Bitboard::EMPTY);
+    }
+}
@@ -0,0 +1,123 @@
+/* 128-bit bitboards
+ *
+ * One bit per square, bit index = Square::array_index(), i.e. files run 9..1
+ * in blocks of 9 bits:
+ *
+ *    bit  0 = 1a   bit  8 = 1i
+ *    bit  9 = 2a   ...
+ *    bit 72 = 9a   bit 80 = 9i
+ *
+ * The 47 high bits are always zero.
+ */
+
+use shogi_core::Square;
+use std::ops::{BitAnd, BitAndAssign, BitOr, BitOrAssign, BitXor, BitXorAssign, Not};
+
+
+const BOARD_MASK: u128 = (1 << 81) - 1;
+
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Bitboard(u128);
+
+
+impl Bitboard {
+
+    pub const EMPTY: Bitboard = Bitboard(0);
+    pub const FULL: Bitboard = Bitboard(BOARD_MASK);
+
+    pub fn from_square(square: Square) -> Bitboard {
+        Bitboard(1 << square.array_index())
+    }
+
+    pub fn contains(self, square: Square) -> bool {
+        self.0 & (1 << square.array_index()) != 0
+    }
+
+    pub fn set(&mut self, square: Square) {
+        self.0 |= 1 << square.array_index();
+    }
+
+    pub fn clear(&mut self, square: Square) {
+        self.0 &= !(1 << square.array_index());
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn count(self) -> u32 {
+        self.0.count_ones()
+    }
+
+    // lowest square of the set, if any
+    pub fn first(self) -> Option<Square> {
+        if self.0 == 0 {
+            return None;
+        }
+        Square::from_u8(self.0.trailing_zeros() as u8 + 1)
+    }
+
+    pub fn to_u128(self) -> u128 {
+        self.0
+    }
+}
+
+
+// iterates over the squares of the set, lowest bit first
+impl Iterator for Bitboard {
+    type Item = Square;
+
+    fn next(&mut self) -> Option<Square> {
+        let square = self.first()?;
+        self.0 &= self.0 - 1;
+        Some(square)
+    }
+}
+
+
+impl BitOr for Bitboard {
+    type Output = Bitboard;
+    fn bitor(self, rhs: Bitboard) -> Bitboard {
+        Bitboard(self.0 | rhs.0)
+    }
+}
+
+impl BitAnd for Bitboard {
+    type Output = Bitboard;
+    fn bitand(self, rhs: Bitboard) -> Bitboard {
+        Bitboard(self.0 & rhs.0)
+    }
+}
+
+impl BitXor for Bitboard {
+    type Output = Bitboard;
+    fn bitxor(self, rhs: Bitboard) -> Bitboard {
+        Bitboard(self.0 ^ rhs.0)
+    }
+}
+
+impl Not for Bitboard {
+    type Output = Bitboard;
+    fn not(self) -> Bitboard {
+        Bitboard(!self.0 & BOARD_MASK)
+    }
+}
+
+impl BitOrAssign for Bitboard {
+    fn bitor_assign(&mut self, rhs: Bitboard) {
+        self.0 |= rhs.0;
+    }
+}
+
+impl BitAndAssign for Bitboard {
+    fn bitand_assign(&mut self, rhs: Bitboard) {
+        self.0 &= rhs.0;
+    }
+}
+
+impl BitXorAssign for Bitboard {
+    fn bitxor_assign(&mut self, rhs: Bitboard) {
+        self.0 ^= rhs.0;
+    }
+}
@@ -0,0 +1,195 @@
+/* Castle (kakoi) recognition
+ *
+ * A shogi king is kept safe by building a castle around it, and the common
+ * castles are recognisable from where a handful of pieces stand. Each castle
+ * is a pattern written from black's side of the board for one wing: the
+ * square the king has to be on and the pieces that make up the walls, each
+ * with how much it matters (pawns in front count for less than the golds and
+ * silvers). Patterns are matched as written and mirrored to the other wing,
+ * and turned around for white.
+ *
+ *    completeness  share of the pattern (by weight) that is in place
+ *    integrity     1 with none of those pieces attacked by the opponent,
+ *                  down to 1/2 with all of them attacked
+ *
+ * The castle a king is in is the pattern it completes best (at least half
+ * of it). Its quality, completeness x integrity, times the castle's weight
+ * ([castle] in params.rs) is the castle term of the evaluation.
+ */
+
+use crate::position::Position;
+use shogi_core::{Color, Piece, PieceKind, Square};
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Castle {
+    Mino,
+    HighMino,
+    Ginkanmuri,
+    Anaguma,
+    Yagura,
+    Funagakoi,
+}
+
+
+impl Castle {
+
+    pub const COUNT: usize = 6;
+    pub const ALL: [Castle; Castle::COUNT] = [
+        Castle::Mino,
+        Castle::HighMino,
+        Castle::Ginkanmuri,
+        Castle::Anaguma,
+        Castle::Yagura,
+        Castle::Funagakoi,
+    ];
+
+    pub fn index(self) -> usize {
+        self as usize
+    }
+
+    // the name it has in parameter names
+    pub fn name(self) -> &'static str {
+        match self {
+            Castle::Mino => "mino",
+            Castle::HighMino => "high_mino",
+            Castle::Ginkanmuri => "ginkanmuri",
+            Castle::Anaguma => "anaguma",
+            Castle::Yagura => "yagura",
+            Castle::Funagakoi => "funagakoi",
+        }
+    }
+}
+
+
+// the king's square and the (kind, file, rank, weight) of every other piece, black's side
+struct Pattern {
+    castle: Castle,
+    king: (u8, u8),
+    pieces: &'static [(PieceKind, u8, u8, f32)],
+}
+
+
+use PieceKind::{Gold as G, Knight as N, Lance as L, Pawn as P, Silver as S};
+
+// ranks are numbered 1 (a) to 9 (i), black's camp is 7 to 9
+const PATTERNS: [Pattern; Castle::COUNT] = [
+    // hon mino: K 2h S 3h G 4i G 5h
+    Pattern { castle: Castle::Mino, king: (2, 8), pieces: &[
+        (S, 3, 8, 1.0), (G, 4, 9, 1.0), (G, 5, 8, 0.8), (P, 1, 7, 0.3), (P, 2, 7, 0.3), (P, 3, 7, 0.3),
+    ] },
+    // takamino: the 5h gold has come up to 4g behind a 4f pawn
+    Pattern { castle: Castle::HighMino, king: (2, 8), pieces: &[
+        (S, 3, 8, 1.0), (G, 4, 7, 1.0), (G, 4, 9, 0.8), (P, 4, 6, 0.4), (P, 3, 6, 0.3), (P, 2, 7, 0.3), (P, 1, 7, 0.2),
+    ] },
+    // silver crown: the silver above the king, a gold beside it
+    Pattern { castle: Castle::Ginkanmuri, king: (2, 8), pieces: &[
+        (S, 2, 7, 1.0), (G, 3, 7, 1.0), (G, 4, 9, 0.6), (N, 2, 9, 0.4), (P, 2, 6, 0.4), (P, 3, 6, 0.3), (P, 1, 7, 0.2),
+    ] },
+    // the king in the corner behind lance, knight, silver and gold
+    Pattern { castle: Castle::Anaguma, king: (1, 9), pieces: &[
+        (L, 1, 8, 0.8), (N, 2, 9, 0.8), (S, 2, 8, 1.0), (G, 3, 9, 1.0), (G, 3, 8, 0.6), (P, 1, 7, 0.3), (P, 2, 7, 0.3),
+    ] },
+    // kin yagura: K 8h S 7g G 7h G 6g
+    Pattern { castle: Castle::Yagura, king: (8, 8), pieces: &[
+        (S, 7, 7, 1.0), (G, 7, 8, 1.0), (G, 6, 7, 1.0), (P, 7, 6, 0.4), (P, 6, 6, 0.4), (P, 8, 7, 0.3), (P, 9, 7, 0.2),
+    ] },
+    // boat castle: K 7h S 6h G 5h G 6i
+    Pattern { castle: Castle::Funagakoi, king: (7, 8), pieces: &[
+        (G, 6, 9, 1.0), (G, 5, 8, 1.0), (S, 6, 8, 0.6), (P, 7, 7, 0.3), (P, 8, 7, 0.3),
+    ] },
+];
+
+
+// share of a pattern that has to be there before it counts as that castle
+const MIN_COMPLETENESS: f32 = 0.5;
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CastleInfo {
+    pub castle: Castle,
+    pub completeness: f32,
+    pub integrity: f32,
+}
+
+
+impl CastleInfo {
+    pub fn quality(&self) -> f32 {
+        self.completeness * self.integrity
+    }
+}
+
+
+// A pattern square as it is for `color`, on the wing written (or mirrored to the other).
+fn square_for(file: u8, rank: u8, color: Color, mirrored: bool) -> Square {
+    let file = if mirrored { 10 - file } else { file };
+    let (file, rank) = if color == Color::Black { (file, rank) } else { (10 - file, 10 - rank) };
+    Square::new(file, rank).expect("pattern squares are on the board")
+}
+
+
+// how well `pattern` (on one wing) is built around `color`'s king, None if the king isn't in it
+fn match_pattern(pos: &Position, color: Color, pattern: &Pattern, mirrored: bool) -> Option<CastleInfo> {
+
+    let (king_file, king_rank) = pattern.king;
+    if pos.king_square(color) != Some(square_for(king_file, king_rank, color, mirrored)) {
+        return None;
+    }
+
+    let attacked = pos.attack_map(color.flip());
+    let (mut total, mut present, mut pieces, mut under_attack) = (0.0, 0.0, 0, 0);
+    for &(kind, file, rank, weight) in pattern.pieces {
+        let square = square_for(file, rank, color, mirrored);
+        total += weight;
+        if pos.piece_at(square) == Some(Piece::new(kind, color)) {
+            present += weight;
+            pieces += 1;
+            if attacked.contains(square) {
+                under_attack += 1;
+            }
+        }
+    }
+
+    let integrity = if pieces == 0 { 1.0 } else { 1.0 - 0.5 * under_attack as f32 / pieces as f32 };
+    Some(CastleInfo { castle: pattern.castle, completeness: present / total, integrity })
+}
+
+
+// the castle `color`'s king is in, if any
+pub fn recognise(pos: &Position, color: Color) -> Option<CastleInfo> {
+    PATTERNS.iter()
+        .flat_map(|pattern| [false, true].map(|mirrored| match_pattern(pos, color, pattern, mirrored)))
+        .flatten()
+        .filter(|info| info.completeness >= MIN_COMPLETENESS)
+        .fold(None, |best: Option<CastleInfo>, info| match best {
+            Some(best) if best.completeness >= info.completeness => Some(best),
+            _ => Some(info),
+        })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recognises_castles_on_both_wings_and_sides() {
+        // black in a complete mino, white in a yagura missing its 9-file pawn
+        let pos = Position::from_sfen("9/1kg6/1psg5/p1pp5/9/9/6PPP/4G+rSK1/5G3 b - 1");
+        let black = recognise(&pos, Color::Black).unwrap();
+        assert_eq!((black.castle, black.completeness), (Castle::Mino, 1.0));
+        // the promoted rook on 4h attacks the silver and gold
+        assert!(black.integrity < 1.0);
+
+        let white = recognise(&pos, Color::White).unwrap();
+        assert_eq!(white.castle, Castle::Yagura);
+        assert!(white.completeness < 1.0 && white.completeness >= MIN_COMPLETENESS);
+
+        // the same castles with the colours swapped
+        let flipped = pos.flipped();
+        assert_eq!(recognise(&flipped, Color::White), Some(black));
+        assert_eq!(recognise(&flipped, Color::Black), Some(white));
+
+        assert_eq!(recognise(&Position::startpos(), Color::Black), None);
+    }
+}
@@ -7,1019 +7,891 @@
         * 2. Promoted Pieces
         * 3. Mobility
         * 4. King Vulnerability
+        * 5. Pieces in Hand
+        * 6. Material
+        * 7. Castles
+        * 8. King Safety
+        * 9. Structure (piece relationships)
     * 
+    * The weights are runtime parameters, see params.rs. Each has an opening and an
+    * endgame value, blended by the game phase of the position (phase.rs).
 
  */
 
 
-use crate::sfen as SFEN;
-use std::collections::HashMap;
-use shogi_legality_lite::{normal_from_candidates, is_legal_partial_lite, all_legal_moves_partial};
-use shogi_core::{Color, Square, PartialPosition, Piece, PieceKind, Move};
-
-
-// CONST WEIGHTS FOR PIECES IN HAND 
-const PAWN_HAND: u32 = 1;
-const LANCE_HAND: u32 = 18;
-const KNIGHT_HAND: u32 = 9;
-const SILVER_HAND: u32 = 15;
-const GOLD_HAND: u32 = 22;
-const ROOK_HAND: u32 = 18;
-const BISHOP_HAND: u32 = 16;
-
-// CONST WEIGHTS FOR EVAL FEATURE VARIATES
-const LANCE_MOBIL: u32 = 17 / 4;
-const ROOK_MOBIL: u32 = 16 / 4;
-const BISHOP_MOBIL: u32 = 17 / 4;
-const PROMOTED_PIECES: u32 = 27;
-const KING_VULN: u32 = 22 * 10;
+use crate::attacks;
+use crate::bitboard::Bitboard;
+use crate::castle::{self, Castle};
+use crate::king_safety::KingAttack;
+use crate::nnue;
+use crate::evalcache;
+use crate::params::{self, EvalParams, TaperedParams, DEFAULT_PARAMS};
+use crate::phase;
+use crate::pst;
+use crate::structure::Structure;
+use crate::position::{Position, HAND_KINDS};
+use shogi_core::{Color, Square, Piece, PieceKind};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+
+// a weighted feature as it goes into the fitness (which can't go below zero)
+fn weigh(value: f32) -> u32 {
+    value.round().max(0.0) as u32
+}
 
 
 /*
     ################################## 1. PIECE SQUARE TABLES ##################################
-    
-    REFERENCE FOR PROMOTED PIECES
-        +P / +p ==> Z / z
-        +L / +l ==> X / x
-        +N / +n ==> Y / y
-        +S / +s ==> Q / q
-        +B / +b ==> W / w
-        +R / +r ==> E / e
- */
 
+    The tables themselves (and loading other ones from a file) are in pst.rs.
+ */
 
-// CONST PST WEIGHTS
-fn pst() -> HashMap<&'static str, [i32; 81]> {
-    let pst: HashMap<&'static str, [i32; 81]> = {
-        let mut map = HashMap::new();
-        map.insert("P", [ 3,  19, 9,  4,  28, 0,  3,  31, 6,
-                          8,  1,  26, 0,  16, 0,  10, 20, 22,
-                          24, 16, 3,  3,  29, 8,  9,  30, 4,
-                          28, 3,  5,  17, 15, 3,  1,  0,  1,
-                          13, 3,  21, 29, 3,  24, 29, 1,  17,
-                          27, 14, 23, 12, 16, 18, 4,  27, 28,
-                          14, 14, 24, 17, 5,  22, 16, 26, 10,
-                          29, 28, 0,  29, 11, 6,  8,  31, 9,
-                          9,  19, 23, 6,  11, 7,  3,  30, 26]);
-
-        map.insert("L", [ 12, 3,  24, 25, 5,  13, 30, 29, 23,
-                          25, 14, 18, 26, 11, 18, 6,  18, 2,
-                          8,  26, 0,  3,  7,  4,  0,  22, 4,
-                          20, 27, 18, 16, 28, 6,  17, 1,  30,
-                          6,  12, 14, 14, 12, 28, 13, 9,  18,
-                          5,  25, 5,  18, 3,  18, 21, 0,  6,
-                          6,  15, 26, 26, 27, 2,  10, 15, 4,
-                          0,  7,  13, 21, 6,  2,  10, 19, 1,
-                          2,  19, 15, 12, 12, 2,  5,  19, 2]);
-
-        map.insert("N", [ 8,  3,  19, 27, 8,  0,  3,  29, 16,
-                          17, 22, 17, 12, 4,  13, 27, 28, 28,
-                          8,  9,  6,  15, 23, 21, 2,  14, 28,
-                          16, 16, 28, 12, 26, 24, 0,  13, 18,
-                          24, 24, 25, 15, 12, 29, 5,  7,  31,
-                          20, 10, 20, 26, 24, 29, 16, 16, 1,
-                          21, 23, 10, 27, 31, 2,  12, 12, 5,
-                          8,  27, 27, 7,  19, 20, 19, 14, 19,
-                          29, 4,  28, 8,  3,  7,  28, 0,  24]);
-
-        map.insert("S", [ 3,  1,  24, 30, 21, 15, 11, 24, 8,
-                          11, 0,  25, 29, 5,  11, 30, 29, 24,
-                          6,  30, 29, 18, 7,  14, 24, 23, 30,
-                          16, 4,  19, 12, 8,  29, 26, 0,  23,
-                          6,  22, 31, 10, 22, 7,  19, 2,  9,
-                          29, 6,  15, 21, 29, 13, 2,  1,  29,
-                          20, 28, 13, 2,  5,  0,  5,  31, 23,
-                          21, 26, 17, 6,  14, 1,  10, 22, 10,
-                          8,  19, 7,  10, 15, 24, 22, 11, 10]);
-
-        map.insert("G", [ 11, 22, 26, 18, 9,  14, 19, 16, 30,
-                          13, 6,  23, 3,  29, 2,  8,  18, 5,
-                          11, 26, 1,  5,  12, 1,  2,  13, 29,
-                          15, 2,  13, 5,  22, 3,  8,  21, 3,
-                          5,  0,  7,  1,  11, 2,  26, 1,  29,
-                          13, 5,  17, 10, 17, 26, 13, 6,  13,
-                          11, 23, 18, 21, 20, 19, 29, 0,  16,
-                          12, 8,  30, 14, 14, 15, 28, 2,  9,
-                          1,  21, 21, 3,  25, 20, 12, 0,  0]);
-
-        map.insert("R", [ 2,  2,  29, 8,  18, 20, 2,  22, 26,
-                          26, 6,  11, 28, 2,  13, 20, 29, 30,
-                          28, 23, 30, 6,  20, 6,  10, 4,  5,
-                          17, 10, 20, 20, 3,  20, 16, 11, 21,
-                          25, 9,  9,  6,  9,  26, 24, 5,  27,
-                          1,  10, 16, 4,  13, 14, 25, 26, 10,
-                          25, 9,  29, 9,  6,  4,  1,  31, 5,
-                          0,  30, 10, 28, 20, 28, 12, 22, 25,
-                          7,  11, 14, 13, 4,  12, 7,  12, 0]);
-
-        map.insert("B", [ 28, 2,  7,  2,  8,  7,  21, 25, 8,
-                          13, 5,  0,  12, 12, 14, 15, 14, 2,
-                          9,  25, 11, 18, 8,  29, 15, 21, 22,
-                          4,  23, 28, 10, 31, 13, 2,  1,  18,
-                          0,  25, 14, 22, 4,  1,  24, 18, 1,
-                          25, 17, 26, 6,  21, 3,  12, 5,  24,
-                          30, 3,  13, 16, 9,  23, 22, 3,  16,
-                          25, 29, 25, 4,  31, 9,  15, 1,  30,
-                          1,  13, 19, 8,  13, 6,  13, 24, 12]);
-
-        map.insert("W", [ 31, 30, 22, 4,  0,  2,  24, 21, 31,
-                          30, 22, 5,  2,  8,  4,  1,  18, 3,
-                          6,  30, 19, 2,  16, 28, 19, 24, 3,
-                          23, 22, 22, 7,  3,  17, 7,  26, 1,
-                          7,  23, 24, 29, 21, 30, 16, 2,  28,
-                          21, 27, 8,  30, 17, 15, 30, 20, 5,
-                          1,  11, 26, 28, 8,  8,  25, 26, 23,
-                          6,  8,  15, 0,  4,  29, 12, 30, 12,
-                          20, 12, 7,  24, 7,  27, 13, 10, 5]);
-
-        map.insert("E", [ 21, 0,  6,  13, 31, 28, 6,  2,  31,
-                          8,  19, 30, 2,  25, 0,  4,  9,  17,
-                          10, 28, 18, 2,  31, 6,  20, 18, 6,
-                          8,  24, 31, 6,  2,  25, 7,  20, 2,
-                          22, 17, 25, 23, 15, 18, 19, 0,  27,
-                          23, 20, 24, 31, 26, 11, 6,  19, 8,
-                          11, 0,  2,  7,  7,  2,  29, 31, 5,
-                          8,  10, 13, 6,  26, 19, 16, 25, 28,
-                          15, 30, 1,  30, 20, 25, 11, 31, 23]);
-        map
-    };
-    
-    pst // return the hashmap
 
+// PST value of `piece` standing on `square`
+pub fn pst_value(piece: Piece, square: Square) -> i32 {
+    pst::value(piece, square)
 }
 
 
-fn pst_parse(sfen: &str) -> Vec<char> {
+pub fn evaluate_piece_table(pos: &Position, color: Color) -> i32 {
 
-    let split: Vec<&str> = sfen.split_whitespace().collect();
-    let pieces = split[0];
-    // println!("{:?}", pieces);
-    let replaced = pieces.replace("/", "");
-    let clean = SFEN::convert_promoted_pieces(&replaced);
+    let mut score = 0;
 
-    let mut result = Vec::new();
-    for ch in clean.chars() {
-        if ch.is_digit(10) {
-            let count = ch.to_digit(10).unwrap();
-            for _ in 0..count {
-                result.push('*');
-            }
-        } else {
-            result.push(ch);
-        }
+    for square in pos.color_bitboard(color) {
+        score += pst_value(pos.piece_at(square).unwrap(), square);
     }
-    
-    result
 
-} 
+    score
 
+}
 
-pub fn evaluate_piece_table(mut sfen: &str, color: &str) -> i32 {
-    
-    let mut white_score = 0;
-    let mut black_score = 0;
-
-    let pst_map = pst();
-    let mut sfen_vec = Vec::<char>::new();
-
-    if color == "black" {
-        let clean = SFEN::convert_promoted_pieces(&sfen);
-        let case_flip = SFEN::flip_case(&clean); // swaps lowercase with uppercase
-        let flipped = SFEN::flip(&case_flip); // actual board flip
-        //println!("SFEN: {:?}", flipped);
-        //view::display_sfen(&flipped);
-        sfen_vec = pst_parse(&flipped);
-    } else if color == "white" {
-        //println!("SFEN: {:?}", sfen);
-        //view::display_sfen(sfen);
-        sfen_vec = pst_parse(sfen);
-    }
-    
 
-    let mut index = 0;
-    for i in sfen_vec {
+//   #################################### 2. PROMOTED PIECES ####################################
 
-        if i == 'p'{
-            let value = pst_map["P"][index];
-            if color == "black" {
-                black_score += value
-            } else if color == "white" {
-                white_score += value;
-            }
-        
-        } else if i == 'l' {
-            let value = pst_map["L"][index];
-            if color == "black" {
-                black_score += value
-            } else if color == "white" {
-                white_score += value;
-            }
 
-        } else if i == 'n' {
-            let value = pst_map["N"][index];
-            if color == "black" {
-                black_score += value
-            } else if color == "white" {
-                white_score += value;
-            }
+// number of promoted pieces, returned as (white, black)
+pub fn promoted_pieces(pos: &Position) -> (u32, u32) {
 
-        } else if i == 's' {
-            let value = pst_map["S"][index];
-            if color == "black" {
-                black_score += value
-            } else if color == "white" {
-                white_score += value;
-            }
+    let promoted = [PieceKind::ProPawn, PieceKind::ProLance, PieceKind::ProKnight, PieceKind::ProSilver, PieceKind::ProBishop, PieceKind::ProRook]
+        .iter()
+        .fold(Bitboard::EMPTY, |bb, &kind| bb | pos.kind_bitboard(kind));
 
-        } else if i == 'g' {
-            let value = pst_map["G"][index];
-            if color == "black" {
-                black_score += value
-            } else if color == "white" {
-                white_score += value;
-            }
+    let num_black_pieces = (promoted & pos.color_bitboard(Color::Black)).count();
+    let num_white_pieces = (promoted & pos.color_bitboard(Color::White)).count();
 
-        } else if i == 'r' {
-            let value = pst_map["R"][index];
-            if color == "black" {
-                black_score += value
-            } else if color == "white" {
-                white_score += value;
-            }
+    (num_white_pieces, num_black_pieces)
 
-        } else if i == 'b' {
-            let value = pst_map["B"][index];
-            if color == "black" {
-                black_score += value
-            } else if color == "white" {
-                white_score += value;
-            }
+}
 
-        } else if i == 'e' /* PR rook */ {
-            let value = pst_map["E"][index];
-            if color == "black" {
-                black_score += value
-            } else if color == "white" {
-                white_score += value;
-            }
 
-        } else if i == 'w' /* PR bishop */ {
-            let value = pst_map["W"][index];
-            if color == "black" {
-                black_score += value
-            } else if color == "white" {
-                white_score += value;
-            }
+//   ####################################### 3. MOBILITY #######################################
 
-        } else if i == 'z' /* PR pawn */ {
-            let value = pst_map["G"][index];
-            if color == "black" {
-                black_score += value
-            } else if color == "white" {
-                white_score += value;
-            }
+/*
+    Every piece but the king counts the squares it attacks that aren't taken by its own pieces,
+    straight from the attack tables (attacks.rs): no moves are generated, so pins and checks
+    don't come into it. Each kind has its own [mobility] weight.
+
+    With safe mobility on (set_safe_mobility; the SafeMobility USI option, --safe-mobility on
+    the command line) a square doesn't count either when an enemy piece worth less than the
+    moving one attacks it: a rook can't really go where a pawn takes it. What's worth less
+    goes by the default material values, so the counts don't depend on the weights.
+ */
 
-        } else if i == 'x' /* PR lance */ {
-            let value = pst_map["G"][index];
-            if color == "black" {
-                black_score += value
-            } else if color == "white" {
-                white_score += value;
-            }
 
-        } else if i == 'y' /* PR knight */ {
-            let value = pst_map["G"][index];
-            if color == "black" {
-                black_score += value
-            } else if color == "white" {
-                white_score += value;
-            }
+static SAFE_MOBILITY: AtomicBool = AtomicBool::new(false);
 
-        } else if i == 'q' /* PR siLver */ {
-            let value = pst_map["G"][index];
-            if color == "black" {
-                black_score += value
-            } else if color == "white" {
-                white_score += value;
-            }
-    
-        } else if i == 'k' {
 
-        } else if i == 'K' {
-        
-        }
-
-        index += 1;
-    }
+pub fn safe_mobility() -> bool {
+    SAFE_MOBILITY.load(Ordering::Relaxed)
+}
 
-    if color == "white" {
-        //println!("{:?}", white_score);
-        white_score
-    } else if color == "black"{
-        //println!("{:?}", black_score);
-        black_score
-    } else {
-        0
-    }
 
+// Turns safe mobility on or off. Evaluations cached with the other setting are dropped.
+pub fn set_safe_mobility(on: bool) {
+    SAFE_MOBILITY.store(on, Ordering::Relaxed);
+    evalcache::global().clear();
 }
 
 
-//   #################################### 2. PROMOTED PIECES ####################################
+// for a piece of each kind (PieceKind::array_index), the squares `enemy`'s cheaper pieces
+// attack; nothing unless `safe`
+fn mobility_threats(pos: &Position, enemy: Color, safe: bool) -> [Bitboard; PieceKind::NUM] {
 
+    let mut threats = [Bitboard::EMPTY; PieceKind::NUM];
+    if !safe {
+        return threats;
+    }
 
-pub fn promoted_pieces(sfen: &str) -> (u32, u32) {
-    let sfen_chars: Vec<char> = sfen.chars().collect();
-    let mut num_black_pieces = 0;
-    let mut num_white_pieces = 0;
-    let mut index = 0;
+    let occupied = pos.occupied();
+    let mut by_kind = [Bitboard::EMPTY; PieceKind::NUM];
+    for square in pos.color_bitboard(enemy) {
+        let piece = pos.piece_at(square).unwrap();
+        if piece.piece_kind() != PieceKind::King {
+            by_kind[piece.piece_kind().array_index()] |= attacks::piece_attacks(piece, square, occupied);
+        }
+    }
 
-    for ch in sfen_chars.iter() {
-        if *ch == '+' {
-            let nxt = sfen_chars[index + 1];
-            // black
-            if nxt.is_ascii_uppercase() {
-                num_black_pieces += 1;
-            }
-            // white
-            if nxt.is_ascii_lowercase() {
-                num_white_pieces += 1;
+    let value = DEFAULT_PARAMS.material;
+    for (i, threat) in threats.iter_mut().enumerate() {
+        for (j, &attacked) in by_kind.iter().enumerate() {
+            if value[j] < value[i] {
+                *threat |= attacked;
             }
         }
-        index += 1;
     }
+    threats
+}
 
-    (num_black_pieces, num_white_pieces)
 
+fn reach(pos: &Position, piece: Piece, square: Square, threats: &[Bitboard; PieceKind::NUM]) -> u32 {
+    let targets = !pos.color_bitboard(piece.color()) & !threats[piece.piece_kind().array_index()];
+    (attacks::piece_attacks(piece, square, pos.occupied()) & targets).count()
 }
 
 
-//   ####################################### 3. MOBILITY #######################################
+// mobility of all of `color`'s pieces, added up by kind (PieceKind::array_index)
+pub fn piece_mobility(pos: &Position, color: Color) -> [u32; PieceKind::NUM] {
+    mobility_counts(pos, color, safe_mobility())
+}
 
 
-pub fn mobility(sfen: &str, coord: String) -> u32 {
-
-/*   
- *              SQUARE INDEXES
- *    FILE >>>  
- *    I   H   G   F   E   D   C   B   A  
- *    #   #   #   #   #   #   #   #   #  #  
- *    73  64  55  46  37  28  19  10  1  #  1  R
- *    74  65  56  47  38  29  20  11  2  #  2  A
- *    75  66  57  48  39  30  21  12  3  #  3  N
- *    76  67  58  49  40  31  22  13  4  #  4  K
- *    77  68  59  50  41  32  23  14  5  #  5  
- *    78  69  60  51  42  33  24  15  6  #  6  
- *    79  70  61  52  43  34  25  16  7  #  7  
- *    80  71  62  53  44  35  26  17  8  #  8  
- *    81  72  63  54  45  36  27  18  9  #  9    
- */
-    
-    // Convert the coordinate to file and rank indices
-    let file = coord.chars().nth(3).unwrap() as u8 - b'1' + 1;
-    let rank = coord.chars().nth(4).unwrap() as u8 - b'A' + 1;
-    //println!("file: {:?}", file);
-    //println!("rank: {:?}", rank);
-
-    // Parse the SFEN string into a position
-    let positions = SFEN::sfen_parse(sfen);// creates list of board squares and the pieces on them (if there are any)
-    let mut pos = SFEN::generate_pos(positions); // creates a "partial position" out of it
-    pos.side_to_move_set(SFEN::get_color(sfen)); // finalize the partial position
-
-    // Find the rook's square based on the given file and rank
-    let square = shogi_core::Square::new(file, rank).expect("Invalid coordinate");
-
-    // Print square index
-    // println!("rook sqr: {:?}", rook_square);
-
-    // Get the Bitboard of possible rook moves from the LiteLegalityChecker
-    let possible_moves = normal_from_candidates(&pos, square);
-
-    // Count the number of squares the rook can move to
-    let num_moves = possible_moves.count() as u32;
-
-    // Iterate over the possible moves and find the captured pieces
-    let mut captured_pieces = Vec::<(PieceKind, Color)>::new();
-    for to_square in possible_moves {
-        if let Some(captured_piece) = pos.piece_at(to_square) {
-            captured_pieces.push(captured_piece.to_parts());
+fn mobility_counts(pos: &Position, color: Color, safe: bool) -> [u32; PieceKind::NUM] {
+
+    let threats = mobility_threats(pos, color.flip(), safe);
+    let mut mobility = [0; PieceKind::NUM];
+    for square in pos.color_bitboard(color) {
+        let piece = pos.piece_at(square).unwrap();
+        if piece.piece_kind() != PieceKind::King {
+            mobility[piece.piece_kind().array_index()] += reach(pos, piece, square, &threats);
         }
     }
+    mobility
+
+}
 
-    // Return the count of possible moves and the captured pieces
-    //(num_moves, captured_pieces)
-    num_moves
 
+fn mobility_value(mobility: &[u32; PieceKind::NUM], params: &EvalParams) -> f32 {
+    mobility.iter().zip(&params.mobility).map(|(&count, weight)| count as f32 * weight).sum()
 }
 
 
-pub fn rook_mobility(sfen: &str) -> (u32, u32) {
+// weighted mobility, returned as (white, black)
+pub fn eval_mobility(pos: &Position, params: &EvalParams) -> (u32, u32) {
+    let value = |color: Color| weigh(mobility_value(&piece_mobility(pos, color), params));
+    (value(Color::White), value(Color::Black))
+}
 
-    let positions = SFEN::sfen_parse(sfen);// creates list of board squares and the pieces on them (if there are any)
-    let mut pos = SFEN::generate_pos(positions.clone()); // creates a "partial position" out of it
-    pos.side_to_move_set(SFEN::get_color(sfen));
-    
-    //println!("Positions: {:?}", positions);
 
-    let mut white_rook_mobil = 0;
-    let mut black_rook_mobil = 0;
-    
-    for sqr in &positions {
-       
-        if sqr.1 == "W_R" {
-            pos.side_to_move_set(Color::White);
-            let coord = &sqr.0;
-            //println!("COORD: {:?}", coord);
-            let sfen = pos.to_sfen_owned();
-            let mobil = mobility(&sfen, coord.to_string());
-            white_rook_mobil += mobil;
-            //println!("MOBILITY: {:?}", mobil);
-
-        } else if sqr.1 == "B_R" {
-            pos.side_to_move_set(Color::Black);
-            let coord = &sqr.0;
-            //println!("COORD: {:?}", coord);
-            let sfen = pos.to_sfen_owned();
-            let mobil = mobility(&sfen, coord.to_string());
-            black_rook_mobil += mobil;
-            //println!("MOBILITY: {:?}", mobil);
-        }
-    }
+//   ################################## 4. KING VULNERABILITY ##################################
+
+
+// The counts king vulnerability is made of, for `side` attacking its opponent's king:
+// [squares around the king `side` attacks, defenders covering them, pieces attacking
+// the king, safe escapes]. All zero when there is no king to attack.
+pub fn king_vuln_counts(pos: &Position, side: Color) -> [f32; 4] {
 
-    //println!("Final White Mobility: {:?}", white_rook_mobil);
-    //println!("Final Black Mobility: {:?}", black_rook_mobil);
-    (white_rook_mobil, black_rook_mobil)
+    let (color, enemy_color) = (side, side.flip());
+
+    // no king to attack (tsume positions have only the defender's)
+    let Some(king_square) = pos.king_square(enemy_color) else {
+        return [0.0; 4];
+    };
+
+    // --------------------------------------------------------------------------------------------------
+    // The (up to) 8 squares that surround the king
+    let king = Piece::new(PieceKind::King, enemy_color);
+    let squares = attacks::piece_attacks(king, king_square, Bitboard::EMPTY);
+
+    // --------------------------------------------------------------------------------------------------
+    // Number of squares around the king the attacker hits
+    let attacked = pos.attack_map(color);
+    let num_attackers = (squares & attacked).count() as f32;
+
+    // --------------------------------------------------------------------------------------------------
+    // Number of defending pieces (king included) covering the squares around the king
+    let defence = pos.attack_counts(enemy_color);
+    let num_defenders: f32 = squares.map(|s| defence[s.array_index()] as f32).sum();
+
+    // --------------------------------------------------------------------------------------------------
+    // Number of pieces attacking the king
+    let num_king_attackers = pos.attackers_to(king_square, color).count() as f32;
+
+    // --------------------------------------------------------------------------------------------------
+    // Number of escape routes: squares next to the king that aren't its own pieces
+    // and that nothing attacks once the king has left its square
+    let without_king = pos.occupied() & !Bitboard::from_square(king_square);
+    let num_escapes = (squares & !pos.color_bitboard(enemy_color))
+        .filter(|&s| attacks::attackers_to(pos, s, color, without_king).is_empty())
+        .count() as f32;
+
+    [num_attackers, num_defenders, num_king_attackers, num_escapes]
 
 }
 
 
-pub fn lance_mobility(sfen: &str) -> (u32, u32) {
+// How exposed `side`'s opponent's king is, from the board's attack maps:
+// attacked squares around it and pieces attacking it count up, defended squares
+// and safe escapes count down (weighted by the [king_vuln] parameters).
+pub fn enemy_king_vuln(pos: &Position, side: Color, params: &EvalParams) -> f32 {
+    king_vuln_weighted(king_vuln_counts(pos, side), params)
+}
 
-    let positions = SFEN::sfen_parse(sfen);// creates list of board squares and the pieces on them (if there are any)
-    let mut pos = SFEN::generate_pos(positions.clone()); // creates a "partial position" out of it
-    pos.side_to_move_set(SFEN::get_color(sfen));
-    
-    //println!("Positions: {:?}", positions);
 
-    let mut white_lance_mobil = 0;
-    let mut black_lance_mobil = 0;
-    
-    for sqr in &positions {
-       
-        if sqr.1 == "W_L" {
-            pos.side_to_move_set(Color::White);
-            let coord = &sqr.0;
-            //println!("COORD: {:?}", coord);
-            let sfen = pos.to_sfen_owned();
-            let mobil = mobility(&sfen, coord.to_string());
-            white_lance_mobil += mobil;
-            //println!("MOBILITY: {:?}", mobil);
-
-        } else if sqr.1 == "B_L" {
-            pos.side_to_move_set(Color::Black);
-            let coord = &sqr.0;
-            //println!("COORD: {:?}", coord);
-            let sfen = pos.to_sfen_owned();
-            let mobil = mobility(&sfen, coord.to_string());
-            black_lance_mobil += mobil;
-            //println!("MOBILITY: {:?}", mobil);
-        }
-    }
+// the counts of king_vuln_counts with the internal weightings applied
+pub fn king_vuln_weighted(counts: [f32; 4], params: &EvalParams) -> f32 {
+    let [num_attackers, num_defenders, num_king_attackers, num_escapes] = counts;
+    (num_attackers * params.vuln_attacked
+        - num_defenders * params.vuln_defenders
+        + num_king_attackers * params.vuln_king_attackers
+        - num_escapes * params.vuln_escapes)
+        .max(0.0)
+}
+
+
+//   ################################## 5. PIECES IN HAND ##################################
+
+
+// weighted pieces in hand (the [hand_bonus] parameters), returned as (white, black)
+pub fn eval_hand(pos: &Position, params: &EvalParams) -> (u32, u32) {
+
+    let hand_value = |color: Color| HAND_KINDS.iter()
+        .map(|&kind| pos.hand(color, kind) as f32 * params.hand_bonus[kind.array_index()])
+        .sum::<f32>();
 
-    (white_lance_mobil, black_lance_mobil)
+    (weigh(hand_value(Color::White)), weigh(hand_value(Color::Black)))
 
 }
 
 
-pub fn bishop_mobility(sfen: &str) -> (u32, u32) {
 
-    let positions = SFEN::sfen_parse(sfen);// creates list of board squares and the pieces on them (if there are any)
-    let mut pos = SFEN::generate_pos(positions.clone()); // creates a "partial position" out of it
-    pos.side_to_move_set(SFEN::get_color(sfen));
-    
-    //println!("Positions: {:?}", positions);
+//   ####################################### 6. MATERIAL #######################################
+
+/*
+    What the pieces themselves are worth. Every kind has its own value on the board, promoted
+    kinds included ([material]), and every kind that can be held has its own value in hand
+    ([material_hand]). The hand bonus above comes on top of this, for the flexibility of a
+    piece that can be dropped anywhere.
+ */
 
-    let mut white_bishop_mobil = 0;
-    let mut black_bishop_mobil = 0;
-    
-    for sqr in &positions {
-       
-        if sqr.1 == "W_B" {
-            pos.side_to_move_set(Color::White);
-            let coord = &sqr.0;
-            //println!("COORD: {:?}", coord);
-            let sfen = pos.to_sfen_owned();
-            let mobil = mobility(&sfen, coord.to_string());
-            white_bishop_mobil += mobil;
-            //println!("MOBILITY: {:?}", mobil);
-
-        } else if sqr.1 == "B_B" {
-            pos.side_to_move_set(Color::Black);
-            let coord = &sqr.0;
-            //println!("COORD: {:?}", coord);
-            let sfen = pos.to_sfen_owned();
-            let mobil = mobility(&sfen, coord.to_string());
-            black_bishop_mobil += mobil;
-            //println!("MOBILITY: {:?}", mobil);
-        }
-    }
 
-    (white_bishop_mobil, black_bishop_mobil)
+// material on the board and in hand, returned as (white, black)
+pub fn eval_material(pos: &Position, params: &EvalParams) -> (u32, u32) {
+
+    // the counts are kept by the position, only the weights are applied here
+    let inc = pos.incremental();
+    let material = |color: Color| {
+        let board: f32 = PieceKind::all().iter()
+            .map(|&kind| inc.pieces(color, kind) as f32 * params.material[kind.array_index()])
+            .sum();
+        let hand: f32 = HAND_KINDS.iter()
+            .map(|&kind| pos.hand(color, kind) as f32 * params.material_hand[kind.array_index()])
+            .sum();
+        board + hand
+    };
+
+    (weigh(material(Color::White)), weigh(material(Color::Black)))
 
 }
 
 
-//   ################################## 4. KING VULNERABILITY ##################################
 
+//   ####################################### 7. CASTLES #######################################
 
-// Function to check if a piece can attack a given square
-fn can_attack(pos: &PartialPosition, piece: Piece, src: Option<Square>, dst: Option<Square>) -> bool {
-    //println!("dst: {:?}", dst);
-    if let (Some(from), Some(to)) = (src, dst) {
-        let mv = Move::Normal {
-            from,
-            to,
-            promote: false,
-        };
-        //println!("move: {:?}", mv);
-        //if is_legal_partial_lite(pos, mv) {
-        //    println!("{:?}", mv);
-        //}
-        
-        return is_legal_partial_lite(&pos, mv);
-    
-    } else {
-       return false;
+/*
+    The castle each king sits in (castle.rs): its quality (0 to 1, how complete and unbroken it
+    is) times the [castle] weight of that castle.
+ */
+
+
+// quality of `color`'s castle, by Castle::index (at most one isn't zero)
+pub fn castle_quality(pos: &Position, color: Color) -> [f32; Castle::COUNT] {
+    let mut quality = [0.0; Castle::COUNT];
+    if let Some(info) = castle::recognise(pos, color) {
+        quality[info.castle.index()] = info.quality();
     }
+    quality
+}
+
 
+fn castle_value(quality: &[f32; Castle::COUNT], params: &EvalParams) -> f32 {
+    quality.iter().zip(&params.castle).map(|(q, value)| q * value).sum()
 }
 
 
-// Function to find the attackers of a given square
-fn attackers(pos: &PartialPosition, color: Color, square: Option<Square>) -> Vec<Option<Square>> {
-    //println!("Entered ATTACKERS!");
-    //println!("CURR TURN: {:?}", pos.side_to_move());
-    //println!("Color param: {:?}", color);
-    //println!("square (king): {:?}", square);
+// weighted castles, returned as (white, black)
+pub fn eval_castle(pos: &Position, params: &EvalParams) -> (u32, u32) {
+    let value = |color: Color| weigh(castle_value(&castle_quality(pos, color), params));
+    (value(Color::White), value(Color::Black))
+}
 
-    let mut result = Vec::new();
 
-    for file in 1..=9 {
-        for rank in 1..=9 {
-            let src_square = Square::new(file, rank);
-            //println!("square: {:?}", src_square);
-            if let Some(piece) = pos.piece_at(src_square.unwrap()) {
-                //println!("piece: {:?}", piece.color());
-                if piece.color() == color && can_attack(pos, piece, src_square, square) {
-                    result.push(src_square);
-                }
-            }
-        }
-    }
 
-    result
+//   ##################################### 8. KING SAFETY #####################################
 
+/*
+    The attack on the opponent's king over a wider zone, with drop threats and safe checks
+    (king_safety.rs). It grows with the square of the attack units, so it only gets big once
+    several pieces work together.
+ */
+
+
+// weighted king safety, returned as (white, black): what each side's attack is worth to it
+pub fn eval_king_safety(pos: &Position, params: &EvalParams) -> (u32, u32) {
+    let value = |color: Color| weigh(KingAttack::compute(pos, color).danger(params) * params.safety_weight);
+    (value(Color::White), value(Color::Black))
 }
 
 
-pub fn enemy_king_vuln(sfen: &str, side: Color) -> i32 {
 
-    const ATK_WEIGHT: i32 = 1;
-    const DEF_WEIGHT: f32 = 0.5;
-    const K_ATK_WEIGHT: i32 = 1;
-    const ESC_WEIGHT: i32 = 1;
+//   ##################################### 9. STRUCTURE #####################################
 
+/*
+    How the pieces stand together (structure.rs): pawn files, rooks on files without their pawn,
+    bishops blocked by their own pawns, golds and silvers connected to the king, knights open to
+    a pawn, lances backed by a rook and promoted pieces at the enemy king. Each count has its own
+    [structure] weight.
+ */
 
-    // Parse the SFEN string into a position
-    let positions = SFEN::sfen_parse(sfen);
-    let mut pos = SFEN::generate_pos(positions.clone());
-    pos.side_to_move_set(side);
-    //println!("Side to Move: {:?}", pos.side_to_move());
 
-    // Determine the color and enemy color based on the player's case
-    let player = pos.side_to_move();
-    let (color, enemy_color) = if player == Color::White {
-        (Color::White, Color::Black)
-    } else {
-        (Color::Black, Color::White)
-    };
+fn structure_value(structure: &Structure, params: &EvalParams) -> f32 {
+    structure.counts.iter().zip(&params.structure).map(|(&count, weight)| count as f32 * weight).sum()
+}
 
-    //println!("Color: {:?}", color);
-    //println!("Enemy Color: {:?}", enemy_color);
 
-    let king_square = pos.king_position(enemy_color);
-    //println!("King's Square: {:?}", king_square);
+// weighted structure, returned as (white, black)
+pub fn eval_structure(pos: &Position, params: &EvalParams) -> (u32, u32) {
+    let value = |color: Color| weigh(structure_value(&Structure::compute(pos, color), params));
+    (value(Color::White), value(Color::Black))
+}
 
-    let file = king_square.unwrap().file();
-    let rank = king_square.unwrap().rank();
 
-    // --------------------------------------------------------------------------------------------------
-    // Construct the list of 8 squares that surround the king
-    //println!("\n-------------Constructing the list of 8 squares that surround the king");
-    let files = (file.saturating_sub(1)..=file.saturating_add(1)).filter(|&f| 1 <= f && f <= 9);
-    let ranks = (rank.saturating_sub(1)..=rank.saturating_add(1)).filter(|&r| 1 <= r && r <= 9);
-    //println!("files: {:?}", files.clone().collect::<Vec<_>>());
-    //println!("ranks: {:?}", ranks.clone().collect::<Vec<_>>());
-    let squares: Vec<Option<Square>> = files
-        .flat_map(|f| ranks.clone().map(move |r| Square::new(f, r)))
-        .filter(|&s| s != king_square)
-        .collect();
-    //println!("SQUARES surrounding king: {:?}\n", squares);
 
+//   ############################ INCREMENTAL (PROMOTED, PST, MATERIAL) ############################
 
-    // --------------------------------------------------------------------------------------------------
-    // Calculate the number of pieces that can attack the squares surrounding the king
-    //println!("\n-------------Calculateing the number of pieces that can attack the squares surrounding the king");
-    pos.side_to_move_set(color);
-    let num_attackers = squares.iter().filter(|&&s| {
-        //println!("S: {:?}", s);
-        let attackers = attackers(&pos, color, s);
-        !attackers.is_empty()
-    }).count() as i32;
-    //println!("num_attackers: {:?}\n", num_attackers);
+/*
+    Promoted pieces, piece square tables and the number of pieces of each kind only depend on
+    what stands where, so Position keeps them up to date as pieces are put on and taken off the
+    board (Incremental::add_piece / remove_piece). Unmaking a move goes through the same calls,
+    so it restores them too. Hands are kept as counts by the position itself. Material and hand
+    weights can change at any time (params::set, the tuners), so only counts are kept and the
+    weights are applied when evaluating.
+
+    In debug builds evaluate_position checks the running sums against Incremental::compute.
+ */
 
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Incremental {
+    promoted: [u32; 2],                  // number of promoted pieces on the board, by colour
+    pst: [i32; 2],                       // piece square table sum, by colour
+    pieces: [[u8; PieceKind::NUM]; 2],  // pieces of each kind on the board, by colour
+}
 
-    // --------------------------------------------------------------------------------------------------
-    // Calculate the number of pieces that can defend the squares surrounding the king
-    //println!("\n-------------Calculateing the number of pieces that can defend the squares surrounding the king");
-    pos.side_to_move_set(enemy_color);
-    let mut total_defenders = Vec::<Option<Square>>::new();
-    for s in squares {
-        //println!("S: {:?}", s);
-        let defenders = attackers(&pos, enemy_color, s);
-        total_defenders.extend(defenders);   
-    };
-    //println!("Total defenders: {:?}", total_defenders);
-    let num_defenders = total_defenders.len() as i32; 
-    //println!("num_defenders: {:?}\n", num_defenders);
 
+impl Incremental {
 
-    // --------------------------------------------------------------------------------------------------
-    // Calculate the number of pieces attacking the king
-    //println!("\n-------------Calculateing the number of pieces attacking the king");
-    //println!("Side To Move: {:?}", color);
-    pos.side_to_move_set(color);
-    let enemy_king_sqr = pos.king_position(enemy_color);
-    //println!("enemy king square: {:?}", enemy_king_sqr);
-    
-    // change king to a pawn
-    //println!("changing king to pawn:");
-    if enemy_color == Color::White {
-        pos.piece_set(enemy_king_sqr.unwrap(), Some(Piece::W_P));
-    } else {
-        pos.piece_set(enemy_king_sqr.unwrap(), Some(Piece::B_P));
+    // from scratch, what the running sums must always equal
+    pub fn compute(pos: &Position) -> Incremental {
+        let (white_pp, black_pp) = promoted_pieces(pos);
+        let mut inc = Incremental::default();
+        for color in Color::all() {
+            inc.pst[color.array_index()] = evaluate_piece_table(pos, color);
+            for kind in PieceKind::all() {
+                inc.pieces[color.array_index()][kind.array_index()] = pos.piece_bitboard(Piece::new(kind, color)).count() as u8;
+            }
+        }
+        inc.promoted[Color::Black.array_index()] = black_pp;
+        inc.promoted[Color::White.array_index()] = white_pp;
+        inc
     }
 
-    let next_moves = all_legal_moves_partial(&pos); 
-    
-    let mut attacks = Vec::new();
+    pub fn add_piece(&mut self, piece: Piece, square: Square) {
+        let side = piece.color().array_index();
+        self.pst[side] += pst_value(piece, square);
+        self.pieces[side][piece.piece_kind().array_index()] += 1;
+        if piece.piece_kind().unpromote().is_some() {
+            self.promoted[side] += 1;
+        }
+    }
 
-    for move_item in next_moves {
-        
-        if move_item.to() == enemy_king_sqr.unwrap() && move_item.is_promoting() == false {
-            attacks.push(move_item);
-            //println!("{:?}", move_item);
+    pub fn remove_piece(&mut self, piece: Piece, square: Square) {
+        let side = piece.color().array_index();
+        self.pst[side] -= pst_value(piece, square);
+        self.pieces[side][piece.piece_kind().array_index()] -= 1;
+        if piece.piece_kind().unpromote().is_some() {
+            self.promoted[side] -= 1;
         }
     }
 
-    // change back to king
-    //println!("changing back to king:");
-    if enemy_color == Color::White {
-        pos.piece_set(enemy_king_sqr.unwrap(), Some(Piece::W_K));
-    } else {
-        pos.piece_set(enemy_king_sqr.unwrap(), Some(Piece::B_K));
+    pub fn promoted(&self, color: Color) -> u32 {
+        self.promoted[color.array_index()]
     }
 
-    let num_king_attackers = attacks.len() as i32;
-    //println!("num_king_attackers: {:?}", num_king_attackers);
+    pub fn pst(&self, color: Color) -> i32 {
+        self.pst[color.array_index()]
+    }
 
+    pub fn pieces(&self, color: Color, kind: PieceKind) -> u8 {
+        self.pieces[color.array_index()][kind.array_index()]
+    }
+}
 
-    // --------------------------------------------------------------------------------------------------
-    // Calculate the number of escape routes the king has
-    //println!("\n-------------Calculateing number of escape routes for the king");
-    pos.side_to_move_set(enemy_color);
-    let escapes = normal_from_candidates(&pos, king_square.clone().unwrap());
-    //println!("escapes: {:?}", escapes);
-    let num_escapes = escapes.count() as i32;
-    //println!("num_escapes: {:?}\n", num_escapes);
-
-    // Modify the values with internal weightings
-    let king_vulnerability = (num_attackers * ATK_WEIGHT
-                            - num_defenders * DEF_WEIGHT as i32
-                            + num_king_attackers * K_ATK_WEIGHT
-                            - num_escapes * ESC_WEIGHT)
-                            .max(0);
-
-    king_vulnerability
 
-}
+// (white, black) fitness of `sfen`. Nothing is printed: `eval --explain` shows where it comes from
+pub fn evaluate(sfen: &str) -> (f32, f32) {
 
+    let trace = EvalTrace::new(&Position::from_sfen(sfen), &params::current());
+    (trace.white, trace.black)
 
-//   ################################## 5. PIECES IN HAND ##################################
+}
 
 
-pub fn eval_hand(sfen: &str) -> (u32, u32) {
-    
-    let mut white_pieces = Vec::new();
-    let mut black_pieces = Vec::new();
-
-    let parts: Vec<&str> = sfen.split_whitespace().collect();
-    //if parts.len() < 3 {
-    //    return (white_pieces, black_pieces);  
-    //}
-
-    let pieces_in_hand = parts[2];
-    let mut current_count: Option<u32> = None;
-    for c in pieces_in_hand.chars() {
-        if let Some(n) = c.to_digit(10) {              
-            current_count = Some(n);
-        } else {
-            let piece = c.to_string();
-            let pieces = vec![piece.clone(); current_count.unwrap_or(1) as usize];
-            if c.is_uppercase() {
-                black_pieces.extend(pieces);
-            } else {
-                white_pieces.extend(pieces);
-            }
-            current_count = None;
-        }
-    }
+// #############################################################################################
 
-    let mut white_hand_value = 0;
-    let mut black_hand_value = 0;
-
-    for piece in white_pieces {
-        white_hand_value += match piece.as_str() {
-            "p" => PAWN_HAND,
-            "l" => LANCE_HAND,
-            "n" => KNIGHT_HAND,
-            "s" => SILVER_HAND,
-            "g" => GOLD_HAND,
-            "r" => ROOK_HAND,
-            "b" => BISHOP_HAND,
-            _ => 0,
-        };
-    }
-    for piece in black_pieces {
-        black_hand_value += match piece.as_str() {
-            "P" => PAWN_HAND,
-            "L" => LANCE_HAND,
-            "N" => KNIGHT_HAND,
-            "S" => SILVER_HAND,
-            "G" => GOLD_HAND,
-            "R" => ROOK_HAND,
-            "B" => BISHOP_HAND,
-            _ => 0,
-        };
+// additionally returns vector containing all the individual feature values
+// (none when the NNUE evaluates, its score is split evenly between the sides)
+pub fn evaluate_position(pos: &Position) -> ((f32, f32), Vec<(u32, u32)>) {
+    if let Some(score) = nnue::evaluate(pos) {
+        let black = if pos.side_to_move() == Color::Black { score } else { -score } as f32;
+        return ((-black / 2.0, black / 2.0), Vec::new());
     }
+    evaluate_tapered(pos, &params::current())
+}
 
-    (white_hand_value, black_hand_value)
 
+// evaluate_position with any weights, not just the engine's (for the tuners)
+pub fn evaluate_tapered(pos: &Position, params: &TaperedParams) -> ((f32, f32), Vec<(u32, u32)>) {
+    evaluate_with(pos, &params.at(phase::phase(pos)))
 }
 
 
-pub fn evaluate(sfen: &str) -> (f32, f32) {
+// the evaluation with one phase's weights, whatever the phase of the position
+pub fn evaluate_with(pos: &Position, params: &EvalParams) -> ((f32, f32), Vec<(u32, u32)>) {
 
     let mut white_fitness = 0;
     let mut black_fitness = 0;
+    let mut feature_vec = Vec::new(); 
+
+    // promoted pieces and PSTs are kept up to date by the position
+    let inc = pos.incremental();
+    debug_assert_eq!(inc, Incremental::compute(pos), "incremental eval out of sync: {}", pos.to_sfen());
 
 // ---------------------------------PROMOTED PIECES---------------------------------
 
-    let (mut white_pp, mut black_pp) = promoted_pieces(sfen);
-    
-    println!(" | ");
-    println!("white PromPiece value: {:?} black PromPiece value: {:?}", white_pp, black_pp);
+    let white_pp = weigh(inc.promoted(Color::White) as f32 * params.promoted_pieces);
+    let black_pp = weigh(inc.promoted(Color::Black) as f32 * params.promoted_pieces);
 
-    white_fitness += white_pp * PROMOTED_PIECES;
-    black_fitness += black_pp * PROMOTED_PIECES;
+    feature_vec.push((white_pp, black_pp));
+    
+    white_fitness += white_pp;
+    black_fitness += black_pp;
    
 
 // ---------------------------------PIECE SQUARE TABLES---------------------------------
 
-    let white_pst = evaluate_piece_table(&sfen, "white");
-    let black_pst = evaluate_piece_table(&sfen, "black");
+    let white_pst = weigh(inc.pst(Color::White) as f32 * params.pst);
+    let black_pst = weigh(inc.pst(Color::Black) as f32 * params.pst);
 
-    println!("white PST value: {:?} black PST value: {:?}", white_pst, black_pst);
+    feature_vec.push((white_pst, black_pst));
     
-    white_fitness += white_pst as u32;
-    black_fitness += black_pst as u32;
+    white_fitness += white_pst;
+    black_fitness += black_pst;
 
 
 // ---------------------------------KING VULN---------------------------------
 
-    let white_king_vln = enemy_king_vuln(&sfen, Color::White);
-    let black_king_vln = enemy_king_vuln(&sfen, Color::Black);
+    let white_king_vln = weigh(enemy_king_vuln(pos, Color::White, params) * params.king_vuln);
+    let black_king_vln = weigh(enemy_king_vuln(pos, Color::Black, params) * params.king_vuln);
 
-    println!("white_king_vln: {:?} black_king_vln: {:?}", white_king_vln, black_king_vln);
-    
-    white_fitness += white_king_vln as u32 * KING_VULN;
-    black_fitness += black_king_vln as u32 * KING_VULN;
+    feature_vec.push((white_king_vln, black_king_vln));   
 
-// ---------------------------------ROOK MOBIL---------------------------------
+    white_fitness += white_king_vln;
+    black_fitness += black_king_vln;
 
-    let (white_rook_mobil, black_rook_mobil) = rook_mobility(&sfen);
+// ---------------------------------MOBILITY---------------------------------
 
-    println!("white rook_mobil value: {:?} black rook_mobil value: {:?}", white_rook_mobil, black_rook_mobil);
-    
-    white_fitness += white_rook_mobil * ROOK_MOBIL;
-    black_fitness += black_rook_mobil * ROOK_MOBIL;
+    let (white_mobility, black_mobility) = eval_mobility(pos, params);
 
-// ---------------------------------LANCE MOBIL---------------------------------
+    feature_vec.push((white_mobility, black_mobility));
 
-    let (white_lance_mobil, black_lance_mobil) = lance_mobility(&sfen);
-    
-    println!("white lance_mobil value: {:?} black lance_mobil value: {:?}", white_lance_mobil, black_lance_mobil);
-    
-    white_fitness += white_lance_mobil * LANCE_MOBIL;
-    black_fitness += black_lance_mobil * LANCE_MOBIL;
-
-// ---------------------------------BISHOP MOBIL---------------------------------
-    
-    let (white_bish_mobil, black_bish_mobil) = bishop_mobility(&sfen);
-    
-    println!("white bish_mobil value: {:?} black bish_mobil value: {:?}", white_bish_mobil, black_bish_mobil);
-    
-    white_fitness += white_bish_mobil * BISHOP_MOBIL;
-    black_fitness += black_bish_mobil * BISHOP_MOBIL;
+    white_fitness += white_mobility;
+    black_fitness += black_mobility;
 
 // ---------------------------------PIECES IN HAND---------------------------------
 
-    let (white_hand, black_hand) = eval_hand(&sfen);
+    let (white_hand, black_hand) = eval_hand(pos, params);
     
-    println!("white hand value: {:?} black hand value: {:?}", white_hand, black_hand);
-    println!(" | ");
+    feature_vec.push((white_hand, black_hand));
     
     white_fitness += white_hand;
     black_fitness += black_hand;
 
+// ---------------------------------MATERIAL---------------------------------
+
+    let (white_material, black_material) = eval_material(pos, params);
+
+    feature_vec.push((white_material, black_material));
+
+    white_fitness += white_material;
+    black_fitness += black_material;
+
+// ---------------------------------CASTLES---------------------------------
+
+    let (white_castle, black_castle) = eval_castle(pos, params);
+
+    feature_vec.push((white_castle, black_castle));
+
+    white_fitness += white_castle;
+    black_fitness += black_castle;
+
+// ---------------------------------KING SAFETY---------------------------------
+
+    let (white_safety, black_safety) = eval_king_safety(pos, params);
+
+    feature_vec.push((white_safety, black_safety));
+
+    white_fitness += white_safety;
+    black_fitness += black_safety;
+
+// ---------------------------------STRUCTURE---------------------------------
+
+    let (white_structure, black_structure) = eval_structure(pos, params);
+
+    feature_vec.push((white_structure, black_structure));
+
+    white_fitness += white_structure;
+    black_fitness += black_structure;
+
 // ---------------------------------RETURN BOTH FITNESSES
-    return(white_fitness as f32, black_fitness as f32);
+    
+    return((white_fitness as f32, black_fitness as f32), feature_vec);
 
 }
 
 
 // #############################################################################################
 
-// additionally returns vector containing all the individual feature values
+// SFEN entry point for evaluate_position
 pub fn evaluate2(sfen: &str) -> ((f32, f32), Vec<(u32, u32)>) {
+    evaluate_position(&Position::from_sfen(sfen))
+}
 
-    let mut white_fitness = 0;
-    let mut black_fitness = 0;
-    let mut feature_vec = Vec::new(); 
-
-// ---------------------------------PROMOTED PIECES---------------------------------
-
-    let (mut white_pp, mut black_pp) = promoted_pieces(sfen);
-    
-    feature_vec.push((white_pp * PROMOTED_PIECES, black_pp * PROMOTED_PIECES));
-    
-    white_fitness += white_pp * PROMOTED_PIECES;
-    black_fitness += black_pp * PROMOTED_PIECES;
-   
 
-// ---------------------------------PIECE SQUARE TABLES---------------------------------
+//   ################################### TERMS (for the tuners) ###################################
 
-    let white_pst = evaluate_piece_table(&sfen, "white");
-    let black_pst = evaluate_piece_table(&sfen, "black");
+/*
+    The evaluation taken apart into what it counts before any weight is applied, so tuners can
+    score a position under many sets of weights without looking at the board again. fitness puts
+    the weights back exactly the way evaluate_with does.
+ */
 
-    feature_vec.push((white_pst.try_into().unwrap(), black_pst.try_into().unwrap()));
-    
-    white_fitness += white_pst as u32;
-    black_fitness += black_pst as u32;
+// what one side's fitness is made of
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SideTerms {
+    pub promoted: u32,               // promoted pieces on the board
+    pub pst: i32,                    // piece square table sum
+    pub king_vuln: [f32; 4],         // king_vuln_counts against the opponent's king
+    pub mobility: [u32; PieceKind::NUM],  // piece_mobility
+    pub hand: [u32; 7],              // pieces in hand, indexed like HAND_KINDS
+    pub board: [u32; PieceKind::NUM],  // pieces on the board by kind
+    pub castle: [f32; Castle::COUNT],  // castle_quality
+    pub king_safety: KingAttack,       // against the opponent's king
+    pub structure: Structure,
+}
 
 
-// ---------------------------------KING VULN---------------------------------
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct EvalTerms {
+    pub white: SideTerms,
+    pub black: SideTerms,
+    pub phase: f32,   // phase::phase of the position
+}
 
-    let white_king_vln = enemy_king_vuln(&sfen, Color::White);
-    let black_king_vln = enemy_king_vuln(&sfen, Color::Black);
 
-    feature_vec.push(((white_king_vln as u32 * KING_VULN), (black_king_vln as u32 * KING_VULN)));   
+impl EvalTerms {
 
-    white_fitness += white_king_vln as u32 * KING_VULN;
-    black_fitness += black_king_vln as u32 * KING_VULN;
+    pub fn compute(pos: &Position) -> EvalTerms {
 
-// ---------------------------------ROOK MOBIL---------------------------------
+        let inc = pos.incremental();
 
-    let (white_rook_mobil, black_rook_mobil) = rook_mobility(&sfen);
+        let side = |color: Color| SideTerms {
+            promoted: inc.promoted(color),
+            pst: inc.pst(color),
+            king_vuln: king_vuln_counts(pos, color),
+            mobility: piece_mobility(pos, color),
+            hand: HAND_KINDS.map(|kind| pos.hand(color, kind) as u32),
+            board: PieceKind::all().map(|kind| pos.piece_bitboard(Piece::new(kind, color)).count()),
+            castle: castle_quality(pos, color),
+            king_safety: KingAttack::compute(pos, color),
+            structure: Structure::compute(pos, color),
+        };
 
-    feature_vec.push((white_rook_mobil * ROOK_MOBIL, black_rook_mobil * ROOK_MOBIL));   
-    
-    white_fitness += white_rook_mobil * ROOK_MOBIL;
-    black_fitness += black_rook_mobil * ROOK_MOBIL;
+        EvalTerms {
+            white: side(Color::White),
+            black: side(Color::Black),
+            phase: phase::phase(pos),
+        }
+    }
 
-// ---------------------------------LANCE MOBIL---------------------------------
+    // the (white, black) fitness evaluate_tapered gives
+    pub fn fitness_tapered(&self, params: &TaperedParams) -> (f32, f32) {
+        self.fitness(&params.at(self.phase))
+    }
 
-    let (white_lance_mobil, black_lance_mobil) = lance_mobility(&sfen);
-    
-    feature_vec.push((white_lance_mobil * LANCE_MOBIL, black_lance_mobil * LANCE_MOBIL));   
-    
-    white_fitness += white_lance_mobil * LANCE_MOBIL;
-    black_fitness += black_lance_mobil * LANCE_MOBIL;
+    // the (white, black) fitness evaluate_with gives for these terms and one phase's weights
+    pub fn fitness(&self, params: &EvalParams) -> (f32, f32) {
+
+        let side = |terms: &SideTerms| {
+            let hand: f32 = HAND_KINDS.iter().zip(terms.hand)
+                .map(|(kind, count)| count as f32 * params.hand_bonus[kind.array_index()])
+                .sum();
+            let board: f32 = PieceKind::all().iter().zip(terms.board)
+                .map(|(kind, count)| count as f32 * params.material[kind.array_index()])
+                .sum();
+            let in_hand: f32 = HAND_KINDS.iter().zip(terms.hand)
+                .map(|(kind, count)| count as f32 * params.material_hand[kind.array_index()])
+                .sum();
+
+            let total = weigh(terms.promoted as f32 * params.promoted_pieces) as i64
+                + weigh(terms.pst as f32 * params.pst) as i64
+                + weigh(king_vuln_weighted(terms.king_vuln, params) * params.king_vuln) as i64
+                + weigh(mobility_value(&terms.mobility, params)) as i64
+                + weigh(hand) as i64
+                + weigh(board + in_hand) as i64
+                + weigh(castle_value(&terms.castle, params)) as i64
+                + weigh(terms.king_safety.danger(params) * params.safety_weight) as i64
+                + weigh(structure_value(&terms.structure, params)) as i64;
+            total as f32
+        };
 
-// ---------------------------------BISHOP MOBIL---------------------------------
-    
-    let (white_bish_mobil, black_bish_mobil) = bishop_mobility(&sfen);
-    
-    feature_vec.push((white_bish_mobil * BISHOP_MOBIL, black_bish_mobil * BISHOP_MOBIL));   
-    
-    white_fitness += white_bish_mobil * BISHOP_MOBIL;
-    black_fitness += black_bish_mobil * BISHOP_MOBIL;
+        (side(&self.white), side(&self.black))
+    }
+}
 
-// ---------------------------------PIECES IN HAND---------------------------------
 
-    let (white_hand, black_hand) = eval_hand(&sfen);
-    
-    feature_vec.push((white_hand, black_hand));
-    
-    white_fitness += white_hand;
-    black_fitness += black_hand;
+//   ######################################## EVAL TRACE ########################################
 
-// ---------------------------------RETURN BOTH FITNESSES
-    
-    return((white_fitness as f32, black_fitness as f32), feature_vec);
+/*
+    Every term of the evaluation by name, for people and scripts: what it counts (raw), what it
+    is multiplied by (weight) and what that adds to the side's fitness (contribution). The names
+    are the parameter names of params.rs (of one phase) and the weights those blended for the
+    position's phase. Contributions are raw x weight before the evaluation rounds them, the
+    totals are the evaluation itself.
+ */
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TermValue {
+    pub raw: f32,
+    pub weight: f32,
+    pub contribution: f32,
 }
 
 
-// #############################################################################################
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceRow {
+    pub name: String,
+    pub white: TermValue,
+    pub black: TermValue,
+}
 
-// additionally returns the sfen thats being evaluated
-pub fn evaluate3(sfen: &str) -> ((f32, f32), Vec<(u32, u32)>, &str) {
 
-    let mut white_fitness = 0;
-    let mut black_fitness = 0;
-    let mut feature_vec = Vec::new(); 
+#[derive(Clone, Debug, PartialEq)]
+pub struct EvalTrace {
+    pub sfen: String,
+    pub phase: f32,
+    pub rows: Vec<TraceRow>,
+    pub white: f32,   // white's fitness
+    pub black: f32,   // black's fitness
+}
 
-// ---------------------------------PROMOTED PIECES---------------------------------
 
-    let (mut white_pp, mut black_pp) = promoted_pieces(sfen);
-    
-    feature_vec.push((white_pp * PROMOTED_PIECES, black_pp * PROMOTED_PIECES));
-    
-    white_fitness += white_pp * PROMOTED_PIECES;
-    black_fitness += black_pp * PROMOTED_PIECES;
-   
+// (name, raw, weight) of every term of one side
+fn side_rows(terms: &SideTerms, params: &EvalParams) -> Vec<(String, f32, f32)> {
 
-// ---------------------------------PIECE SQUARE TABLES---------------------------------
+    let mut rows = vec![
+        ("weights.promoted_pieces".to_string(), terms.promoted as f32, params.promoted_pieces),
+        ("weights.pst".to_string(), terms.pst as f32, params.pst),
+        ("weights.king_vuln".to_string(), king_vuln_weighted(terms.king_vuln, params), params.king_vuln),
+    ];
+    for kind in PieceKind::all().into_iter().filter(|&kind| kind != PieceKind::King) {
+        let i = kind.array_index();
+        rows.push((format!("mobility.{}", params::kind_name(kind)), terms.mobility[i] as f32, params.mobility[i]));
+    }
+    for (kind, &count) in HAND_KINDS.iter().zip(&terms.hand) {
+        rows.push((format!("hand_bonus.{}", params::kind_name(*kind)), count as f32, params.hand_bonus[kind.array_index()]));
+    }
+    for kind in PieceKind::all().into_iter().filter(|&kind| kind != PieceKind::King) {
+        let i = kind.array_index();
+        rows.push((format!("material.{}", params::kind_name(kind)), terms.board[i] as f32, params.material[i]));
+    }
+    for (kind, &count) in HAND_KINDS.iter().zip(&terms.hand) {
+        rows.push((format!("material_hand.{}", params::kind_name(*kind)), count as f32, params.material_hand[kind.array_index()]));
+    }
+    for castle in Castle::ALL {
+        rows.push((format!("castle.{}", castle.name()), terms.castle[castle.index()], params.castle[castle.index()]));
+    }
+    // the attack units themselves aren't points, only their square times the weight is
+    rows.push(("king_safety.weight".to_string(), terms.king_safety.danger(params), params.safety_weight));
+    for (i, name) in Structure::NAMES.iter().enumerate() {
+        rows.push((format!("structure.{}", name), terms.structure.counts[i] as f32, params.structure[i]));
+    }
+    rows
+}
 
-    let white_pst = evaluate_piece_table(&sfen, "white");
-    let black_pst = evaluate_piece_table(&sfen, "black");
 
-    feature_vec.push((white_pst.try_into().unwrap(), black_pst.try_into().unwrap()));
-    
-    white_fitness += white_pst as u32;
-    black_fitness += black_pst as u32;
+impl EvalTrace {
 
+    pub fn new(pos: &Position, params: &TaperedParams) -> EvalTrace {
 
-// ---------------------------------KING VULN---------------------------------
+        let terms = EvalTerms::compute(pos);
+        let (white, black) = terms.fitness_tapered(params);
+        let params = &params.at(terms.phase);
+        let value = |(_, raw, weight): (String, f32, f32)| TermValue { raw, weight, contribution: raw * weight };
+        let rows = side_rows(&terms.white, params).into_iter()
+            .zip(side_rows(&terms.black, params))
+            .map(|(w, b)| TraceRow { name: w.0.clone(), white: value(w), black: value(b) })
+            .collect();
 
-    let white_king_vln = enemy_king_vuln(&sfen, Color::White);
-    let black_king_vln = enemy_king_vuln(&sfen, Color::Black);
+        EvalTrace { sfen: pos.to_sfen(), phase: terms.phase, rows, white, black }
+    }
 
-    feature_vec.push(((white_king_vln as u32 * KING_VULN), (black_king_vln as u32 * KING_VULN)));   
+    // the trace as a table, leaving out terms that are zero for both sides
+    pub fn table(&self) -> String {
+
+        let mut lines = vec![
+            format!(" | game phase {:.2} (1 opening, 0 endgame)", self.phase),
+            format!(" | {:<30}|{:>9}{:>9}{:>10} |{:>9}{:>9}{:>10}", "term", "white", "weight", "points", "black", "weight", "points"),
+            format!(" | {:-<30}+{:-<28}-+{:-<28}", "", "", ""),
+        ];
+        // whole numbers as they are, anything else to two decimals
+        let number = |x: f32| if x.fract() == 0.0 { format!("{}", x) } else { format!("{:.2}", x) };
+        for row in self.rows.iter().filter(|row| row.white.raw != 0.0 || row.black.raw != 0.0) {
+            let (w, b) = (row.white, row.black);
+            lines.push(format!(" | {:<30}|{:>9}{:>9}{:>10} |{:>9}{:>9}{:>10}", row.name,
+                number(w.raw), number(w.weight), number(w.contribution), number(b.raw), number(b.weight), number(b.contribution)));
+        }
+        lines.push(format!(" | {:-<30}+{:-<28}-+{:-<28}", "", "", ""));
+        lines.push(format!(" | {:<30}|{:>28} |{:>28}", "fitness", self.white, self.black));
+        lines.push(format!(" | black - white: {}", self.black - self.white));
+        lines.join("\n")
+    }
 
-    white_fitness += white_king_vln as u32 * KING_VULN;
-    black_fitness += black_king_vln as u32 * KING_VULN;
+    pub fn to_json(&self) -> serde_json::Value {
+        let value = |v: &TermValue| serde_json::json!({ "raw": v.raw, "weight": v.weight, "contribution": v.contribution });
+        serde_json::json!({
+            "sfen": self.sfen,
+            "phase": self.phase,
+            "white": self.white,
+            "black": self.black,
+            "terms": self.rows.iter()
+                .map(|row| serde_json::json!({ "name": row.name, "white": value(&row.white), "black": value(&row.black) }))
+                .collect::<Vec<_>>(),
+        })
+    }
+}
 
-// ---------------------------------ROOK MOBIL---------------------------------
 
-    let (white_rook_mobil, black_rook_mobil) = rook_mobility(&sfen);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::params::DEFAULT_TAPERED;
 
-    feature_vec.push((white_rook_mobil * ROOK_MOBIL, black_rook_mobil * ROOK_MOBIL));   
-    
-    white_fitness += white_rook_mobil * ROOK_MOBIL;
-    black_fitness += black_rook_mobil * ROOK_MOBIL;
+    #[test]
+    fn material_counts_board_and_hand() {
+        let start = Position::from_sfen("lnsgkgsnl/1r5b1/ppppppppp/9/9/9/PPPPPPPPP/1B5R1/LNSGKGSNL b - 1");
+        let (white, black) = eval_material(&start, &EvalParams::default());
+        assert_eq!(white, black);
 
-// ---------------------------------LANCE MOBIL---------------------------------
+        // black gave its rook for a pawn: white holds the rook, black the pawn
+        let traded = Position::from_sfen("lnsgkgsnl/1r5b1/pppppppp1/9/9/9/PPPPPPPPP/1B7/LNSGKGSNL b Pr 1");
+        let (white_traded, black_traded) = eval_material(&traded, &EvalParams::default());
+        assert_eq!(white_traded, white - 100 + 1000);
+        assert_eq!(black_traded, black - 1000 + 100);
+    }
 
-    let (white_lance_mobil, black_lance_mobil) = lance_mobility(&sfen);
-    
-    feature_vec.push((white_lance_mobil * LANCE_MOBIL, black_lance_mobil * LANCE_MOBIL));   
-    
-    white_fitness += white_lance_mobil * LANCE_MOBIL;
-    black_fitness += black_lance_mobil * LANCE_MOBIL;
+    // black's promoted pawn counts for black, in the evaluation and in the tuners' terms
+    #[test]
+    fn promoted_pieces_count_for_their_owner() {
+        let pos = Position::from_sfen("lnsgkgsnl/1r5b1/pppp1pppp/4+P4/9/9/PPPP1PPPP/1B5R1/LNSGKGSNL b P 1");
+        let params = EvalParams::default();
+        let (_, features) = evaluate_with(&pos, &params);
+        assert_eq!(features[0], (0, weigh(params.promoted_pieces)));
+
+        let terms = EvalTerms::compute(&pos);
+        assert_eq!((terms.white.promoted, terms.black.promoted), (0, 1));
+        assert_eq!(promoted_pieces(&pos), (0, 1));
+    }
 
-// ---------------------------------BISHOP MOBIL---------------------------------
-    
-    let (white_bish_mobil, black_bish_mobil) = bishop_mobility(&sfen);
-    
-    feature_vec.push((white_bish_mobil * BISHOP_MOBIL, black_bish_mobil * BISHOP_MOBIL));   
-    
-    white_fitness += white_bish_mobil * BISHOP_MOBIL;
-    black_fitness += black_bish_mobil * BISHOP_MOBIL;
+    // with the default weights the same position scores differently by phase
+    #[test]
+    fn phase_changes_the_score() {
+        let late = Position::from_sfen("4k4/4G4/9/9/9/9/9/9/4K4 b 2RBG 1");
+        let phase = phase::phase(&late);
+        assert!(phase < 1.0);
 
-// ---------------------------------PIECES IN HAND---------------------------------
+        let (tapered, _) = evaluate_tapered(&late, &DEFAULT_TAPERED);
+        assert_eq!(tapered, evaluate_with(&late, &DEFAULT_TAPERED.at(phase)).0);
+        assert_ne!(tapered, evaluate_with(&late, &DEFAULT_PARAMS).0);
+    }
 
-    let (white_hand, black_hand) = eval_hand(&sfen);
-    
-    feature_vec.push((white_hand, black_hand));
-    
-    white_fitness += white_hand;
-    black_fitness += black_hand;
+    #[test]
+    fn mobility_of_every_piece() {
+        let start = Position::startpos();
+        let mobility = mobility_counts(&start, Color::Black, false);
+        let of = |mobility: [u32; PieceKind::NUM], kind: PieceKind| mobility[kind.array_index()];
+        // pawns 9, lances 1 + 1, silvers 2 + 2, golds 3 + 3, rook 6 (the bishop is boxed in)
+        assert_eq!(PieceKind::all().map(|kind| of(mobility, kind)), [9, 2, 0, 4, 6, 0, 6, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(mobility_counts(&start, Color::White, false), mobility);
+
+        // the dragon on 5e reaches 19 squares (5a's gold included), but the pawn on 6c,
+        // silver on 4c and gold on 5a attack 6d, 4d, 5d and 5b
+        let pos = Position::from_sfen("4g4/9/3p1s3/9/4+R4/9/9/9/4K4 b - 1");
+        let dragon = |safe| of(mobility_counts(&pos, Color::Black, safe), PieceKind::ProRook);
+        assert_eq!((dragon(false), dragon(true)), (19, 15));
+        assert_eq!(mobility_counts(&pos.flipped(), Color::White, true), mobility_counts(&pos, Color::Black, true));
+    }
 
-// ---------------------------------RETURN BOTH FITNESSES
-    
-    return((white_fitness as f32, black_fitness as f32), feature_vec, sfen);
+    #[test]
+    fn trace_adds_up_to_the_evaluation() {
+        let pos = Position::from_sfen("lnsgkg1nl/1r5s1/pppppp+Bpp/6p2/9/2P6/PP1PPPPPP/7R1/LNSGKGSNL w B 8");
+        let params = TaperedParams::default();
+        let trace = EvalTrace::new(&pos, &params);
+        let ((white, black), _) = evaluate_tapered(&pos, &params);
+        assert_eq!((trace.white, trace.black), (white, black));
+
+        let names: Vec<&str> = trace.rows.iter().map(|row| row.name.as_str()).collect();
+        // all but the king_vuln parts and the king safety units
+        assert_eq!(names.len(), EvalParams::names().len() - 4 - KingAttack::default().counts().len());
+        assert!(trace.rows.iter().all(|row| EvalParams::names().contains(&row.name)));
+
+        let sum = |side: fn(&TraceRow) -> f32| trace.rows.iter().map(side).sum::<f32>();
+        assert!((sum(|row| row.white.contribution) - white).abs() <= 8.0);
+        assert!((sum(|row| row.black.contribution) - black).abs() <= 8.0);
+
+        let json = trace.to_json();
+        assert_eq!(json["terms"].as_array().map(Vec::len), Some(names.len()));
+        assert_eq!(json["terms"][0]["name"], "weights.promoted_pieces");
+    }
 
+    // swapping the colours must swap the scores, term by term: eval(pos) == -eval(flipped(pos)),
+    // and the tuners' EvalTerms must give the same scores as the evaluation itself
+    #[test]
+    fn evaluation_is_colour_symmetric() {
+        for _ in 0..10 {
+            let mut pos = Position::from_sfen("lnsgkgsnl/1r5b1/ppppppppp/9/9/9/PPPPPPPPP/1B5R1/LNSGKGSNL b - 1");
+            for _ in 0..80 {
+                let ((white, black), features) = evaluate_position(&pos);
+                let ((flipped_white, flipped_black), flipped_features) = evaluate_position(&pos.flipped());
+                assert_eq!((white, black), (flipped_black, flipped_white), "{}", pos.to_sfen());
+                let swapped: Vec<(u32, u32)> = flipped_features.iter().map(|&(w, b)| (b, w)).collect();
+                assert_eq!(features, swapped, "{}", pos.to_sfen());
+                assert_eq!(EvalTerms::compute(&pos).fitness_tapered(&params::current()), (white, black), "{}", pos.to_sfen());
+
+                let moves = pos.legal_moves();
+                if moves.is_empty() {
+                    break;
+                }
+                pos.make_move(moves[random_number::random!(..moves.len())]);
+            }
+        }
+    }
 }
-
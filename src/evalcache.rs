@@ -0,0 +1,208 @@
+/* Evaluation cache
+ *
+ * The same leaves come up again and again: every iteration of the iterative
+ * deepening evaluates the previous iteration's leaves once more, and different
+ * move orders reach the same position. evaluate remembers the result of
+ * eval::evaluate_position for each position key in a fixed size table and
+ * hands it back instead of evaluating again.
+ *
+ * The table is shared by every thread (USI search, pondering) without a lock.
+ * An entry is a row of atomic words: the packed evaluation and a check word,
+ * which is the position key XORed with a hash of the data. Two threads writing
+ * the same entry at once can leave it half one and half the other, but then
+ * the check no longer matches and the probe is simply a miss. A newer position
+ * always replaces whatever was in its slot.
+ *
+ * Hits and probes are counted so the search can report the hit rate.
+ */
+
+use crate::eval;
+use crate::position::Position;
+use crate::zobrist;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+
+// entries in the shared table, 8 bytes per word (about 10 MB in all)
+pub const DEFAULT_ENTRIES: usize = 1 << 16;
+// an evaluation with more feature pairs than this isn't cached
+pub const MAX_FEATURES: usize = 16;
+
+// fitness pair, feature count, one word per feature pair
+const DATA_WORDS: usize = 2 + MAX_FEATURES;
+
+
+#[derive(Default)]
+struct Entry {
+    check: AtomicU64,
+    data: [AtomicU64; DATA_WORDS],
+}
+
+
+// hits and probes, either since the cache was made or between two snapshots
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub probes: u64,
+}
+
+
+impl CacheStats {
+
+    // what happened between `earlier` and self
+    pub fn since(&self, earlier: CacheStats) -> CacheStats {
+        CacheStats { hits: self.hits - earlier.hits, probes: self.probes - earlier.probes }
+    }
+
+    // percentage of probes that were hits
+    pub fn hit_rate(&self) -> f64 {
+        if self.probes == 0 { 0.0 } else { 100.0 * self.hits as f64 / self.probes as f64 }
+    }
+}
+
+
+pub struct EvalCache {
+    entries: Vec<Entry>,
+    hits: AtomicU64,
+    probes: AtomicU64,
+}
+
+
+pub type Evaluation = ((f32, f32), Vec<(u32, u32)>);
+
+
+// mixes the data words into one, so a torn entry fails the check
+fn fold(data: &[u64; DATA_WORDS]) -> u64 {
+    data.iter().fold(0, |hash: u64, &word| (hash.rotate_left(17) ^ word).wrapping_mul(0x9e37_79b9_7f4a_7c15))
+}
+
+
+fn pack(eval: &Evaluation) -> Option<[u64; DATA_WORDS]> {
+    let ((white, black), features) = eval;
+    if features.len() > MAX_FEATURES {
+        return None;
+    }
+    let mut data = [0; DATA_WORDS];
+    data[0] = white.to_bits() as u64 | (black.to_bits() as u64) << 32;
+    data[1] = features.len() as u64;
+    for (word, &(white, black)) in data[2..].iter_mut().zip(features) {
+        *word = white as u64 | (black as u64) << 32;
+    }
+    Some(data)
+}
+
+
+fn unpack(data: &[u64; DATA_WORDS]) -> Evaluation {
+    let white = f32::from_bits(data[0] as u32);
+    let black = f32::from_bits((data[0] >> 32) as u32);
+    let len = (data[1] as usize).min(MAX_FEATURES);
+    let features = data[2..2 + len].iter().map(|&word| (word as u32, (word >> 32) as u32)).collect();
+    ((white, black), features)
+}
+
+
+impl EvalCache {
+
+    // `entries` is rounded up to a power of two
+    pub fn new(entries: usize) -> EvalCache {
+        let entries = entries.max(1).next_power_of_two();
+        EvalCache {
+            entries: (0..entries).map(|_| Entry::default()).collect(),
+            hits: AtomicU64::new(0),
+            probes: AtomicU64::new(0),
+        }
+    }
+
+    fn entry(&self, key: u64) -> &Entry {
+        &self.entries[key as usize & (self.entries.len() - 1)]
+    }
+
+    pub fn probe(&self, key: u64) -> Option<Evaluation> {
+        self.probes.fetch_add(1, Ordering::Relaxed);
+        let entry = self.entry(key);
+        let data: [u64; DATA_WORDS] = std::array::from_fn(|i| entry.data[i].load(Ordering::Relaxed));
+        let check = entry.check.load(Ordering::Relaxed);
+        // a never written entry (all zero) would otherwise match key 0
+        if check ^ fold(&data) != key || (check == 0 && data[1] == 0) {
+            return None;
+        }
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        Some(unpack(&data))
+    }
+
+    pub fn store(&self, key: u64, eval: &Evaluation) {
+        let Some(data) = pack(eval) else { return };
+        let entry = self.entry(key);
+        for (slot, word) in entry.data.iter().zip(data) {
+            slot.store(word, Ordering::Relaxed);
+        }
+        entry.check.store(key ^ fold(&data), Ordering::Relaxed);
+    }
+
+    // forgets every evaluation, e.g. once the eval weights change
+    pub fn clear(&self) {
+        for entry in &self.entries {
+            entry.check.store(0, Ordering::Relaxed);
+            for word in &entry.data {
+                word.store(0, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats { hits: self.hits.load(Ordering::Relaxed), probes: self.probes.load(Ordering::Relaxed) }
+    }
+}
+
+
+// the table every search shares
+pub fn global() -> &'static EvalCache {
+    static CACHE: OnceLock<EvalCache> = OnceLock::new();
+    CACHE.get_or_init(|| EvalCache::new(DEFAULT_ENTRIES))
+}
+
+
+// eval::evaluate_position through the shared cache
+pub fn evaluate(pos: &Position) -> Evaluation {
+    let cache = global();
+    debug_assert_eq!(pos.key(), zobrist::compute(pos), "hash key out of sync: {}", pos.to_sfen());
+    if let Some(eval) = cache.probe(pos.key()) {
+        return eval;
+    }
+    let eval = eval::evaluate_position(pos);
+    cache.store(pos.key(), &eval);
+    eval
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stores_and_finds_evaluations() {
+        let cache = EvalCache::new(16);
+        let pos = Position::startpos();
+        let eval = eval::evaluate_position(&pos);
+
+        assert_eq!(cache.probe(pos.key()), None);
+        cache.store(pos.key(), &eval);
+        assert_eq!(cache.probe(pos.key()), Some(eval.clone()));
+
+        // same slot, different key
+        assert_eq!(cache.probe(pos.key() ^ 16), None);
+        assert_eq!(cache.stats(), CacheStats { hits: 1, probes: 3 });
+
+        cache.clear();
+        assert_eq!(cache.probe(pos.key()), None);
+    }
+
+    #[test]
+    fn torn_entries_are_misses() {
+        let cache = EvalCache::new(1);
+        let eval = ((1.0, 2.0), vec![(3, 4)]);
+        cache.store(42, &eval);
+        cache.entries[0].data[2].store(5, Ordering::Relaxed);
+        assert_eq!(cache.probe(42), None);
+    }
+}
@@ -0,0 +1,516 @@
+/* Genetic algorithm tuner for the evaluation weights
+ *
+ * A population of TaperedParams (both phases' weights) evolves by playing the engine against itself:
+ *
+ *    fitness    every member plays mini-matches against a few others picked
+ *               at random: pairs of games from the same random opening, one
+ *               with each colour. Fitness is the share of points scored
+ *               (win 1, draw 1/2) over every game the member took part in.
+ *    selection  tournament selection, the best `elite` members are carried
+ *               over unchanged.
+ *    crossover  uniform: every weight comes from one parent or the other.
+ *    mutation   a weight is moved by gaussian noise scaled to its size.
+ *    best       fitness only compares members of one generation, so each
+ *               generation's leader plays `playoff` pairs of games against
+ *               the best so far (at first the starting weights) and takes
+ *               its place only by scoring more than half the points.
+ *
+ * Every search is given the weights of the side to move (search::think_with),
+ * the engine's own weights and its evaluation cache are left alone.
+ *
+ * After every generation the population and its fitness are written to
+ * <dir>/latest.json (and generation_NNNN.json) and the best member so far to
+ * <dir>/best.toml, which --eval-file and the USI EvalFile option read
+ * directly. `resume` carries on from latest.json.
+ */
+
+use crate::eval;
+use crate::params::{self, TaperedParams, DEFAULT_PARAMS};
+use crate::position::Position;
+use crate::search::{self, SearchLimits};
+use shogi_core::{Color, PositionStatus};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct GaConfig {
+    pub population: usize,
+    pub generations: usize,
+    pub opponents: usize,      // members each one is paired with per generation, two games each
+    pub playoff: usize,        // pairs of games between a generation's leader and the best so far
+    pub elite: usize,          // best members copied unchanged into the next generation
+    pub tournament: usize,     // members drawn for each tournament selection
+    pub mutation_rate: f64,    // chance of each weight being mutated
+    pub mutation_scale: f64,   // standard deviation of a mutation, relative to the weight
+    pub limits: SearchLimits,  // per move
+    pub opening_plies: usize,  // random moves before the engines take over
+    pub max_plies: usize,      // longer games are adjudicated on material
+    pub adjudicate: u32,       // material lead that wins an adjudicated game
+    pub checkpoint: PathBuf,   // directory for the checkpoints
+    pub resume: bool,
+}
+
+
+impl Default for GaConfig {
+    fn default() -> Self {
+        GaConfig {
+            population: 12,
+            generations: 100,
+            opponents: 2,
+            playoff: 2,
+            elite: 2,
+            tournament: 3,
+            mutation_rate: 0.1,
+            mutation_scale: 0.2,
+            limits: SearchLimits::depth(2),
+            opening_plies: 6,
+            max_plies: 256,
+            adjudicate: 1000,
+            checkpoint: PathBuf::from("ga"),
+            resume: false,
+        }
+    }
+}
+
+
+impl GaConfig {
+
+    // Parses e.g. ["population", "16", "depth", "1", "checkpoint", "runs/a", "resume"].
+    // depth/nodes/movetime are the search limits per move, as for `go`.
+    pub fn parse(args: &[&str]) -> Result<Self, String> {
+
+        let mut config = GaConfig::default();
+        let mut search_args = Vec::new();
+        let mut i = 0;
+        while i < args.len() {
+            let value = args.get(i + 1).copied();
+            let missing = || format!("{} needs a value", args[i]);
+            let number = |value: Option<&str>| -> Result<f64, String> {
+                let text = value.ok_or_else(missing)?;
+                text.parse::<f64>().map_err(|_| format!("invalid value for {}: {}", args[i], text))
+            };
+            match args[i] {
+                "population" => config.population = number(value)? as usize,
+                "generations" => config.generations = number(value)? as usize,
+                "opponents" => config.opponents = number(value)? as usize,
+                "playoff" => config.playoff = number(value)? as usize,
+                "elite" => config.elite = number(value)? as usize,
+                "tournament" => config.tournament = number(value)? as usize,
+                "mutation" => config.mutation_rate = number(value)?,
+                "scale" => config.mutation_scale = number(value)?,
+                "opening" => config.opening_plies = number(value)? as usize,
+                "plies" => config.max_plies = number(value)? as usize,
+                "adjudicate" => config.adjudicate = number(value)? as u32,
+                "checkpoint" => config.checkpoint = PathBuf::from(value.ok_or_else(missing)?),
+                "depth" | "nodes" | "movetime" => {
+                    search_args.push(args[i]);
+                    search_args.push(value.ok_or_else(missing)?);
+                },
+                "resume" => {
+                    config.resume = true;
+                    i += 1;
+                    continue;
+                },
+                other => return Err(format!("unknown tuner option: {}", other)),
+            }
+            i += 2;
+        }
+        if !search_args.is_empty() {
+            config.limits = SearchLimits::parse(&search_args)?;
+        }
+        if config.population < 2 || config.opponents == 0 || config.tournament == 0 {
+            return Err("the tuner needs a population of 2 or more, 1+ opponents and a tournament size of 1+".to_string());
+        }
+        config.opponents = config.opponents.min(config.population - 1);
+        config.elite = config.elite.min(config.population);
+        Ok(config)
+    }
+}
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Member {
+    pub params: TaperedParams,
+    pub fitness: f64,
+}
+
+
+#[derive(Debug)]
+pub enum GaError {
+    Io(String),
+    Checkpoint(String),   // latest.json can't be read back
+}
+
+
+impl fmt::Display for GaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GaError::Io(err) => write!(f, "checkpoint: {}", err),
+            GaError::Checkpoint(err) => write!(f, "bad checkpoint: {}", err),
+        }
+    }
+}
+
+
+impl std::error::Error for GaError {}
+
+
+// ------------------------------------------ games ------------------------------------------
+
+
+// a normally distributed number (Box-Muller)
+fn gaussian() -> f64 {
+    let u: f64 = random_number::random!(f64::EPSILON..1.0);
+    let v: f64 = random_number::random!(0.0..1.0);
+    (-2.0 * u.ln()).sqrt() * (2.0 * std::f64::consts::PI * v).cos()
+}
+
+
+// a position after `plies` random legal moves from the start, which both games of a pair begin from
+fn random_opening(plies: usize) -> Position {
+    let mut pos = Position::startpos();
+    for _ in 0..plies {
+        let moves = pos.legal_moves();
+        if moves.is_empty() {
+            break;
+        }
+        pos.make_move(moves[random_number::random!(..moves.len())]);
+    }
+    pos
+}
+
+
+// Plays one game from `start` and returns black's points. Four repetitions of a
+// position are a draw, a game reaching max_plies goes to the side ahead on
+// material (with the default values) by `adjudicate` or more.
+fn play_game(start: &Position, black: &TaperedParams, white: &TaperedParams, config: &GaConfig) -> f64 {
+
+    let mut pos = start.clone();
+    let mut seen: HashMap<u64, u32> = HashMap::new();
+    let stop = AtomicBool::new(false);
+
+    for _ in 0..config.max_plies {
+        match pos.status() {
+            PositionStatus::BlackWins => return 1.0,
+            PositionStatus::WhiteWins => return 0.0,
+            _ => {},
+        }
+        let count = seen.entry(pos.key()).or_insert(0);
+        *count += 1;
+        if *count >= 4 {
+            return 0.5;
+        }
+
+        let color = pos.side_to_move();
+        let params = if color == Color::Black { black } else { white };
+        let result = search::think_with(&pos.to_sfen(), &config.limits, &stop, Some(params));
+        match result.best_move {
+            Some(mv) => {
+                pos.make_move(mv);
+            },
+            None => return if color == Color::Black { 0.0 } else { 1.0 },
+        }
+    }
+
+    let (white_material, black_material) = eval::eval_material(&pos, &DEFAULT_PARAMS);
+    let lead = black_material as i64 - white_material as i64;
+    if lead >= config.adjudicate as i64 {
+        1.0
+    } else if -lead >= config.adjudicate as i64 {
+        0.0
+    } else {
+        0.5
+    }
+}
+
+
+// two games from `start`, one with each colour, and a's points out of the 2
+fn play_pair(start: &Position, a: &TaperedParams, b: &TaperedParams, config: &GaConfig) -> f64 {
+    play_game(start, a, b, config) + 1.0 - play_game(start, b, a, config)
+}
+
+
+// Every member plays two games (one with each colour) against `opponents` others;
+// fitness is the share of the points available in every game a member played.
+fn play_round(population: &mut [Member], config: &GaConfig, generation: usize) {
+
+    let size = population.len();
+    let mut points = vec![0.0; size];
+    let mut games = vec![0u32; size];
+
+    for i in 0..size {
+        let mut opponents: Vec<usize> = (0..size).filter(|&j| j != i).collect();
+        for _ in 0..config.opponents {
+            let j = opponents.swap_remove(random_number::random!(..opponents.len()));
+            let start = random_opening(config.opening_plies);
+            let (a, b) = (population[i].params, population[j].params);
+
+            let scored = play_pair(&start, &a, &b, config);
+            points[i] += scored;
+            points[j] += 2.0 - scored;
+            games[i] += 2;
+            games[j] += 2;
+            println!(" | generation {} member {:>2} vs {:>2}: {} - {}", generation, i, j, scored, 2.0 - scored);
+        }
+    }
+
+    for (member, (points, games)) in population.iter_mut().zip(points.iter().zip(&games)) {
+        member.fitness = if *games == 0 { 0.0 } else { points / *games as f64 };
+    }
+}
+
+
+// Does `challenger` beat `holder`? `playoff` pairs of games from random openings,
+// the challenger needs more than half the points.
+fn wins_playoff(challenger: &TaperedParams, holder: &TaperedParams, config: &GaConfig) -> bool {
+    if challenger == holder {
+        return false;
+    }
+    let points: f64 = (0..config.playoff)
+        .map(|_| play_pair(&random_opening(config.opening_plies), challenger, holder, config))
+        .sum();
+    println!(" | play-off against the best so far: {} - {}", points, 2.0 * config.playoff as f64 - points);
+    points > config.playoff as f64
+}
+
+
+// ----------------------------------------- breeding -----------------------------------------
+
+
+// the fittest of `size` members drawn at random
+fn tournament(population: &[Member], size: usize) -> &Member {
+    (0..size)
+        .map(|_| &population[random_number::random!(..population.len())])
+        .max_by(|a, b| a.fitness.total_cmp(&b.fitness))
+        .expect("tournament of at least one")
+}
+
+
+// every weight from one parent or the other
+fn crossover(a: &TaperedParams, b: &TaperedParams) -> TaperedParams {
+    let mut child = *a;
+    for (name, value) in b.fields() {
+        if random_number::random!(..2u8) == 1 {
+            child.set(&name, value).expect("names from fields()");
+        }
+    }
+    child
+}
+
+
+// Moves each weight with probability `rate` by gaussian noise of `scale` times its size
+// (at least 1, so weights at zero can move too). Weights stay zero or above.
+fn mutate(params: &mut TaperedParams, rate: f64, scale: f64) {
+    for (name, value) in params.fields() {
+        if random_number::random!(0.0..1.0) < rate {
+            let noise = gaussian() * scale * (value.abs() as f64).max(1.0);
+            params.set(&name, (value as f64 + noise).max(0.0) as f32).expect("names from fields()");
+        }
+    }
+}
+
+
+// the next generation: the elite unchanged, the rest bred from tournament winners
+fn next_generation(population: &[Member], config: &GaConfig) -> Vec<Member> {
+
+    let mut ranked = population.to_vec();
+    ranked.sort_by(|a, b| b.fitness.total_cmp(&a.fitness));
+
+    let mut next: Vec<Member> = ranked.iter().take(config.elite).cloned().collect();
+    while next.len() < population.len() {
+        let a = tournament(&ranked, config.tournament);
+        let b = tournament(&ranked, config.tournament);
+        let mut child = crossover(&a.params, &b.params);
+        mutate(&mut child, config.mutation_rate, config.mutation_scale);
+        next.push(Member { params: child, fitness: 0.0 });
+    }
+    next
+}
+
+
+// --------------------------------------- checkpoints ---------------------------------------
+
+
+fn checkpoint_json(generation: usize, population: &[Member], best: &Member) -> serde_json::Value {
+    let member = |m: &Member| serde_json::json!({ "fitness": m.fitness, "params": m.params.to_json() });
+    serde_json::json!({
+        "generation": generation,
+        "best": member(best),
+        "population": population.iter().map(member).collect::<Vec<_>>(),
+    })
+}
+
+
+// (generation, population, best so far) as written by checkpoint_json
+fn parse_checkpoint(text: &str) -> Result<(usize, Vec<Member>, Member), GaError> {
+
+    let bad = |what: &str| GaError::Checkpoint(what.to_string());
+    let root: serde_json::Value = serde_json::from_str(text).map_err(|e| GaError::Checkpoint(e.to_string()))?;
+    let member = |value: &serde_json::Value| -> Result<Member, GaError> {
+        let params = TaperedParams::from_json(&value["params"].to_string()).map_err(|e| GaError::Checkpoint(e.to_string()))?;
+        let fitness = value["fitness"].as_f64().ok_or_else(|| bad("member without fitness"))?;
+        Ok(Member { params, fitness })
+    };
+
+    let generation = root["generation"].as_u64().ok_or_else(|| bad("no generation"))? as usize;
+    let population = root["population"].as_array().ok_or_else(|| bad("no population"))?
+        .iter().map(member).collect::<Result<Vec<Member>, GaError>>()?;
+    let best = member(&root["best"])?;
+    Ok((generation, population, best))
+}
+
+
+fn write_checkpoint(dir: &Path, generation: usize, population: &[Member], best: &Member) -> Result<(), GaError> {
+    let io = |e: std::io::Error| GaError::Io(e.to_string());
+    std::fs::create_dir_all(dir).map_err(io)?;
+    let text = serde_json::to_string_pretty(&checkpoint_json(generation, population, best)).unwrap_or_default();
+    std::fs::write(dir.join(format!("generation_{:04}.json", generation)), &text).map_err(io)?;
+    std::fs::write(dir.join("latest.json"), &text).map_err(io)?;
+    std::fs::write(dir.join("best.toml"), best.params.to_toml()).map_err(io)
+}
+
+
+// ------------------------------------------- run -------------------------------------------
+
+
+// generation 0: the starting weights and mutated copies of them
+fn first_generation(start: &TaperedParams, config: &GaConfig) -> Vec<Member> {
+    (0..config.population).map(|i| {
+        let mut params = *start;
+        if i > 0 {
+            mutate(&mut params, config.mutation_rate.max(0.5), config.mutation_scale);
+        }
+        Member { params, fitness: 0.0 }
+    }).collect()
+}
+
+
+// Runs the tuner from the engine's active weights (or the checkpoint with `resume`)
+// and returns the best member found. The active weights are never changed.
+pub fn run(config: &GaConfig) -> Result<Member, GaError> {
+
+    let start = params::current();
+    let (mut generation, mut population, mut best) = if config.resume {
+        let text = std::fs::read_to_string(config.checkpoint.join("latest.json")).map_err(|e| GaError::Io(e.to_string()))?;
+        let (generation, population, best) = parse_checkpoint(&text)?;
+        println!(" | resuming after generation {} (best fitness {:.3})", generation, best.fitness);
+        (generation + 1, next_generation(&population, config), best)
+    } else {
+        (0, first_generation(&start, config), Member { params: start, fitness: 0.0 })
+    };
+
+    let end = if config.resume { generation + config.generations } else { config.generations };
+    while generation < end {
+        play_round(&mut population, config, generation);
+
+        let leader = population.iter().max_by(|a, b| a.fitness.total_cmp(&b.fitness)).expect("population").clone();
+        let average = population.iter().map(|m| m.fitness).sum::<f64>() / population.len() as f64;
+        println!(" | generation {}: best {:.3} average {:.3}", generation, leader.fitness, average);
+
+        // fitness is relative to the generation it was earned in, so the leader has to beat the best so far
+        if wins_playoff(&leader.params, &best.params, config) {
+            best = leader;
+        }
+        write_checkpoint(&config.checkpoint, generation, &population, &best)?;
+
+        generation += 1;
+        if generation < end {
+            population = next_generation(&population, config);
+        }
+    }
+
+    Ok(best)
+}
+
+
+// the best weights: fitness and every weight that differs from `start`
+pub fn report(best: &Member, start: &TaperedParams) {
+    println!(" | ");
+    println!(" |------------------------------BEST WEIGHTS-------------------------------|");
+    println!(" | fitness: {:.3}", best.fitness);
+    let before: HashMap<String, f32> = start.fields().into_iter().collect();
+    for (name, value) in best.params.fields() {
+        let old = before[&name];
+        if old != value {
+            println!(" | {:<36} {:>9.2}  (was {:.2})", name, value, old);
+        } else {
+            println!(" | {:<36} {:>9.2}", name, value);
+        }
+    }
+    println!(" |-------------------------------------------------------------------------|");
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::params::DEFAULT_TAPERED;
+
+    #[test]
+    fn breeding_keeps_weights_valid() {
+        let mut other = DEFAULT_TAPERED;
+        other.set("endgame.weights.king_vuln", 100.0).unwrap();
+        let child = crossover(&DEFAULT_TAPERED, &other);
+        assert!(child.endgame.king_vuln == 100.0 || child.endgame.king_vuln == DEFAULT_TAPERED.endgame.king_vuln);
+        assert_eq!(child.opening, DEFAULT_PARAMS);
+
+        let mut mutated = DEFAULT_TAPERED;
+        mutate(&mut mutated, 1.0, 5.0);
+        assert!(mutated.fields().iter().all(|(_, value)| *value >= 0.0));
+        assert_eq!(mutated.endgame.material[shogi_core::PieceKind::King.array_index()], 0.0);
+    }
+
+    #[test]
+    fn checkpoints_read_back() {
+        let config = GaConfig::parse(&["population", "4", "depth", "1", "resume"]).unwrap();
+        assert_eq!((config.population, config.limits.depth, config.resume), (4, Some(1), true));
+        assert!(GaConfig::parse(&["population", "1"]).is_err());
+
+        let mut population = first_generation(&DEFAULT_TAPERED, &config);
+        for (i, member) in population.iter_mut().enumerate() {
+            member.fitness = i as f64 / 4.0;
+        }
+        let best = population[3].clone();
+        let text = checkpoint_json(7, &population, &best).to_string();
+        assert_eq!(parse_checkpoint(&text).unwrap(), (7, population, best));
+    }
+
+    // one-ply searches and short games, so the tests stay quick
+    fn quick_config(population: usize) -> GaConfig {
+        let args = ["population", &population.to_string(), "opponents", "1", "depth", "1", "opening", "2", "plies", "4"].map(String::from);
+        GaConfig::parse(&args.iter().map(String::as_str).collect::<Vec<&str>>()).unwrap()
+    }
+
+    #[test]
+    fn games_are_decided_by_mate_or_material() {
+        let config = quick_config(2);
+        let before = params::current();
+
+        // already over
+        let mated_white = Position::from_sfen("4k4/4G4/4G4/9/9/9/9/9/4K4 w - 1");
+        assert_eq!(play_game(&mated_white, &DEFAULT_TAPERED, &DEFAULT_TAPERED, &config), 1.0);
+        let mated_black = Position::from_sfen("4k4/9/9/9/9/9/4g4/4g4/4K4 b - 1");
+        assert_eq!(play_game(&mated_black, &DEFAULT_TAPERED, &DEFAULT_TAPERED, &config), 0.0);
+
+        // four plies can't undo a rook and a bishop ahead
+        let ahead = Position::from_sfen("4k4/9/9/9/9/9/9/1B5R1/4K4 b - 1");
+        assert_eq!(play_game(&ahead, &DEFAULT_TAPERED, &DEFAULT_TAPERED, &config), 1.0);
+
+        // the weights are handed to the search, the engine's own are left as they were
+        assert_eq!(params::current(), before);
+    }
+
+    #[test]
+    fn a_round_shares_out_every_point() {
+        let config = quick_config(2);
+        let mut population = first_generation(&DEFAULT_TAPERED, &config);
+        play_round(&mut population, &config, 0);
+
+        // two pairs of games between the only two members, 4 games each
+        assert!(population.iter().all(|member| (0.0..=1.0).contains(&member.fitness)));
+        assert!((population[0].fitness + population[1].fitness - 1.0).abs() < 1e-9);
+    }
+}
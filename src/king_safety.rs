@@ -0,0 +1,176 @@
+/* King safety: how hard one side is attacking the other's king
+ *
+ * eval::enemy_king_vuln looks at the 8 squares around the king and the pieces
+ * on the board only. This term looks further:
+ *
+ *    zone           the king's square, the squares around it and the three
+ *                   squares two ranks in front of it (where attacks come from)
+ *    attackers      every attacking piece that hits the zone, weighted by its
+ *                   kind ([zone_attackers])
+ *    zone squares   zone squares the attacker hits
+ *    safe checks    checks by a board move or a lance/bishop/rook drop landing
+ *                   on a safe square (promotions and discovered checks aren't
+ *                   counted)
+ *    drop threats   safe checking drops of a gold, silver or knight in hand,
+ *                   which are what usually breaks a castle open
+ *
+ * A square is safe when no defending piece but the king covers it and, next
+ * to the king, something else of the attacker's backs up the piece landing
+ * there, so the king can't take it either.
+ *
+ * The weighted counts add up to attack units, and the term grows with their
+ * square (capped at MAX_UNITS) times the [king_safety] weight: one attacker
+ * does little, several together are dangerous.
+ */
+
+use crate::attacks;
+use crate::bitboard::Bitboard;
+use crate::params::{self, EvalParams};
+use crate::position::Position;
+use shogi_core::{Color, Piece, PieceKind, Square};
+
+
+// attack units beyond this don't add anything
+pub const MAX_UNITS: f32 = 40.0;
+
+// the hand pieces whose checking drops are drop threats, in [king_safety] drop_* order
+pub const DROP_KINDS: [PieceKind; 3] = [PieceKind::Gold, PieceKind::Silver, PieceKind::Knight];
+
+
+// what `side` has aimed at its opponent's king
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct KingAttack {
+    pub attackers: [u32; PieceKind::NUM],  // pieces hitting the zone, by kind
+    pub zone_squares: u32,
+    pub safe_checks: u32,
+    pub drops: [u32; 3],                   // safe checking drop squares, like DROP_KINDS
+}
+
+
+// the king's square, its neighbours and the three squares two ranks in front of it
+fn zone(king: Square, color: Color) -> Bitboard {
+    let mut zone = attacks::piece_attacks(Piece::new(PieceKind::King, color), king, Bitboard::EMPTY) | Bitboard::from_square(king);
+    let forward = if color == Color::Black { -2 } else { 2 };
+    for file_delta in -1..=1 {
+        if let Some(square) = king.shift(file_delta, forward) {
+            zone.set(square);
+        }
+    }
+    zone
+}
+
+
+impl KingAttack {
+
+    pub fn compute(pos: &Position, side: Color) -> KingAttack {
+
+        let defender = side.flip();
+        let mut attack = KingAttack::default();
+        let Some(king) = pos.king_square(defender) else {
+            return attack;
+        };
+
+        let zone = zone(king, defender);
+        let occupied = pos.occupied();
+        let own = pos.color_bitboard(side);
+        let near = attacks::piece_attacks(Piece::new(PieceKind::King, defender), king, Bitboard::EMPTY);
+        let (defence, support) = (pos.attack_counts(defender), pos.attack_counts(side));
+        // `supporters` attackers of the square needed next to the king (the moving piece counts itself)
+        let safe = |squares: Bitboard, supporters: u8| -> u32 {
+            squares.filter(|&s| {
+                let i = s.array_index();
+                let guards = defence[i] - near.contains(s) as u8;
+                guards == 0 && (!near.contains(s) || support[i] >= supporters)
+            }).count() as u32
+        };
+        // squares from which a `kind` of ours attacks the king: the same piece of theirs on the king's square attacks them
+        let checks_from = |kind: PieceKind| attacks::piece_attacks(Piece::new(kind, defender), king, occupied);
+
+        for square in own {
+            let Some(piece) = pos.piece_at(square) else { continue };
+            let kind = piece.piece_kind();
+            if kind == PieceKind::King {
+                continue;
+            }
+            let reach = attacks::piece_attacks(piece, square, occupied);
+            if !(reach & zone).is_empty() {
+                attack.attackers[kind.array_index()] += 1;
+            }
+            attack.safe_checks += safe(reach & !own & checks_from(kind), 2);
+        }
+        attack.zone_squares = (pos.attack_map(side) & zone).count();
+
+        let empty = !occupied;
+        for kind in [PieceKind::Lance, PieceKind::Bishop, PieceKind::Rook] {
+            if pos.hand(side, kind) > 0 {
+                attack.safe_checks += safe(empty & checks_from(kind), 1);
+            }
+        }
+        for (drops, kind) in attack.drops.iter_mut().zip(DROP_KINDS) {
+            if pos.hand(side, kind) > 0 {
+                *drops = safe(empty & checks_from(kind), 1);
+            }
+        }
+        attack
+    }
+
+    // (parameter name, count) of everything the attack units are made of
+    pub fn counts(&self) -> Vec<(String, f32)> {
+        let mut counts: Vec<(String, f32)> = PieceKind::all().into_iter()
+            .filter(|&kind| kind != PieceKind::King)
+            .map(|kind| (format!("zone_attackers.{}", params::kind_name(kind)), self.attackers[kind.array_index()] as f32))
+            .collect();
+        counts.push(("king_safety.zone_squares".to_string(), self.zone_squares as f32));
+        counts.push(("king_safety.safe_checks".to_string(), self.safe_checks as f32));
+        for (kind, &drops) in DROP_KINDS.iter().zip(&self.drops) {
+            counts.push((format!("king_safety.drop_{}", params::kind_name(*kind)), drops as f32));
+        }
+        counts
+    }
+
+    // the weighted counts, capped at MAX_UNITS
+    pub fn units(&self, params: &EvalParams) -> f32 {
+        let attackers: f32 = PieceKind::all().iter().zip(self.attackers)
+            .map(|(kind, count)| count as f32 * params.zone_attackers[kind.array_index()])
+            .sum();
+        let drops: f32 = self.drops.iter().zip(params.safety_drops).map(|(&count, weight)| count as f32 * weight).sum();
+        let units = attackers
+            + self.zone_squares as f32 * params.safety_zone_squares
+            + self.safe_checks as f32 * params.safety_checks
+            + drops;
+        units.clamp(0.0, MAX_UNITS)
+    }
+
+    // the king safety term before weighing, units squared
+    pub fn danger(&self, params: &EvalParams) -> f32 {
+        self.units(params).powi(2)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_zone_attacks_checks_and_drops() {
+        assert_eq!(KingAttack::compute(&Position::startpos(), Color::Black), KingAttack::default());
+
+        // white king on 5a, black rook on 5e: it hits 5c, 5b and 5a in the zone and
+        // checks from 5b (the king takes), 5c and 5d
+        let pos = Position::from_sfen("4k4/9/9/9/4R4/9/9/9/4K4 b G 1");
+        let attack = KingAttack::compute(&pos, Color::Black);
+        assert_eq!(attack.attackers[PieceKind::Rook.array_index()], 1);
+        assert_eq!(attack.zone_squares, 3);
+        assert_eq!(attack.safe_checks, 2);
+        // of the gold drops next to the king only 5b is backed up (by the rook)
+        assert_eq!(attack.drops, [1, 0, 0]);
+
+        // a silver on 5c backs up gold drops on 4b, 5b and 6b but can't check safely itself
+        let supported = KingAttack::compute(&Position::from_sfen("4k4/9/4S4/9/9/9/9/9/4K4 b G 1"), Color::Black);
+        assert_eq!((supported.safe_checks, supported.drops), (0, [3, 0, 0]));
+
+        let flipped = pos.flipped();
+        assert_eq!(KingAttack::compute(&flipped, Color::White), attack);
+    }
+}
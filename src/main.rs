@@ -7,10 +7,35 @@ mod search;
 mod tree;
 mod play;
 mod book;
+mod ponder;
+mod usi;
+mod tsume;
+mod bitboard;
+mod position;
+mod attacks;
+mod movegen;
+mod perft;
+mod validate;
+mod zobrist;
+mod evalcache;
+mod pst;
+mod castle;
+mod king_safety;
+mod phase;
+mod structure;
+mod nnue;
+mod nnue_train;
+mod params;
+mod genetic;
+mod texel;
 
 use shogi::color;
 use shogi_legality_lite::{normal_from_candidates, is_legal_partial_lite, all_legal_moves_partial};
 use shogi_core::{PartialPosition, Square, Piece, Color, Move, PieceKind};
+use shogi_core::ToUsi;
+use position::Position;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 
 
@@ -56,7 +81,73 @@ fn main() {
     
     //play::play_book();
     //play::play_OG();
-    play::play_bots();
+
+    // `rusty_engine usi` talks to a GUI, `rusty_engine play` plays against a human,
+    // `rusty_engine go [depth N] [nodes N] [movetime MS] [infinite] [mate N] [sfen <sfen>]` searches once,
+    // `rusty_engine tsume [nodes N] [movetime MS] <sfen>` solves a tsume-shogi problem (within tsume::DEFAULT_NODES by default),
+    // `rusty_engine perft [depth [sfen]]` counts move tree leaves (no depth: the test suite),
+    // `rusty_engine divide <depth> [sfen]` splits a perft count by first move,
+    // `rusty_engine eval [--explain] [--json] [sfen]` evaluates a position, term by term with --explain (a table) or --json,
+    // `rusty_engine params [--json]` prints the evaluation weights (a template for a weights file),
+    // `rusty_engine tune [population N] [generations N] [depth N] [checkpoint DIR] [resume] ...` evolves them (see genetic.rs),
+    // `rusty_engine texel <positions> [epochs N] [rate X] [k X] [out FILE]` fits them to game results (see texel.rs),
+    // `rusty_engine train <records> [epochs N] [batch N] [rate X] [optimizer adam|sgd] [lambda X] [scale X] [l1 N] [l2 N] [l3 N]
+    //     [checkpoint DIR] [resume]` trains an NNUE network on scored positions (see nnue_train.rs);
+    // `--eval-file <toml/json>` before any command evaluates with the weights in that file,
+    // `--safe-mobility` with mobility counting only squares no cheaper enemy piece attacks,
+    // `--nnue <file>` with the network in that file instead (see nnue.rs)
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    loop {
+        match args.first().map(String::as_str) {
+            Some("--eval-file") => {
+                let Some(path) = args.get(1).cloned() else {
+                    println!(" | --eval-file needs a file");
+                    return;
+                };
+                match params::TaperedParams::load(&path) {
+                    Ok(loaded) => params::set(loaded),
+                    Err(err) => {
+                        println!(" | {}: {}", path, err);
+                        return;
+                    },
+                }
+                args.drain(..2);
+            },
+            Some("--safe-mobility") => {
+                eval::set_safe_mobility(true);
+                args.remove(0);
+            },
+            Some("--nnue") => {
+                let Some(path) = args.get(1).cloned() else {
+                    println!(" | --nnue needs a file");
+                    return;
+                };
+                match nnue::Network::load(&path) {
+                    Ok(net) => nnue::set_network(Some(net)),
+                    Err(err) => {
+                        println!(" | {}: {}", path, err);
+                        return;
+                    },
+                }
+                args.drain(..2);
+            },
+            _ => break,
+        }
+    }
+    match args.first().map(String::as_str) {
+        Some("usi") => usi::run(),
+        Some("play") => play::play_OG(),
+        Some("go") => go(&args[1..]),
+        Some("tsume") => tsume(&args[1..]),
+        Some("perft") => perft_command(&args[1..], false),
+        Some("divide") => perft_command(&args[1..], true),
+        Some("eval") => eval_command(&args[1..]),
+        Some("params") => params_command(&args[1..]),
+        Some("tune") => tune_command(&args[1..]),
+        Some("texel") => texel_command(&args[1..]),
+        Some("train") => train_command(&args[1..]),
+        _ => play::play_bots(),
+    }
 
     //play::play_one_move();
     //book::read_file_test("/Users/russell/research/rusty-shogi-engine/src/formatted_openings.txt")
@@ -64,6 +155,248 @@ fn main() {
 }
 
 
+// one search from the command line, limits use the same words as the USI `go` command
+fn go(args: &[String]) {
+
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let sfen_at = args.iter().position(|&a| a == "sfen").unwrap_or(args.len());
+    let start = "lnsgkgsnl/1r5b1/ppppppppp/9/9/9/PPPPPPPPP/1B5R1/LNSGKGSNL b - 1".to_string();
+    let sfen = if sfen_at < args.len() { args[sfen_at + 1..].join(" ") } else { start };
+    let limits = match search::SearchLimits::parse_cli(&args[..sfen_at]) {
+        Ok(limits) => limits,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        },
+    };
+
+    // a tsume problem (no king for the side to move) can only be searched for a mate
+    let options = validate::ValidateOptions { tsume: limits.mate.is_some() };
+    match sfen::parse(&sfen).map(|pos| validate::validate(&pos, options)) {
+        Err(err) => return println!("invalid sfen: {}", err),
+        Ok(Err(violations)) => return println!("invalid position: {}", validate::describe(&violations)),
+        Ok(Ok(())) => {},
+    }
+
+    // an infinite search runs until enter is pressed
+    let stop = Arc::new(AtomicBool::new(false));
+    if limits.infinite {
+        let stop = Arc::clone(&stop);
+        std::thread::spawn(move || {
+            let _ = std::io::stdin().read_line(&mut String::new());
+            stop.store(true, Ordering::Relaxed);
+        });
+    }
+
+    view::display_sfen(&sfen);
+    let result = search::think(&sfen, &limits, &stop);
+
+    if limits.mate.is_some() {
+        match result.mate {
+            Some(line) => println!("mate in {}: {:?}", line.len().div_ceil(2), line),
+            None if result.mate_timeout => println!("no mate found before the limits ran out"),
+            None => println!("no mate found"),
+        }
+    } else {
+        println!("best move: {:?}", result.best_move);
+        println!("ponder move: {:?}", result.ponder_move);
+        println!("score (white, black): {:?}", result.score);
+        match result.mate_plies {
+            Some(plies) if plies > 0 => println!("mate in {} ({} plies)", (plies + 1) / 2, plies),
+            Some(plies) => println!("mated in {} ({} plies)", -plies / 2, -plies),
+            None => {},
+        }
+    }
+    println!("depth: {} nodes: {} time: {:?}", result.depth, result.nodes, result.time);
+    println!("eval cache: {} hits / {} probes ({:.1}%)", result.eval_cache.hits, result.eval_cache.probes, result.eval_cache.hit_rate());
+
+}
+
+
+// the static evaluation of a position (the start position without one)
+fn eval_command(args: &[String]) {
+
+    let explain = args.iter().any(|arg| arg == "--explain");
+    let json = args.iter().any(|arg| arg == "--json");
+    let words: Vec<&str> = args.iter().map(String::as_str).filter(|arg| !arg.starts_with("--")).collect();
+    let start = "lnsgkgsnl/1r5b1/ppppppppp/9/9/9/PPPPPPPPP/1B5R1/LNSGKGSNL b - 1".to_string();
+    let sfen = if words.is_empty() { start } else { words.join(" ") };
+    let pos = match sfen::parse(&sfen).map(|pos| validate::validate(&pos, validate::ValidateOptions::default()).map(|()| pos)) {
+        Err(err) => return println!(" | invalid sfen: {}", err),
+        Ok(Err(violations)) => return println!(" | invalid position: {}", validate::describe(&violations)),
+        Ok(Ok(pos)) => pos,
+    };
+
+    // with a network loaded its score (for the side to move) comes too, inside the JSON with --json
+    let trace = eval::EvalTrace::new(&pos, &params::current());
+    let nnue_score = nnue::evaluate(&pos);
+    if json {
+        let mut json = trace.to_json();
+        if let Some(score) = nnue_score {
+            json["nnue"] = serde_json::json!(score);
+        }
+        return println!("{}", serde_json::to_string_pretty(&json).unwrap_or_default());
+    }
+    if explain {
+        view::display_sfen(&sfen);
+        println!("{}", trace.table());
+    } else {
+        println!(" | white: {} black: {} (black - white: {})", trace.white, trace.black, trace.black - trace.white);
+    }
+    if let Some(score) = nnue_score {
+        println!(" | nnue: {} for the side to move", score);
+    }
+}
+
+
+// the weights in use, as TOML (or JSON with --json)
+fn params_command(args: &[String]) {
+    let current = params::current();
+    if args.iter().any(|arg| arg == "--json") {
+        println!("{}", serde_json::to_string_pretty(&current.to_json()).unwrap_or_default());
+    } else {
+        print!("{}", current.to_toml());
+    }
+}
+
+
+// the genetic algorithm tuner, starting from the weights in use
+fn tune_command(args: &[String]) {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let config = match genetic::GaConfig::parse(&args) {
+        Ok(config) => config,
+        Err(e) => return println!(" | {}", e),
+    };
+    let start = params::current();
+    match genetic::run(&config) {
+        Ok(best) => {
+            genetic::report(&best, &start);
+            println!(" | written to {}", config.checkpoint.join("best.toml").display());
+        },
+        Err(err) => println!(" | {}", err),
+    }
+}
+
+
+// Texel tuning on a file of labelled positions, starting from the weights in use
+fn texel_command(args: &[String]) {
+    let Some(path) = args.first() else {
+        return println!(" | texel needs a file of <sfen> <result> lines");
+    };
+    let options: Vec<&str> = args[1..].iter().map(String::as_str).collect();
+    let config = match texel::TexelConfig::parse(&options) {
+        Ok(config) => config,
+        Err(e) => return println!(" | {}", e),
+    };
+    let samples = match texel::load_positions(path) {
+        Ok(samples) => samples,
+        Err(e) => return println!(" | {}: {}", path, e),
+    };
+    let start = params::current();
+    match texel::tune(&samples, &start, &config) {
+        Ok(result) => {
+            texel::report(&result, &start);
+            match std::fs::write(&config.out, result.params.to_toml()) {
+                Ok(()) => println!(" | written to {}", config.out.display()),
+                Err(e) => println!(" | {}: {}", config.out.display(), e),
+            }
+        },
+        Err(e) => println!(" | {}", e),
+    }
+}
+
+
+// NNUE training on a file of scored positions, from scratch or from the last checkpoint
+fn train_command(args: &[String]) {
+    let Some(path) = args.first() else {
+        return println!(" | train needs a file of <sfen> <score> <result> lines");
+    };
+    let options: Vec<&str> = args[1..].iter().map(String::as_str).collect();
+    let config = match nnue_train::TrainConfig::parse(&options) {
+        Ok(config) => config,
+        Err(e) => return println!(" | {}", e),
+    };
+    let records = match nnue_train::load_records(path) {
+        Ok(records) => records,
+        Err(e) => return println!(" | {}: {}", path, e),
+    };
+    match nnue_train::train(&records, &config) {
+        Ok(trainer) => println!(" | {} epochs, network written to {}", trainer.epoch, config.checkpoint.join("network.nnue").display()),
+        Err(e) => println!(" | {}", e),
+    }
+}
+
+
+// perft / divide from the command line, the start position when no sfen is given
+fn perft_command(args: &[String], divide: bool) {
+
+    let Some(depth) = args.first() else {
+        let ok = perft::run_suite(u32::MAX);
+        println!(" | {}", if ok { "all counts match" } else { "MISMATCH" });
+        return;
+    };
+    let Ok(depth) = depth.parse::<u32>() else {
+        println!(" | bad depth: {}", depth);
+        return;
+    };
+    let start = "lnsgkgsnl/1r5b1/ppppppppp/9/9/9/PPPPPPPPP/1B5R1/LNSGKGSNL b - 1".to_string();
+    let sfen = if args.len() > 1 { args[1..].join(" ") } else { start };
+    match sfen::parse(&sfen).map(|pos| validate::validate(&pos, validate::ValidateOptions::default())) {
+        Err(err) => return println!(" | invalid sfen: {}", err),
+        Ok(Err(violations)) => return println!(" | invalid position: {}", validate::describe(&violations)),
+        Ok(Ok(())) => {},
+    }
+
+    if divide {
+        perft::print_divide(&sfen, depth);
+    } else {
+        let start = std::time::Instant::now();
+        let nodes = perft::perft(&mut Position::from_sfen(&sfen), depth);
+        println!(" | perft({}) = {} time: {:?}", depth, nodes, start.elapsed());
+    }
+}
+
+
+// solves a tsume problem: the defender holds every piece not on the board or in the attacker's hand
+fn tsume(args: &[String]) {
+
+    // leading `nodes N` / `movetime MS` replace the default node budget
+    let limit_words = args.chunks(2).take_while(|pair| matches!(pair[0].as_str(), "nodes" | "movetime")).count() * 2;
+    let words: Vec<&str> = args[..limit_words].iter().map(String::as_str).collect();
+    let mut limits = match search::SearchLimits::parse_cli(&words) {
+        Ok(limits) => limits,
+        Err(e) => return println!("{}", e),
+    };
+    if limits.nodes.is_none() && limits.movetime.is_none() {
+        limits.nodes = Some(tsume::DEFAULT_NODES);
+    }
+    let sfen = &args[limit_words..].join(" ");
+
+    let pos = match sfen::parse(sfen) {
+        Ok(pos) => pos,
+        Err(err) => return println!("invalid sfen: {}", err),
+    };
+    if let Err(violations) = validate::validate(&pos, validate::ValidateOptions { tsume: true }) {
+        return println!("invalid position: {}", validate::describe(&violations));
+    }
+    let pos = pos.to_partial();
+    view::display_sfen(sfen);
+    let stop = AtomicBool::new(false);
+    let ctl = search::SearchControl::new(&limits, &stop);
+
+    match tsume::Solver::new(&ctl).solve(&pos, tsume::TsumeOptions::default()) {
+        tsume::TsumeResult::Mate(line) => {
+            let moves: Vec<String> = line.iter().map(|mv| mv.to_usi_owned()).collect();
+            println!("mate in {} plies: {}", line.len(), moves.join(" "));
+        },
+        tsume::TsumeResult::NoMate => println!("no mate"),
+        tsume::TsumeResult::Unknown => println!("unknown (out of nodes or time, see `nodes N` / `movetime MS`)"),
+    }
+    println!("nodes: {} time: {:?}", ctl.nodes(), ctl.elapsed());
+
+}
+
+
 fn search_test() {
     
     let start = "lnsgkgsnl/1r5b1/ppppppppp/9/9/9/PPPPPPPPP/1B5R1/LNSGKGSNL b - 1";
@@ -108,7 +441,8 @@ fn king_vuln_test() {
     println!("SFEN: {:?}", sfen);
     view::display_sfen(sfen);
 
-    let king_vuln = eval::enemy_king_vuln(sfen, Color::White);
+    let pos = Position::from_sfen(sfen);
+    let king_vuln = eval::enemy_king_vuln(&pos, Color::White, &params::current().at(phase::phase(&pos)));
     println!("KING VULN: {:?}", king_vuln);
 
 }
@@ -178,36 +512,13 @@ fn coord_test() {
     let sfen = "lnsgkgsnl/1r5b1/ppppppppp/9/9/9/PPPPPPPPP/1B5R1/LNSGKGSNL b - 1";
     view::display_sfen(&sfen);
 
-    let positions = sfen::sfen_parse(sfen);// creates list of board squares and the pieces on them (if there are any)
-    let mut pos = sfen::generate_pos(positions.clone()); // creates a "partial position" out of it
-    pos.side_to_move_set(sfen::get_color(sfen));
-    
-    println!("Positions: {:?}", positions);
-
-    let mut white_rook_mobil = 0;
-    let mut black_rook_mobil = 0;
-    
-    for sqr in &positions {
-       
-        if sqr.1 == "W_R" {
-            pos.side_to_move_set(Color::White);
-            let coord = &sqr.0;
-            println!("COORD: {:?}", coord);
-            let sfen = pos.to_sfen_owned();
-            let mobil = eval::mobility(&sfen, coord.to_string());
-            white_rook_mobil += mobil;
-            println!("MOBILITY: {:?}", mobil);
-
-        } else if sqr.1 == "B_R" {
-            pos.side_to_move_set(Color::Black);
-            let coord = &sqr.0;
-            println!("COORD: {:?}", coord);
-            let sfen = pos.to_sfen_owned();
-            let mobil = eval::mobility(&sfen, coord.to_string());
-            black_rook_mobil += mobil;
-            println!("MOBILITY: {:?}", mobil);
-        }
+    let pos = Position::from_sfen(sfen);
+
+    for square in pos.piece_bitboard(Piece::W_R) | pos.piece_bitboard(Piece::B_R) {
+        println!("COORD: {:?}", square);
     }
+    let white_rook_mobil = eval::piece_mobility(&pos, Color::White)[PieceKind::Rook.array_index()];
+    let black_rook_mobil = eval::piece_mobility(&pos, Color::Black)[PieceKind::Rook.array_index()];
 
     println!("Final White Mobility: {:?}", white_rook_mobil);
     println!("Final Black Mobility: {:?}", black_rook_mobil);
@@ -220,17 +531,13 @@ fn mobility_tests() {
     let sfen = "lnsgkgsnl/1r5b1/ppppppppp/9/9/9/PP1PPPPPP/1B5R1/LNSGKGSNL b - 1";
     view::display_sfen(sfen);
 
-    let (wr_mob, br_mob) = eval::rook_mobility(&sfen);
-    println!("white rook mobil: {:?}", wr_mob);
-    println!("black rook mobil: {:?}", br_mob);
-    
-    let (wl_mob, bl_mob) = eval::lance_mobility(&sfen);
-    println!("white lance mobil: {:?}", wl_mob);
-    println!("black lance mobil: {:?}", bl_mob);
+    let pos = Position::from_sfen(sfen);
 
-    let (wb_mob, bb_mob) = eval::bishop_mobility(&sfen);
-    println!("white bishop mobil: {:?}", wb_mob);
-    println!("black bishop mobil: {:?}", bb_mob);
+    let (white_mob, black_mob) = (eval::piece_mobility(&pos, Color::White), eval::piece_mobility(&pos, Color::Black));
+    for kind in PieceKind::all() {
+        let i = kind.array_index();
+        println!("{:?} mobil: white {:?} black {:?}", kind, white_mob[i], black_mob[i]);
+    }
 
 }
 
@@ -247,7 +554,8 @@ fn hand_test() {
     println!("SFEN: {:?}", sfen);
 
         
-    let (white_hand, black_hand) = eval::eval_hand(&sfen);
+    let hand_pos = Position::from_sfen(sfen);
+    let (white_hand, black_hand) = eval::eval_hand(&hand_pos, &params::current().at(phase::phase(&hand_pos)));
     
     println!("white hand: {:?}", white_hand);
     println!("black hand: {:?}", black_hand);
@@ -273,37 +581,12 @@ fn eval2_test() {
     println!("sfen: {:?}", sfen);
     view::display_sfen(&sfen);
     
-    let ((white_fitness, black_fitness), best_features) = eval::evaluate2(&sfen);
+    let trace = eval::EvalTrace::new(&Position::from_sfen(sfen), &params::current());
     
-    println!("white fitness: {:?}", white_fitness);
-    println!("black fitness: {:?}", black_fitness);
-    
-    let (white_promoted_pieces, black_promoted_pieces) = best_features[0];
-    let (white_pst, black_pst) = best_features[1];
-    let (white_king_vln, black_king_vln) = best_features[2];
-    let (white_rook_mobil, black_rook_mobil) = best_features[3];
-    let (white_lance_mobil, black_lance_mobil) = best_features[4];
-    let (white_bish_mobil, black_bish_mobil) = best_features[5];
-    let (white_hand, black_hand) = best_features[6];
-
+    println!("white fitness: {:?}", trace.white);
+    println!("black fitness: {:?}", trace.black);
     println!(" | feature variate values: ");
-    println!(" |    |WHITE|");
-    println!(" | white_promoted_pieces: {:?}", white_promoted_pieces);
-    println!(" | white_pst: {:?}", white_pst);
-    println!(" | white_king_vln: {:?}", white_king_vln);
-    println!(" | white_rook_mobil: {:?}", white_rook_mobil);
-    println!(" | white_lance_mobil: {:?}", white_lance_mobil);
-    println!(" | white_bish_mobil: {:?}", white_bish_mobil);
-    println!(" | white_hand: {:?}", white_hand);
-    println!(" | ");
-    println!(" |    |BLACK|");
-    println!(" | black_promoted_pieces: {:?}", black_promoted_pieces);
-    println!(" | black_pst: {:?}", black_pst);
-    println!(" | black_king_vln: {:?}", black_king_vln);
-    println!(" | black_rook_mobil: {:?}", black_rook_mobil);
-    println!(" | black_lance_mobil: {:?}", black_lance_mobil);
-    println!(" | black_bish_mobil: {:?}", black_bish_mobil);
-    println!(" | black_hand: {:?}", black_hand);
+    println!("{}", trace.table());
     println!(" | ");
 
 }
@@ -396,7 +679,7 @@ pub fn test_tree_search() {
     
     for node in &moves {
         println!("{:?}", node);
-        println!("sfen: {:?}", node.sfen);
+        println!("sfen: {:?}", node.pos.to_sfen());
         println!("---------------------------------------------------------------------------------");
     }
 
@@ -0,0 +1,397 @@
+/* Move generation
+ *
+ * Moves are written into a MoveList, a fixed size buffer on the stack, so
+ * generating moves never allocates. Generation is split into stages that can
+ * be asked for separately:
+ *
+ *  - captures:  board moves taking an enemy piece
+ *  - quiets:    board moves to an empty square
+ *  - drops:     pieces from hand onto empty squares
+ *  - evasions:  moves that might get the side to move out of check
+ *  - checks:    moves that give check
+ *
+ * The stages produce pseudo-legal moves: every shogi rule holds (forced
+ * promotion, no dead pieces, nifu) except that the mover's own king may be
+ * left in check and a pawn drop may be mate. is_legal sorts those out, and
+ * generate_legal does all of it at once.
+ *
+ * generate_legal returns the moves in the same order as
+ * shogi_legality_lite::all_legal_moves_partial (by from square, unpromoted
+ * before promoted, then drops by piece), so the search sees the same move
+ * ordering it always did.
+ */
+
+use crate::attacks::{attackers_to, between, piece_attacks};
+use crate::bitboard::Bitboard;
+use crate::position::{Position, HAND_KINDS};
+use shogi_core::{Move, Piece, PieceKind, Square};
+
+
+// The most legal moves known in any shogi position is 593. Pseudo-legal lists
+// can be a little longer, this leaves plenty of room.
+pub const MAX_MOVES: usize = 1024;
+
+// filler for the unused part of a MoveList, never read
+const NO_MOVE: Move = Move::Drop { piece: Piece::B_P, to: Square::SQ_5E };
+
+
+pub struct MoveList {
+    moves: [Move; MAX_MOVES],
+    len: usize,
+}
+
+
+impl MoveList {
+
+    pub fn new() -> MoveList {
+        MoveList { moves: [NO_MOVE; MAX_MOVES], len: 0 }
+    }
+
+    pub fn push(&mut self, mv: Move) {
+        self.moves[self.len] = mv;
+        self.len += 1;
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_slice(&self) -> &[Move] {
+        &self.moves[..self.len]
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Move> + '_ {
+        self.as_slice().iter().copied()
+    }
+}
+
+
+impl Default for MoveList {
+    fn default() -> Self {
+        MoveList::new()
+    }
+}
+
+
+// ------------------------------------------ helpers ------------------------------------------
+
+// Adds the moves of `piece` from `from` to each of `targets`: unpromoted moves
+// first (unless the piece would be left without a move), then promotions.
+fn push_board_moves(list: &mut MoveList, piece: Piece, from: Square, targets: Bitboard) {
+
+    let side = piece.color();
+    let kind = piece.piece_kind();
+    let promotable = kind.promote().is_some();
+
+    for to in targets {
+        let rank = to.relative_rank(side);
+        let must_promote = match kind {
+            PieceKind::Pawn | PieceKind::Lance => rank == 1,
+            PieceKind::Knight => rank <= 2,
+            _ => false,
+        };
+        if !must_promote {
+            list.push(Move::Normal { from, to, promote: false });
+        }
+    }
+
+    if promotable {
+        let from_zone = from.relative_rank(side) <= 3;
+        for to in targets {
+            if from_zone || to.relative_rank(side) <= 3 {
+                list.push(Move::Normal { from, to, promote: true });
+            }
+        }
+    }
+}
+
+
+// board moves of the side to move (king included) that land on `targets`
+fn generate_board_moves(pos: &Position, targets: Bitboard, list: &mut MoveList) {
+    let side = pos.side_to_move();
+    let occupied = pos.occupied();
+    for from in pos.color_bitboard(side) {
+        let piece = pos.piece_at(from).expect("movegen: colour bitboard out of sync with the board");
+        push_board_moves(list, piece, from, piece_attacks(piece, from, occupied) & targets);
+    }
+}
+
+
+// drops of the side to move onto `targets` (which must be empty squares)
+fn generate_drops_to(pos: &Position, targets: Bitboard, list: &mut MoveList) {
+
+    let side = pos.side_to_move();
+
+    // nifu: files that already hold one of our unpromoted pawns
+    let mut pawn_files = [false; 10];
+    for square in pos.piece_bitboard(Piece::new(PieceKind::Pawn, side)) {
+        pawn_files[square.file() as usize] = true;
+    }
+
+    for kind in HAND_KINDS {
+        if pos.hand(side, kind) == 0 {
+            continue;
+        }
+        let piece = Piece::new(kind, side);
+        for to in targets {
+            let rank = to.relative_rank(side);
+            let allowed = match kind {
+                PieceKind::Pawn => rank > 1 && !pawn_files[to.file() as usize],
+                PieceKind::Lance => rank > 1,
+                PieceKind::Knight => rank > 2,
+                _ => true,
+            };
+            if allowed {
+                list.push(Move::Drop { piece, to });
+            }
+        }
+    }
+}
+
+
+// every square we may move to: empty or enemy, but never the enemy king
+fn board_targets(pos: &Position) -> Bitboard {
+    let side = pos.side_to_move();
+    !pos.color_bitboard(side) & !pos.kind_bitboard(PieceKind::King)
+}
+
+
+// would the mover's own king be attacked after the pseudo-legal move `mv`?
+fn leaves_king_attacked(pos: &Position, mv: Move) -> bool {
+
+    let side = pos.side_to_move();
+    let Some(king) = pos.king_square(side) else { return false };
+    let occupied = pos.occupied();
+
+    match mv {
+        Move::Normal { from, to, .. } => {
+            let king = if from == king { to } else { king };
+            let after = (occupied & !Bitboard::from_square(from)) | Bitboard::from_square(to);
+            // a piece captured on `to` no longer attacks anything
+            let attackers = attackers_to(pos, king, side.flip(), after) & !Bitboard::from_square(to);
+            !attackers.is_empty()
+        },
+        Move::Drop { to, .. } => {
+            let after = occupied | Bitboard::from_square(to);
+            !attackers_to(pos, king, side.flip(), after).is_empty()
+        },
+    }
+}
+
+
+// does the side to move have any legal move at all?
+fn has_legal_move(pos: &Position) -> bool {
+    let mut list = MoveList::new();
+    if pos.is_in_check() {
+        generate_evasions(pos, &mut list);
+    } else {
+        generate_captures(pos, &mut list);
+        generate_quiets(pos, &mut list);
+        generate_drops(pos, &mut list);
+    }
+    let found = list.iter().any(|mv| is_legal(pos, mv));
+    found
+}
+
+
+// ------------------------------------------ stages ------------------------------------------
+
+pub fn generate_captures(pos: &Position, list: &mut MoveList) {
+    let enemy = pos.color_bitboard(pos.side_to_move().flip());
+    generate_board_moves(pos, enemy & board_targets(pos), list);
+}
+
+
+pub fn generate_quiets(pos: &Position, list: &mut MoveList) {
+    generate_board_moves(pos, !pos.occupied(), list);
+}
+
+
+pub fn generate_drops(pos: &Position, list: &mut MoveList) {
+    generate_drops_to(pos, !pos.occupied(), list);
+}
+
+
+// For a side in check: king moves, and against a single checker, capturing it
+// or putting a piece (moved or dropped) in the way.
+pub fn generate_evasions(pos: &Position, list: &mut MoveList) {
+
+    let side = pos.side_to_move();
+    let Some(king) = pos.king_square(side) else { return };
+    let checkers = pos.checkers();
+    let occupied = pos.occupied();
+
+    let king_piece = Piece::new(PieceKind::King, side);
+    push_board_moves(list, king_piece, king, piece_attacks(king_piece, king, occupied) & board_targets(pos));
+
+    // against a double check only the king can move
+    if checkers.count() != 1 {
+        return;
+    }
+    let checker = checkers.first().expect("one checker");
+    let block = between(king, checker);
+    let targets = block | checkers;
+
+    for from in pos.color_bitboard(side) & !Bitboard::from_square(king) {
+        let piece = pos.piece_at(from).expect("movegen: colour bitboard out of sync with the board");
+        push_board_moves(list, piece, from, piece_attacks(piece, from, occupied) & targets);
+    }
+    generate_drops_to(pos, block, list);
+}
+
+
+// moves that attack the enemy king, directly or by uncovering a slider
+pub fn generate_checks(pos: &Position, list: &mut MoveList) {
+    let mut all = MoveList::new();
+    generate_board_moves(pos, board_targets(pos), &mut all);
+    generate_drops(pos, &mut all);
+    for mv in all.iter() {
+        if gives_check(pos, mv) {
+            list.push(mv);
+        }
+    }
+}
+
+
+// does the pseudo-legal move `mv` check the enemy king?
+pub fn gives_check(pos: &Position, mv: Move) -> bool {
+
+    let side = pos.side_to_move();
+    let Some(enemy_king) = pos.king_square(side.flip()) else { return false };
+    let occupied = pos.occupied();
+
+    match mv {
+        Move::Normal { from, to, promote } => {
+            let piece = pos.piece_at(from).expect("gives_check: no piece on the from square");
+            let moved = if promote { piece.promote().expect("gives_check: piece can't promote") } else { piece };
+            let after = (occupied & !Bitboard::from_square(from)) | Bitboard::from_square(to);
+            // direct check from the moved piece, or a discovered one from behind it
+            piece_attacks(moved, to, after).contains(enemy_king)
+                || !attackers_to(pos, enemy_king, side, after).is_empty()
+        },
+        Move::Drop { piece, to } => {
+            piece_attacks(piece, to, occupied | Bitboard::from_square(to)).contains(enemy_king)
+        },
+    }
+}
+
+
+// Is the pseudo-legal move `mv` legal? Checks that the own king is safe
+// afterwards and that a pawn drop doesn't give mate (uchifuzume).
+pub fn is_legal(pos: &Position, mv: Move) -> bool {
+
+    if leaves_king_attacked(pos, mv) {
+        return false;
+    }
+
+    if let Move::Drop { piece, .. } = mv {
+        if piece.piece_kind() == PieceKind::Pawn && gives_check(pos, mv) {
+            let mut next = pos.clone();
+            next.make_move(mv);
+            if !has_legal_move(&next) {
+                return false;
+            }
+        }
+    }
+
+    true
+}
+
+
+// all legal moves of the side to move
+pub fn generate_legal(pos: &Position, list: &mut MoveList) {
+    let mut pseudo = MoveList::new();
+    generate_board_moves(pos, board_targets(pos), &mut pseudo);
+    generate_drops(pos, &mut pseudo);
+    for mv in pseudo.iter() {
+        if is_legal(pos, mv) {
+            list.push(mv);
+        }
+    }
+}
+
+
+// has the side to move lost (no legal move left)?
+pub fn is_mated(pos: &Position) -> bool {
+    !has_legal_move(pos)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shogi_core::ToUsi;
+    use shogi_legality_lite::{all_legal_moves_partial, is_in_check_partial_lite, status_partial};
+
+    fn sorted(moves: impl Iterator<Item = Move>) -> Vec<String> {
+        let mut moves: Vec<String> = moves.map(|mv| mv.to_usi_owned()).collect();
+        moves.sort();
+        moves
+    }
+
+    // drops random games may never try: pawn drop mate, a pawn drop check the king
+    // can take, a second pawn on a file
+    #[test]
+    fn pawn_drop_rules() {
+        let drops = [
+            ("kn7/9/1G7/9/9/9/9/9/K8 b P 1", false),
+            ("k8/9/9/9/9/9/9/9/K8 b P 1", true),
+            ("k8/9/9/9/9/9/P8/9/K8 b P 1", false),
+        ];
+        for (sfen, allowed) in drops {
+            let found = Position::from_sfen(sfen).legal_moves().iter().any(|mv| mv.to_usi_owned() == "P*9b");
+            assert_eq!(found, allowed, "{}", sfen);
+        }
+    }
+
+    // Plays random games and checks every position's moves against shogi_legality_lite:
+    // the legal list (same moves in the same order), the checks and evasions after the
+    // legality filter and the game status.
+    #[test]
+    fn agrees_with_shogi_legality_lite() {
+        for _ in 0..20 {
+            let mut pos = Position::startpos();
+            for _ in 0..300 {
+                let partial = pos.to_partial();
+                let legal = pos.legal_moves();
+                let expected = all_legal_moves_partial(&partial);
+                assert_eq!(legal, expected, "{}", pos.to_sfen());
+                assert_eq!(pos.status(), status_partial(&partial), "{}", pos.to_sfen());
+
+                let legal_only = |list: &MoveList| sorted(list.iter().filter(|&mv| is_legal(&pos, mv)));
+
+                // the captures, quiets and drops stages cover every legal move between them
+                let mut staged = MoveList::new();
+                generate_captures(&pos, &mut staged);
+                generate_quiets(&pos, &mut staged);
+                generate_drops(&pos, &mut staged);
+                assert_eq!(legal_only(&staged), sorted(expected.iter().copied()), "{}", pos.to_sfen());
+
+                // legal moves after which the opponent is in check
+                let expected_checks = expected.iter().copied().filter(|&mv| {
+                    let mut next = partial.clone();
+                    next.make_move(mv);
+                    is_in_check_partial_lite(&next)
+                });
+                let mut checks = MoveList::new();
+                generate_checks(&pos, &mut checks);
+                assert_eq!(legal_only(&checks), sorted(expected_checks), "{}", pos.to_sfen());
+
+                if is_in_check_partial_lite(&partial) {
+                    let mut evasions = MoveList::new();
+                    generate_evasions(&pos, &mut evasions);
+                    assert_eq!(legal_only(&evasions), sorted(expected.iter().copied()), "{}", pos.to_sfen());
+                }
+
+                if expected.is_empty() {
+                    break;
+                }
+                pos.make_move(expected[random_number::random!(..expected.len())]);
+            }
+        }
+    }
+}
@@ -0,0 +1,573 @@
+/* NNUE: an efficiently updatable neural network evaluation (HalfKP)
+ *
+ * Instead of the hand-made terms of eval.rs a network scores the position.
+ *
+ *    input        HalfKP: for each side ("perspective"), every piece but the
+ *                 kings and every piece in hand, each combined with where that
+ *                 side's own king stands. The board is turned around for
+ *                 white, so both sides see themselves at the bottom.
+ *    transformer  the active inputs' columns added up, plus a bias: one row of
+ *                 `l1` int16 values per perspective (the accumulator)
+ *    layers       both rows, the side to move's first, through a clipped ReLU
+ *                 (0..127 as uint8) into int8 affine layers of `l2` and `l3`
+ *                 outputs (each shifted down by WEIGHT_SHIFT, rounded, and clipped
+ *                 again) and one int8 output, divided by OUTPUT_SCALE into
+ *                 centipawns (rounded too, so the float trainer's scores agree)
+ *
+ * A move only changes a few inputs, so Position keeps an Accumulator next to
+ * its other incremental sums and adds or subtracts single columns as pieces
+ * are put on, taken off and change hands (a king move recomputes that king's
+ * side). The affine layers use AVX2 when the CPU has it.
+ *
+ * A network is read from a file (format below; Network::save writes it, the
+ * trainer in nnue_train.rs too). While one is active (set_network: the USI
+ * option NnueFile, --nnue on the command line) the engine evaluates with it
+ * instead of eval.rs. Like the piece square tables, positions made before
+ * keep the network they were made with, so changing it belongs between
+ * searches.
+ *
+ * File format, little endian:
+ *
+ *    "RSNNUE01"  u32 l1  u32 l2  u32 l3  u32 length, description (UTF-8)
+ *    transformer  i16 biases[l1]  i16 weights[FEATURES][l1]
+ *    layer 1      i32 biases[l2]  i8 weights[l2][2 * l1]
+ *    layer 2      i32 biases[l3]  i8 weights[l3][l2]
+ *    output       i32 bias        i8 weights[l3]
+ */
+
+use crate::evalcache;
+use crate::position::{Position, HAND_KINDS};
+use shogi_core::{Color, Piece, PieceKind, Square};
+use std::fmt;
+use std::sync::{Arc, RwLock};
+
+
+const MAGIC: &[u8; 8] = b"RSNNUE01";
+
+// what 1.0 is in activations (uint8) and in the accumulator (int16)
+pub const ACTIVATION: i32 = 127;
+// what 1.0 is in the int8 weights of the affine layers, 1 << WEIGHT_SHIFT
+pub const WEIGHT_SHIFT: u32 = 6;
+// the output layer's sum divided by this is centipawns
+pub const OUTPUT_SCALE: i32 = 16;
+
+// hand slots per kind and owner (a feature for every piece held), like HAND_KINDS
+const HAND_SLOTS: [usize; 7] = [18, 4, 4, 4, 4, 2, 2];
+const HAND_PER_OWNER: usize = 38;
+// inputs per king square: hand slots of both owners, then 9 piece classes x 2 owners x 81 squares
+pub const FE_HAND_END: usize = 2 * HAND_PER_OWNER;
+const CLASSES: usize = 9;
+pub const FE_END: usize = FE_HAND_END + CLASSES * 2 * 81;
+pub const FEATURES: usize = 81 * FE_END;
+
+
+#[derive(Debug)]
+pub enum NnueError {
+    Io(String),
+    Format(String),
+}
+
+
+impl fmt::Display for NnueError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NnueError::Io(err) => write!(f, "can't read the network: {}", err),
+            NnueError::Format(err) => write!(f, "not a network file: {}", err),
+        }
+    }
+}
+
+
+impl std::error::Error for NnueError {}
+
+
+// --------------------------------------- features ---------------------------------------
+
+
+// squares as the perspective sees them: black's as they are, white's turned around
+fn orient(square: Square, perspective: Color) -> usize {
+    match perspective {
+        Color::Black => square.array_index(),
+        Color::White => 80 - square.array_index(),
+    }
+}
+
+
+// promoted silvers, knights, lances and pawns move like golds and share their inputs
+fn class(kind: PieceKind) -> usize {
+    match kind {
+        PieceKind::Pawn => 0,
+        PieceKind::Lance => 1,
+        PieceKind::Knight => 2,
+        PieceKind::Silver => 3,
+        PieceKind::Gold | PieceKind::ProPawn | PieceKind::ProLance | PieceKind::ProKnight | PieceKind::ProSilver => 4,
+        PieceKind::Bishop => 5,
+        PieceKind::ProBishop => 6,
+        PieceKind::Rook => 7,
+        PieceKind::ProRook => 8,
+        PieceKind::King => unreachable!("kings aren't inputs"),
+    }
+}
+
+
+fn owner(color: Color, perspective: Color) -> usize {
+    (color != perspective) as usize
+}
+
+
+// the input of `piece` on `square` for `perspective`, whose king is on `king`
+pub fn board_feature(perspective: Color, king: Square, piece: Piece, square: Square) -> usize {
+    let bona = FE_HAND_END + (class(piece.piece_kind()) * 2 + owner(piece.color(), perspective)) * 81 + orient(square, perspective);
+    orient(king, perspective) * FE_END + bona
+}
+
+
+// the input of the `nth` (from 0) piece of `kind` in `color`'s hand
+pub fn hand_feature(perspective: Color, king: Square, color: Color, kind: PieceKind, nth: usize) -> usize {
+    let i = kind.array_index();
+    let slot = HAND_SLOTS[..i].iter().sum::<usize>() + nth.min(HAND_SLOTS[i] - 1);
+    orient(king, perspective) * FE_END + owner(color, perspective) * HAND_PER_OWNER + slot
+}
+
+
+// every active input of `perspective`, none without its king
+pub fn active_features(pos: &Position, perspective: Color) -> Vec<usize> {
+    let Some(king) = pos.king_square(perspective) else {
+        return Vec::new();
+    };
+    let mut features = Vec::with_capacity(64);
+    for square in pos.occupied() {
+        let piece = pos.piece_at(square).expect("occupied square");
+        if piece.piece_kind() != PieceKind::King {
+            features.push(board_feature(perspective, king, piece, square));
+        }
+    }
+    for color in Color::all() {
+        for kind in HAND_KINDS {
+            for nth in 0..pos.hand(color, kind) as usize {
+                features.push(hand_feature(perspective, king, color, kind, nth));
+            }
+        }
+    }
+    features
+}
+
+
+// ---------------------------------------- network ----------------------------------------
+
+
+// an int8 fully connected layer, weights row by row (one row per output)
+#[derive(Clone, Debug, PartialEq)]
+pub struct Affine {
+    pub inputs: usize,
+    pub biases: Vec<i32>,
+    pub weights: Vec<i8>,
+}
+
+
+fn dot_scalar(weights: &[i8], input: &[u8]) -> i32 {
+    weights.iter().zip(input).map(|(&w, &x)| w as i32 * x as i32).sum()
+}
+
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+unsafe fn dot_avx2(weights: &[i8], input: &[u8]) -> i32 {
+    use std::arch::x86_64::*;
+    let ones = _mm256_set1_epi16(1);
+    let mut sum = _mm256_setzero_si256();
+    for (w, x) in weights.chunks_exact(32).zip(input.chunks_exact(32)) {
+        let w = _mm256_loadu_si256(w.as_ptr() as *const __m256i);
+        let x = _mm256_loadu_si256(x.as_ptr() as *const __m256i);
+        // u8 x i8 pairs added into i16 (at most 2 x 127 x 128, no saturation), then pairs of those into i32
+        sum = _mm256_add_epi32(sum, _mm256_madd_epi16(_mm256_maddubs_epi16(x, w), ones));
+    }
+    let mut lanes = [0i32; 8];
+    _mm256_storeu_si256(lanes.as_mut_ptr() as *mut __m256i, sum);
+    lanes.iter().sum()
+}
+
+
+fn dot(weights: &[i8], input: &[u8]) -> i32 {
+    #[cfg(target_arch = "x86_64")]
+    if input.len().is_multiple_of(32) && is_x86_feature_detected!("avx2") {
+        // SAFETY: the CPU has AVX2 and both slices hold whole 32 byte chunks
+        return unsafe { dot_avx2(weights, input) };
+    }
+    dot_scalar(weights, input)
+}
+
+
+impl Affine {
+
+    pub fn new(inputs: usize, outputs: usize) -> Affine {
+        Affine { inputs, biases: vec![0; outputs], weights: vec![0; inputs * outputs] }
+    }
+
+    pub fn outputs(&self) -> usize {
+        self.biases.len()
+    }
+
+    // the sums before any activation
+    pub fn raw(&self, input: &[u8]) -> Vec<i32> {
+        self.biases.iter().zip(self.weights.chunks_exact(self.inputs))
+            .map(|(&bias, row)| bias + dot(row, input))
+            .collect()
+    }
+
+    // the sums scaled back down (to the nearest step) and clipped to 0..ACTIVATION
+    pub fn propagate(&self, input: &[u8]) -> Vec<u8> {
+        let half = 1 << (WEIGHT_SHIFT - 1);
+        self.raw(input).iter().map(|&sum| ((sum + half) >> WEIGHT_SHIFT).clamp(0, ACTIVATION) as u8).collect()
+    }
+}
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Network {
+    pub description: String,
+    pub l1: usize,
+    pub ft_biases: Vec<i16>,
+    pub ft_weights: Vec<i16>,   // FEATURES columns of l1
+    pub hidden1: Affine,        // 2 x l1 -> l2
+    pub hidden2: Affine,        // l2 -> l3
+    pub output: Affine,         // l3 -> 1
+}
+
+
+// reads the file format at the top of this file
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+
+impl<'a> Reader<'a> {
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], NnueError> {
+        if self.bytes.len() < n {
+            return Err(NnueError::Format("file too short".to_string()));
+        }
+        let (taken, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u32(&mut self) -> Result<u32, NnueError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn i16s(&mut self, n: usize) -> Result<Vec<i16>, NnueError> {
+        Ok(self.take(2 * n)?.chunks_exact(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect())
+    }
+
+    fn i32s(&mut self, n: usize) -> Result<Vec<i32>, NnueError> {
+        Ok(self.take(4 * n)?.chunks_exact(4).map(|b| i32::from_le_bytes(b.try_into().unwrap())).collect())
+    }
+
+    fn i8s(&mut self, n: usize) -> Result<Vec<i8>, NnueError> {
+        Ok(self.take(n)?.iter().map(|&b| b as i8).collect())
+    }
+
+    fn affine(&mut self, inputs: usize, outputs: usize) -> Result<Affine, NnueError> {
+        let biases = self.i32s(outputs)?;
+        let weights = self.i8s(inputs * outputs)?;
+        Ok(Affine { inputs, biases, weights })
+    }
+}
+
+
+impl Network {
+
+    // all weights zero
+    pub fn new(l1: usize, l2: usize, l3: usize) -> Network {
+        Network {
+            description: String::new(),
+            l1,
+            ft_biases: vec![0; l1],
+            ft_weights: vec![0; FEATURES * l1],
+            hidden1: Affine::new(2 * l1, l2),
+            hidden2: Affine::new(l2, l3),
+            output: Affine::new(l3, 1),
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Network, NnueError> {
+        let mut reader = Reader { bytes };
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(NnueError::Format("bad magic".to_string()));
+        }
+        let (l1, l2, l3) = (reader.u32()? as usize, reader.u32()? as usize, reader.u32()? as usize);
+        if l1 == 0 || l2 == 0 || l3 == 0 || l1 > 4096 || l2 > 4096 || l3 > 4096 {
+            return Err(NnueError::Format(format!("bad layer sizes {}x2-{}-{}", l1, l2, l3)));
+        }
+        let length = reader.u32()? as usize;
+        let description = String::from_utf8_lossy(reader.take(length)?).into_owned();
+        let network = Network {
+            description,
+            l1,
+            ft_biases: reader.i16s(l1)?,
+            ft_weights: reader.i16s(FEATURES * l1)?,
+            hidden1: reader.affine(2 * l1, l2)?,
+            hidden2: reader.affine(l2, l3)?,
+            output: reader.affine(l3, 1)?,
+        };
+        if !reader.bytes.is_empty() {
+            return Err(NnueError::Format(format!("{} bytes too many", reader.bytes.len())));
+        }
+        Ok(network)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        for size in [self.l1, self.hidden1.outputs(), self.hidden2.outputs(), self.description.len()] {
+            bytes.extend((size as u32).to_le_bytes());
+        }
+        bytes.extend(self.description.as_bytes());
+        bytes.extend(self.ft_biases.iter().chain(&self.ft_weights).flat_map(|v| v.to_le_bytes()));
+        for layer in [&self.hidden1, &self.hidden2, &self.output] {
+            bytes.extend(layer.biases.iter().flat_map(|v| v.to_le_bytes()));
+            bytes.extend(layer.weights.iter().map(|&v| v as u8));
+        }
+        bytes
+    }
+
+    pub fn load(path: &str) -> Result<Network, NnueError> {
+        let bytes = std::fs::read(path).map_err(|e| NnueError::Io(e.to_string()))?;
+        Network::from_bytes(&bytes)
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), NnueError> {
+        std::fs::write(path, self.to_bytes()).map_err(|e| NnueError::Io(e.to_string()))
+    }
+
+    // the column of one input
+    fn column(&self, feature: usize) -> &[i16] {
+        &self.ft_weights[feature * self.l1..(feature + 1) * self.l1]
+    }
+
+    // centipawns for the side to move, from its accumulator row and the opponent's
+    pub fn forward(&self, own: &[i16], other: &[i16]) -> i32 {
+        let input: Vec<u8> = own.iter().chain(other)
+            .map(|&v| (v as i32).clamp(0, ACTIVATION) as u8)
+            .collect();
+        let hidden = self.hidden2.propagate(&self.hidden1.propagate(&input));
+        (self.output.raw(&hidden)[0] + OUTPUT_SCALE / 2).div_euclid(OUTPUT_SCALE)
+    }
+}
+
+
+// ---------------------------------------- accumulator ----------------------------------------
+
+
+// The transformer's output for both perspectives, kept up to date by Position. A perspective
+// without its king on the board has no values (its inputs all depend on where the king is).
+#[derive(Clone)]
+pub struct Accumulator {
+    net: Arc<Network>,
+    values: [Vec<i16>; 2],        // by Color::array_index
+    kings: [Option<Square>; 2],   // the king square the values are for
+}
+
+
+impl PartialEq for Accumulator {
+    fn eq(&self, other: &Accumulator) -> bool {
+        Arc::ptr_eq(&self.net, &other.net) && self.values == other.values && self.kings == other.kings
+    }
+}
+
+
+impl Eq for Accumulator {}
+
+
+impl fmt::Debug for Accumulator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Accumulator {{ kings: {:?} }}", self.kings)
+    }
+}
+
+
+impl Accumulator {
+
+    // for an empty board
+    pub fn new(net: Arc<Network>) -> Accumulator {
+        Accumulator { values: [net.ft_biases.clone(), net.ft_biases.clone()], kings: [None, None], net }
+    }
+
+    // from scratch, what the running values must always equal
+    pub fn compute(net: Arc<Network>, pos: &Position) -> Accumulator {
+        let mut acc = Accumulator::new(net);
+        for color in Color::all() {
+            acc.refresh(pos, color);
+        }
+        acc
+    }
+
+    fn refresh(&mut self, pos: &Position, perspective: Color) {
+        let side = perspective.array_index();
+        self.kings[side] = pos.king_square(perspective);
+        self.values[side].copy_from_slice(&self.net.ft_biases);
+        for feature in active_features(pos, perspective) {
+            add(&mut self.values[side], self.net.column(feature));
+        }
+    }
+
+    // `f` with the king square of every perspective that has values
+    fn update(&mut self, mut f: impl FnMut(Color, Square) -> (usize, bool)) {
+        for perspective in Color::all() {
+            let side = perspective.array_index();
+            if let Some(king) = self.kings[side] {
+                let (feature, added) = f(perspective, king);
+                let column = self.net.column(feature);
+                if added { add(&mut self.values[side], column) } else { sub(&mut self.values[side], column) }
+            }
+        }
+    }
+
+    // `piece` has been put on `square` of `pos`
+    pub fn piece_added(&mut self, pos: &Position, piece: Piece, square: Square) {
+        if piece.piece_kind() == PieceKind::King {
+            self.refresh(pos, piece.color());
+        } else {
+            self.update(|perspective, king| (board_feature(perspective, king, piece, square), true));
+        }
+    }
+
+    // `piece` has been taken off `square`
+    pub fn piece_removed(&mut self, piece: Piece, square: Square) {
+        if piece.piece_kind() == PieceKind::King {
+            self.kings[piece.color().array_index()] = None;
+        } else {
+            self.update(|perspective, king| (board_feature(perspective, king, piece, square), false));
+        }
+    }
+
+    // `color`'s hand went from `old` to `new` pieces of `kind`
+    pub fn hand_changed(&mut self, color: Color, kind: PieceKind, old: u8, new: u8) {
+        for nth in new.min(old)..new.max(old) {
+            self.update(|perspective, king| (hand_feature(perspective, king, color, kind, nth as usize), new > old));
+        }
+    }
+
+    pub fn network(&self) -> &Arc<Network> {
+        &self.net
+    }
+
+    // centipawns for `side_to_move`, None unless both kings are on the board
+    pub fn evaluate(&self, side_to_move: Color) -> Option<i32> {
+        self.kings.iter().all(Option::is_some).then(|| {
+            let own = &self.values[side_to_move.array_index()];
+            let other = &self.values[side_to_move.flip().array_index()];
+            self.net.forward(own, other)
+        })
+    }
+}
+
+
+// written plainly so the compiler vectorises them
+fn add(values: &mut [i16], column: &[i16]) {
+    for (v, &w) in values.iter_mut().zip(column) {
+        *v = v.wrapping_add(w);
+    }
+}
+
+
+fn sub(values: &mut [i16], column: &[i16]) {
+    for (v, &w) in values.iter_mut().zip(column) {
+        *v = v.wrapping_sub(w);
+    }
+}
+
+
+// ------------------------------------------ active ------------------------------------------
+
+
+static ACTIVE: RwLock<Option<Arc<Network>>> = RwLock::new(None);
+
+
+// the network the engine evaluates with, if any
+pub fn network() -> Option<Arc<Network>> {
+    ACTIVE.read().unwrap().clone()
+}
+
+
+// Evaluates with `net` from now on, or with eval.rs again for None. Evaluations cached
+// with the other one are dropped.
+pub fn set_network(net: Option<Network>) {
+    *ACTIVE.write().unwrap() = net.map(Arc::new);
+    evalcache::global().clear();
+}
+
+
+// centipawns for the side to move, when a network is active and `pos` was made with it
+pub fn evaluate(pos: &Position) -> Option<i32> {
+    let acc = pos.accumulator()?;
+    let net = network()?;
+    if !Arc::ptr_eq(&net, acc.network()) {
+        return None;
+    }
+    debug_assert_eq!(acc, &Accumulator::compute(net, pos), "accumulator out of sync: {}", pos.to_sfen());
+    acc.evaluate(pos.side_to_move())
+}
+
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    // small random weights everywhere, so every input and layer makes a difference
+    pub fn random_network(l1: usize, l2: usize, l3: usize) -> Network {
+        let mut net = Network::new(l1, l2, l3);
+        net.description = "random".to_string();
+        net.ft_biases.iter_mut().for_each(|v| *v = random_number::random!(-20..=60));
+        net.ft_weights.iter_mut().for_each(|v| *v = random_number::random!(-8..=8));
+        for layer in [&mut net.hidden1, &mut net.hidden2, &mut net.output] {
+            layer.biases.iter_mut().for_each(|v| *v = random_number::random!(-500..=500));
+            layer.weights.iter_mut().for_each(|v| *v = random_number::random!(-127..=127));
+        }
+        net
+    }
+
+    #[test]
+    fn reads_back_what_it_writes() {
+        let net = random_network(16, 32, 32);
+        let bytes = net.to_bytes();
+        assert_eq!(Network::from_bytes(&bytes).unwrap(), net);
+        assert!(matches!(Network::from_bytes(&bytes[..bytes.len() - 1]), Err(NnueError::Format(_))));
+        assert!(matches!(Network::from_bytes(b"RSNNUE00"), Err(NnueError::Format(_))));
+
+        // the SIMD dot product (where there is one) is the plain one
+        let input: Vec<u8> = (0..64).map(|_| random_number::random!(0..=127)).collect();
+        let row = &net.hidden1.weights[..64];
+        assert_eq!(dot(row, &input), dot_scalar(row, &input));
+    }
+
+    // random games with a network: the accumulator must match a recomputation after every
+    // make and unmake, and the colours turned around must give the same score
+    #[test]
+    fn accumulator_follows_make_and_unmake() {
+        let net = Arc::new(random_network(16, 32, 32));
+        for _ in 0..4 {
+            let mut pos = Position::startpos();
+            pos.attach_accumulator(net.clone());
+            for _ in 0..120 {
+                let moves = pos.legal_moves();
+                if moves.is_empty() {
+                    break;
+                }
+                let mv = moves[random_number::random!(..moves.len())];
+                let before = pos.clone();
+                let undo = pos.make_move(mv);
+                let acc = pos.accumulator().unwrap();
+                assert_eq!(acc, &Accumulator::compute(net.clone(), &pos), "{}", pos.to_sfen());
+
+                let mut flipped = pos.flipped();
+                flipped.attach_accumulator(net.clone());
+                assert_eq!(flipped.accumulator().unwrap().evaluate(flipped.side_to_move()), acc.evaluate(pos.side_to_move()));
+
+                pos.unmake_move(undo);
+                assert_eq!(pos, before);
+                pos.make_move(mv);
+            }
+        }
+    }
+}
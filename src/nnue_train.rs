@@ -0,0 +1,706 @@
+/* NNUE training: fitting a HalfKP network (nnue.rs) to scored positions
+ *
+ * Input is a text file of records, one per line:
+ *
+ *    <sfen> <score> <result>   e.g.  lnsgkgsnl/1r5b1/ppppppppp/9/9/9/PPPPPPPPP/1B5R1/LNSGKGSNL b - 1 35 0.5
+ *
+ * The score is a search score in centipawns for the side to move (what the
+ * engine reports as `score cp` during self-play), the result the game's
+ * outcome for black as in texel.rs: 1 (or 1-0), 0 (0-1), 0.5 (1/2-1/2).
+ * Empty lines and # comments are skipped.
+ *
+ * The network is trained in floating point, shaped like the quantised one so
+ * that exporting it is only rounding:
+ *
+ *    transformer  accumulator values clipped to 0..1 (1 is nnue::ACTIVATION)
+ *    layers       clipped ReLU to 0..1, weights kept to what int8 holds at
+ *                 1 << WEIGHT_SHIFT
+ *    output       times OUTPUT_CP, what the quantised output comes to
+ *
+ * The prediction sigmoid(eval) = 1 / (1 + 10^(-eval / scale)) is fitted by
+ * mean squared error to lambda * sigmoid(score) + (1 - lambda) * result, in
+ * mini-batches of shuffled records, with Adam or SGD with momentum. Of the
+ * transformer only the columns of inputs seen in the batch are updated.
+ *
+ * After every epoch the weights, the optimiser's state and the epoch count
+ * are written to <dir>/latest.ckpt and the quantised network to
+ * <dir>/network.nnue, which --nnue and the USI NnueFile option load. `resume`
+ * carries on from latest.ckpt (with the layer sizes stored there).
+ */
+
+use crate::nnue::{self, Affine, Network, ACTIVATION, FEATURES, OUTPUT_SCALE, WEIGHT_SHIFT};
+use crate::sfen;
+use crate::texel;
+use shogi_core::Color;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+
+// the float output times this is centipawns, like the quantised output divided by OUTPUT_SCALE
+const OUTPUT_CP: f32 = ((1 << WEIGHT_SHIFT) * ACTIVATION / OUTPUT_SCALE) as f32;
+// the largest layer weight int8 holds
+const MAX_WEIGHT: f32 = 127.0 / (1 << WEIGHT_SHIFT) as f32;
+// the largest transformer weight int16 holds
+const MAX_FT_WEIGHT: f32 = i16::MAX as f32 / ACTIVATION as f32;
+
+const CHECKPOINT_MAGIC: &[u8; 8] = b"RSNNCK01";
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Optimizer {
+    Sgd,    // with momentum
+    Adam,
+}
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrainConfig {
+    pub epochs: usize,
+    pub batch: usize,
+    pub rate: f64,
+    pub optimizer: Optimizer,
+    pub lambda: f64,                    // share of the score (against the result) in the target
+    pub scale: f64,                     // centipawns of the sigmoid
+    pub layers: (usize, usize, usize),  // l1, l2, l3 of a new network
+    pub checkpoint: PathBuf,            // directory for the checkpoint and the network
+    pub resume: bool,
+}
+
+
+impl Default for TrainConfig {
+    fn default() -> Self {
+        TrainConfig {
+            epochs: 10,
+            batch: 256,
+            rate: 0.001,
+            optimizer: Optimizer::Adam,
+            lambda: 0.75,
+            scale: 400.0,
+            layers: (64, 32, 32),
+            checkpoint: PathBuf::from("nnue"),
+            resume: false,
+        }
+    }
+}
+
+
+impl TrainConfig {
+
+    // Parses e.g. ["epochs", "20", "optimizer", "sgd", "rate", "0.01", "l1", "128", "checkpoint", "runs/a", "resume"].
+    pub fn parse(args: &[&str]) -> Result<Self, String> {
+
+        let mut config = TrainConfig::default();
+        let mut i = 0;
+        while i < args.len() {
+            let value = args.get(i + 1).copied();
+            let missing = || format!("{} needs a value", args[i]);
+            let number = |value: Option<&str>| -> Result<f64, String> {
+                let text = value.ok_or_else(missing)?;
+                text.parse::<f64>().map_err(|_| format!("invalid value for {}: {}", args[i], text))
+            };
+            match args[i] {
+                "epochs" => config.epochs = number(value)? as usize,
+                "batch" => config.batch = number(value)? as usize,
+                "rate" => config.rate = number(value)?,
+                "lambda" => config.lambda = number(value)?,
+                "scale" => config.scale = number(value)?,
+                "l1" => config.layers.0 = number(value)? as usize,
+                "l2" => config.layers.1 = number(value)? as usize,
+                "l3" => config.layers.2 = number(value)? as usize,
+                "optimizer" => config.optimizer = match value.ok_or_else(missing)? {
+                    "adam" => Optimizer::Adam,
+                    "sgd" => Optimizer::Sgd,
+                    other => return Err(format!("unknown optimizer: {}", other)),
+                },
+                "checkpoint" => config.checkpoint = PathBuf::from(value.ok_or_else(missing)?),
+                "resume" => {
+                    config.resume = true;
+                    i += 1;
+                    continue;
+                },
+                other => return Err(format!("unknown trainer option: {}", other)),
+            }
+            i += 2;
+        }
+        let (l1, l2, l3) = config.layers;
+        if config.batch == 0 || l1 == 0 || l2 == 0 || l3 == 0 || !(0.0..=1.0).contains(&config.lambda) || config.scale <= 0.0 {
+            return Err("the trainer needs a batch and layers of 1+, a lambda from 0 to 1 and a positive scale".to_string());
+        }
+        Ok(config)
+    }
+}
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TrainError {
+    Io(String),
+    Line { line: usize, message: String },
+    Checkpoint(String),   // latest.ckpt can't be read back
+    NoRecords,
+}
+
+
+impl fmt::Display for TrainError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrainError::Io(err) => write!(f, "{}", err),
+            TrainError::Line { line, message } => write!(f, "line {}: {}", line, message),
+            TrainError::Checkpoint(err) => write!(f, "bad checkpoint: {}", err),
+            TrainError::NoRecords => write!(f, "no records to train on"),
+        }
+    }
+}
+
+
+impl std::error::Error for TrainError {}
+
+
+// ---------------------------------------- records ----------------------------------------
+
+
+// one scored position, reduced to the network's inputs
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record {
+    features: [Vec<u32>; 2],   // nnue::active_features of the side to move, then of the other side
+    score: f32,                // centipawns for the side to move
+    result: f32,               // for the side to move
+}
+
+
+// the records in `text`, in the format at the top of this file
+pub fn parse_records(text: &str) -> Result<Vec<Record>, TrainError> {
+
+    let mut records = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let error = |message: String| TrainError::Line { line: number + 1, message };
+        let expected = || error("expected <sfen> <score> <result>".to_string());
+        let (rest, result) = line.rsplit_once(char::is_whitespace).ok_or_else(expected)?;
+        let (sfen_text, score) = rest.trim_end().rsplit_once(char::is_whitespace).ok_or_else(expected)?;
+        let result = texel::parse_result(result).ok_or_else(|| error(format!("bad result \"{}\"", result)))?;
+        let score = score.parse::<f32>().map_err(|_| error(format!("bad score \"{}\"", score)))?;
+        let pos = sfen::parse(sfen_text).map_err(|e| error(format!("invalid sfen: {}", e)))?;
+        if pos.king_square(Color::Black).is_none() || pos.king_square(Color::White).is_none() {
+            return Err(error("the network needs both kings".to_string()));
+        }
+
+        let side = pos.side_to_move();
+        let features = [side, side.flip()].map(|perspective| nnue::active_features(&pos, perspective).into_iter().map(|f| f as u32).collect());
+        let result = if side == Color::Black { result } else { 1.0 - result };
+        records.push(Record { features, score, result: result as f32 });
+    }
+    Ok(records)
+}
+
+
+pub fn load_records(path: &str) -> Result<Vec<Record>, TrainError> {
+    let text = std::fs::read_to_string(path).map_err(|e| TrainError::Io(format!("{}: {}", path, e)))?;
+    parse_records(&text)
+}
+
+
+// ------------------------------------------ model ------------------------------------------
+
+
+// The network in floating point, weights laid out like Network's. Also used for the
+// gradients and the optimiser's moments, which have the same shape.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Model {
+    pub l1: usize,
+    pub l2: usize,
+    pub l3: usize,
+    ft_weights: Vec<f32>,
+    ft_biases: Vec<f32>,
+    w1: Vec<f32>,
+    b1: Vec<f32>,
+    w2: Vec<f32>,
+    b2: Vec<f32>,
+    w3: Vec<f32>,
+    b3: Vec<f32>,
+}
+
+
+// what the forward pass leaves for the backward one
+struct Activations {
+    acc: Vec<f32>,   // both accumulators, side to move first, before clipping
+    x: Vec<f32>,
+    z1: Vec<f32>,
+    h1: Vec<f32>,
+    z2: Vec<f32>,
+    h2: Vec<f32>,
+    out: f32,        // centipawns
+}
+
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+
+fn affine(weights: &[f32], biases: &[f32], input: &[f32]) -> Vec<f32> {
+    biases.iter().zip(weights.chunks_exact(input.len())).map(|(bias, row)| bias + dot(row, input)).collect()
+}
+
+
+fn clip(values: &[f32]) -> Vec<f32> {
+    values.iter().map(|v| v.clamp(0.0, 1.0)).collect()
+}
+
+
+// the gradient through a clipped ReLU, given what went into it
+fn unclip(grad: Vec<f32>, pre: &[f32]) -> Vec<f32> {
+    grad.into_iter().zip(pre).map(|(g, &z)| if z > 0.0 && z < 1.0 { g } else { 0.0 }).collect()
+}
+
+
+// adds the gradients of a layer's weights and biases for `dz` at its sums, returns the one at its input
+fn backward_layer(weights: &[f32], grad_weights: &mut [f32], grad_biases: &mut [f32], dz: &[f32], input: &[f32]) -> Vec<f32> {
+    let mut grad_input = vec![0.0; input.len()];
+    let rows = weights.chunks_exact(input.len()).zip(grad_weights.chunks_exact_mut(input.len()));
+    for (((row, grad_row), grad_bias), &d) in rows.zip(grad_biases.iter_mut()).zip(dz) {
+        if d == 0.0 {
+            continue;
+        }
+        *grad_bias += d;
+        for i in 0..input.len() {
+            grad_row[i] += d * input[i];
+            grad_input[i] += d * row[i];
+        }
+    }
+    grad_input
+}
+
+
+impl Model {
+
+    pub fn zeros(l1: usize, l2: usize, l3: usize) -> Model {
+        Model {
+            l1, l2, l3,
+            ft_weights: vec![0.0; FEATURES * l1],
+            ft_biases: vec![0.0; l1],
+            w1: vec![0.0; l2 * 2 * l1],
+            b1: vec![0.0; l2],
+            w2: vec![0.0; l3 * l2],
+            b2: vec![0.0; l3],
+            w3: vec![0.0; l3],
+            b3: vec![0.0; 1],
+        }
+    }
+
+    // small random weights, every sum starting within 0..1 where the gradient gets through
+    pub fn random(l1: usize, l2: usize, l3: usize) -> Model {
+        let mut model = Model::zeros(l1, l2, l3);
+        let uniform = |limit: f32| -> f32 { random_number::random!(-limit..limit) };
+        model.ft_weights.iter_mut().for_each(|w| *w = uniform(0.05));
+        model.ft_biases.iter_mut().for_each(|b| *b = 0.5);
+        model.b1.iter_mut().chain(&mut model.b2).for_each(|b| *b = 0.5);
+        for (weights, inputs) in [(&mut model.w1, 2 * l1), (&mut model.w2, l2), (&mut model.w3, l3)] {
+            weights.iter_mut().for_each(|w| *w = uniform(1.0 / (inputs as f32).sqrt()));
+        }
+        model
+    }
+
+    // every tensor, in checkpoint order
+    fn tensors(&self) -> [&Vec<f32>; 8] {
+        [&self.ft_weights, &self.ft_biases, &self.w1, &self.b1, &self.w2, &self.b2, &self.w3, &self.b3]
+    }
+
+    fn tensors_mut(&mut self) -> [&mut Vec<f32>; 8] {
+        [&mut self.ft_weights, &mut self.ft_biases, &mut self.w1, &mut self.b1, &mut self.w2, &mut self.b2, &mut self.w3, &mut self.b3]
+    }
+
+    fn forward(&self, record: &Record) -> Activations {
+        let mut acc = Vec::with_capacity(2 * self.l1);
+        for features in &record.features {
+            let mut values = self.ft_biases.clone();
+            for &feature in features {
+                let column = &self.ft_weights[feature as usize * self.l1..(feature as usize + 1) * self.l1];
+                values.iter_mut().zip(column).for_each(|(v, w)| *v += w);
+            }
+            acc.extend(values);
+        }
+        let x = clip(&acc);
+        let z1 = affine(&self.w1, &self.b1, &x);
+        let h1 = clip(&z1);
+        let z2 = affine(&self.w2, &self.b2, &h1);
+        let h2 = clip(&z2);
+        let out = OUTPUT_CP * (self.b3[0] + dot(&self.w3, &h2));
+        Activations { acc, x, z1, h1, z2, h2, out }
+    }
+
+    // centipawns for the side to move
+    pub fn evaluate(&self, record: &Record) -> f32 {
+        self.forward(record).out
+    }
+
+    // adds to `grads` the gradient of what has `d_out` as its derivative by the output
+    fn backward(&self, record: &Record, act: &Activations, d_out: f32, grads: &mut Model) {
+        let d_h2 = backward_layer(&self.w3, &mut grads.w3, &mut grads.b3, &[d_out * OUTPUT_CP], &act.h2);
+        let d_h1 = backward_layer(&self.w2, &mut grads.w2, &mut grads.b2, &unclip(d_h2, &act.z2), &act.h1);
+        let d_x = backward_layer(&self.w1, &mut grads.w1, &mut grads.b1, &unclip(d_h1, &act.z1), &act.x);
+        let d_acc = unclip(d_x, &act.acc);
+        for (features, d) in record.features.iter().zip(d_acc.chunks_exact(self.l1)) {
+            grads.ft_biases.iter_mut().zip(d).for_each(|(g, d)| *g += d);
+            for &feature in features {
+                let column = &mut grads.ft_weights[feature as usize * self.l1..(feature as usize + 1) * self.l1];
+                column.iter_mut().zip(d).for_each(|(g, d)| *g += d);
+            }
+        }
+    }
+
+    // the network the engine loads: every weight rounded to its fixed point
+    pub fn quantise(&self, description: &str) -> Network {
+        let ft = |w: &f32| (w * ACTIVATION as f32).round().clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+        let weight = |w: &f32| (w * (1 << WEIGHT_SHIFT) as f32).round().clamp(-127.0, 127.0) as i8;
+        let bias = |b: &f32| (b * ((1 << WEIGHT_SHIFT) * ACTIVATION) as f32).round() as i32;
+        let layer = |weights: &[f32], biases: &[f32], inputs: usize| Affine {
+            inputs,
+            biases: biases.iter().map(bias).collect(),
+            weights: weights.iter().map(weight).collect(),
+        };
+        Network {
+            description: description.to_string(),
+            l1: self.l1,
+            ft_biases: self.ft_biases.iter().map(ft).collect(),
+            ft_weights: self.ft_weights.iter().map(ft).collect(),
+            hidden1: layer(&self.w1, &self.b1, 2 * self.l1),
+            hidden2: layer(&self.w2, &self.b2, self.l2),
+            output: layer(&self.w3, &self.b3, self.l3),
+        }
+    }
+}
+
+
+fn sigmoid(score: f32, scale: f32) -> f32 {
+    1.0 / (1.0 + 10f32.powf(-score / scale))
+}
+
+
+fn target(record: &Record, config: &TrainConfig) -> f32 {
+    let lambda = config.lambda as f32;
+    lambda * sigmoid(record.score, config.scale as f32) + (1.0 - lambda) * record.result
+}
+
+
+// mean squared error between the predictions and the targets
+pub fn loss(model: &Model, records: &[Record], config: &TrainConfig) -> f64 {
+    let total: f64 = records.iter()
+        .map(|record| (sigmoid(model.evaluate(record), config.scale as f32) - target(record, config)).powi(2) as f64)
+        .sum();
+    total / records.len() as f64
+}
+
+
+// ------------------------------------------ trainer ------------------------------------------
+
+
+// the model and everything needed to carry on training it
+#[derive(Clone, Debug, PartialEq)]
+pub struct Trainer {
+    pub model: Model,
+    pub epoch: usize,   // epochs done
+    step: u64,          // optimiser steps done
+    m: Model,           // first moment (Adam), velocity (SGD)
+    v: Model,           // second moment (Adam)
+    grads: Model,       // of the current batch
+    seen: Vec<bool>,    // transformer columns with a gradient in the current batch
+    seen_list: Vec<u32>,
+}
+
+
+impl Trainer {
+
+    pub fn new(model: Model) -> Trainer {
+        let zeros = Model::zeros(model.l1, model.l2, model.l3);
+        Trainer {
+            epoch: 0,
+            step: 0,
+            m: zeros.clone(),
+            v: zeros.clone(),
+            grads: zeros,
+            seen: vec![false; FEATURES],
+            seen_list: Vec::new(),
+            model,
+        }
+    }
+
+    // adds one record's gradient to the batch's, returns its loss
+    fn accumulate(&mut self, record: &Record, config: &TrainConfig) -> f32 {
+        let act = self.model.forward(record);
+        let scale = config.scale as f32;
+        let p = sigmoid(act.out, scale);
+        let error = p - target(record, config);
+        let d_out = 2.0 * error * p * (1.0 - p) * std::f32::consts::LN_10 / scale;
+        self.model.backward(record, &act, d_out, &mut self.grads);
+        for &feature in record.features.iter().flatten() {
+            if !self.seen[feature as usize] {
+                self.seen[feature as usize] = true;
+                self.seen_list.push(feature);
+            }
+        }
+        error * error
+    }
+
+    // applies the batch's mean gradient and clears it
+    fn apply(&mut self, config: &TrainConfig, batch: usize) {
+
+        self.step += 1;
+        let (rate, n) = (config.rate as f32, batch as f32);
+        let (beta1, beta2, epsilon, momentum) = (0.9f32, 0.999f32, 1e-8f32, 0.9f32);
+        let (correction1, correction2) = (1.0 - beta1.powi(self.step as i32), 1.0 - beta2.powi(self.step as i32));
+        let optimizer = config.optimizer;
+        let update = |w: &mut f32, g: &mut f32, m: &mut f32, v: &mut f32, limit: f32| {
+            let grad = *g / n;
+            *g = 0.0;
+            match optimizer {
+                Optimizer::Adam => {
+                    *m = beta1 * *m + (1.0 - beta1) * grad;
+                    *v = beta2 * *v + (1.0 - beta2) * grad * grad;
+                    *w -= rate * (*m / correction1) / ((*v / correction2).sqrt() + epsilon);
+                },
+                Optimizer::Sgd => {
+                    *m = momentum * *m + grad;
+                    *w -= rate * *m;
+                },
+            }
+            *w = w.clamp(-limit, limit);
+        };
+
+        // what each tensor may hold once quantised (biases are 32 bit, no limit to speak of)
+        let limits = [MAX_FT_WEIGHT, MAX_FT_WEIGHT, MAX_WEIGHT, f32::MAX, MAX_WEIGHT, f32::MAX, MAX_WEIGHT, f32::MAX];
+        let l1 = self.model.l1;
+        let tensors = self.model.tensors_mut().into_iter()
+            .zip(self.grads.tensors_mut())
+            .zip(self.m.tensors_mut())
+            .zip(self.v.tensors_mut())
+            .zip(limits);
+        for (index, ((((w, g), m), v), limit)) in tensors.enumerate() {
+            // of the transformer's weights only the columns of this batch's inputs
+            if index == 0 {
+                for &feature in &self.seen_list {
+                    for i in feature as usize * l1..(feature as usize + 1) * l1 {
+                        update(&mut w[i], &mut g[i], &mut m[i], &mut v[i], limit);
+                    }
+                }
+            } else {
+                for i in 0..w.len() {
+                    update(&mut w[i], &mut g[i], &mut m[i], &mut v[i], limit);
+                }
+            }
+        }
+        for feature in self.seen_list.drain(..) {
+            self.seen[feature as usize] = false;
+        }
+    }
+
+    // one pass over `records` in a random order, returns the mean loss seen on the way
+    pub fn run_epoch(&mut self, records: &[Record], config: &TrainConfig) -> f64 {
+        let mut order: Vec<usize> = (0..records.len()).collect();
+        for i in (1..order.len()).rev() {
+            order.swap(i, random_number::random!(..=i));
+        }
+        let mut total = 0.0;
+        for batch in order.chunks(config.batch) {
+            for &i in batch {
+                total += self.accumulate(&records[i], config) as f64;
+            }
+            self.apply(config, batch.len());
+        }
+        self.epoch += 1;
+        total / records.len() as f64
+    }
+
+    pub fn network(&self) -> Network {
+        let model = &self.model;
+        self.model.quantise(&format!("HalfKP {}x2-{}-{}, {} epochs", model.l1, model.l2, model.l3, self.epoch))
+    }
+}
+
+
+// --------------------------------------- checkpoints ---------------------------------------
+
+
+//    "RSNNCK01"  u32 l1  u32 l2  u32 l3  u64 epoch  u64 step
+//    the model's, m's and v's tensors in Model::tensors order, f32
+fn checkpoint_bytes(trainer: &Trainer) -> Vec<u8> {
+    let model = &trainer.model;
+    let mut bytes = CHECKPOINT_MAGIC.to_vec();
+    for size in [model.l1, model.l2, model.l3] {
+        bytes.extend((size as u32).to_le_bytes());
+    }
+    bytes.extend((trainer.epoch as u64).to_le_bytes());
+    bytes.extend(trainer.step.to_le_bytes());
+    for part in [model, &trainer.m, &trainer.v] {
+        for tensor in part.tensors() {
+            bytes.extend(tensor.iter().flat_map(|v| v.to_le_bytes()));
+        }
+    }
+    bytes
+}
+
+
+fn parse_checkpoint(bytes: &[u8]) -> Result<Trainer, TrainError> {
+
+    let bad = |what: &str| TrainError::Checkpoint(what.to_string());
+    let mut rest = bytes.strip_prefix(CHECKPOINT_MAGIC.as_slice()).ok_or_else(|| bad("bad magic"))?;
+    let mut take = |n: usize| -> Result<&[u8], TrainError> {
+        if rest.len() < n {
+            return Err(bad("file too short"));
+        }
+        let (taken, remaining) = rest.split_at(n);
+        rest = remaining;
+        Ok(taken)
+    };
+    let mut sizes = [0; 3];
+    for size in sizes.iter_mut() {
+        *size = u32::from_le_bytes(take(4)?.try_into().unwrap()) as usize;
+    }
+    let [l1, l2, l3] = sizes;
+    if l1 == 0 || l2 == 0 || l3 == 0 || l1 > 4096 || l2 > 4096 || l3 > 4096 {
+        return Err(bad("bad layer sizes"));
+    }
+    let epoch = u64::from_le_bytes(take(8)?.try_into().unwrap()) as usize;
+    let step = u64::from_le_bytes(take(8)?.try_into().unwrap());
+
+    let mut trainer = Trainer::new(Model::zeros(l1, l2, l3));
+    trainer.epoch = epoch;
+    trainer.step = step;
+    for part in [&mut trainer.model, &mut trainer.m, &mut trainer.v] {
+        for tensor in part.tensors_mut() {
+            let values = take(4 * tensor.len())?;
+            for (value, b) in tensor.iter_mut().zip(values.chunks_exact(4)) {
+                *value = f32::from_le_bytes(b.try_into().unwrap());
+            }
+        }
+    }
+    if !rest.is_empty() {
+        return Err(bad("bytes after the end"));
+    }
+    Ok(trainer)
+}
+
+
+fn write_checkpoint(dir: &Path, trainer: &Trainer) -> Result<(), TrainError> {
+    let io = |e: std::io::Error| TrainError::Io(e.to_string());
+    std::fs::create_dir_all(dir).map_err(io)?;
+    std::fs::write(dir.join("latest.ckpt"), checkpoint_bytes(trainer)).map_err(io)?;
+    std::fs::write(dir.join("network.nnue"), trainer.network().to_bytes()).map_err(io)
+}
+
+
+// ------------------------------------------- run -------------------------------------------
+
+
+// Trains a new network (or the checkpoint's with `resume`) on `records` and returns it,
+// writing a checkpoint and the quantised network after every epoch.
+pub fn train(records: &[Record], config: &TrainConfig) -> Result<Trainer, TrainError> {
+
+    if records.is_empty() {
+        return Err(TrainError::NoRecords);
+    }
+    let mut trainer = if config.resume {
+        let path = config.checkpoint.join("latest.ckpt");
+        let bytes = std::fs::read(&path).map_err(|e| TrainError::Io(format!("{}: {}", path.display(), e)))?;
+        let trainer = parse_checkpoint(&bytes)?;
+        println!(" | resuming after epoch {}", trainer.epoch);
+        trainer
+    } else {
+        let (l1, l2, l3) = config.layers;
+        Trainer::new(Model::random(l1, l2, l3))
+    };
+    let model = &trainer.model;
+    println!(" | {} records, network {}x2-{}-{}, loss {:.6}", records.len(), model.l1, model.l2, model.l3, loss(model, records, config));
+
+    let end = if config.resume { trainer.epoch + config.epochs } else { config.epochs };
+    while trainer.epoch < end {
+        let seen = trainer.run_epoch(records, config);
+        println!(" | epoch {:>4}  loss {:.6} (during the epoch {:.6})", trainer.epoch, loss(&trainer.model, records, config), seen);
+        write_checkpoint(&config.checkpoint, &trainer)?;
+    }
+    Ok(trainer)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nnue::Accumulator;
+    use crate::position::Position;
+    use std::sync::Arc;
+
+    const START: &str = "lnsgkgsnl/1r5b1/ppppppppp/9/9/9/PPPPPPPPP/1B5R1/LNSGKGSNL b - 1";
+    // black has taken a rook (white's is in black's hand), white to move
+    const AHEAD: &str = "lnsgkgsnl/7b1/ppppppppp/9/9/9/PPPPPPPPP/1B5R1/LNSGKGSNL w R 1";
+
+    // Weights on the quantisation grid (feature transformer steps of 1 / ACTIVATION, layer
+    // steps of 1 / 64), so quantising loses nothing and only the activations are rounded.
+    fn grid_model(l1: usize, l2: usize, l3: usize) -> Model {
+        let mut model = Model::zeros(l1, l2, l3);
+        let step = |i: usize, span: usize| ((i * 7919) % (2 * span + 1)) as f32 - span as f32;
+        model.ft_weights.iter_mut().enumerate().for_each(|(i, w)| *w = step(i, 6) / ACTIVATION as f32);
+        model.ft_biases.iter_mut().for_each(|b| *b = 64.0 / ACTIVATION as f32);
+        model.b1.iter_mut().chain(&mut model.b2).for_each(|b| *b = 0.5);
+        for weights in [&mut model.w1, &mut model.w2, &mut model.w3] {
+            weights.iter_mut().enumerate().for_each(|(i, w)| *w = step(i, 8) / (1 << WEIGHT_SHIFT) as f32);
+        }
+        model
+    }
+
+    #[test]
+    fn reads_records_and_exports_the_network() {
+        let text = format!("# test\n{} 20 0.5\n{} -900 1-0\n\n", START, AHEAD);
+        let records = parse_records(&text).unwrap();
+        // the result is kept for the side to move
+        assert_eq!(records.iter().map(|r| (r.score, r.result)).collect::<Vec<_>>(), vec![(20.0, 0.5), (-900.0, 0.0)]);
+        assert!(matches!(parse_records(&format!("{} 0 2", START)), Err(TrainError::Line { line: 1, .. })));
+        assert!(matches!(parse_records(&format!("{} x 1", START)), Err(TrainError::Line { line: 1, .. })));
+        assert!(matches!(parse_records("4k4/9/9/9/9/9/9/9/9 b - 1 0 1"), Err(TrainError::Line { line: 1, .. })));
+
+        // the quantised network scores like the float one, up to rounding
+        let model = grid_model(32, 32, 32);
+        let net = Arc::new(model.quantise("test"));
+        for (sfen, record) in [START, AHEAD].iter().zip(&records) {
+            let pos = Position::from_sfen(sfen);
+            let quantised = Accumulator::compute(net.clone(), &pos).evaluate(pos.side_to_move()).unwrap();
+            let float = model.evaluate(record);
+            assert!((quantised as f32 - float).abs() <= 2.0, "{} vs {}", quantised, float);
+        }
+
+        let trainer = Trainer::new(model);
+        assert_eq!(parse_checkpoint(&checkpoint_bytes(&trainer)).unwrap(), trainer);
+        assert!(matches!(parse_checkpoint(b"RSNNCK01"), Err(TrainError::Checkpoint(_))));
+    }
+
+    #[test]
+    fn training_lowers_the_loss_and_resumes() {
+        let text = format!("{} 0 0.5\n{} -1200 1\n", START, AHEAD);
+        let records = parse_records(&text).unwrap();
+        let dir = std::env::temp_dir().join(format!("nnue_train_test_{}", std::process::id()));
+        let config = TrainConfig {
+            epochs: 2,
+            batch: 1,
+            rate: 0.002,
+            layers: (8, 32, 32),
+            checkpoint: dir.clone(),
+            ..Default::default()
+        };
+        let mut trainer = Trainer::new(Model::random(8, 32, 32));
+        let start = loss(&trainer.model, &records, &config);
+        for _ in 0..200 {
+            trainer.run_epoch(&records, &config);
+        }
+        assert!(loss(&trainer.model, &records, &config) < start.min(0.05) / 2.0);
+
+        // every epoch writes a checkpoint and the network the engine loads
+        let trainer = train(&records, &config).unwrap();
+        let net = Network::load(dir.join("network.nnue").to_str().unwrap()).unwrap();
+        assert_eq!(net, trainer.network());
+
+        let resumed = train(&records, &TrainConfig { epochs: 2, resume: true, optimizer: Optimizer::Sgd, ..config }).unwrap();
+        assert_eq!(resumed.epoch, 4);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
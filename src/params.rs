@@ -0,0 +1,515 @@
+/* EvalParams - the evaluation weights, settable at runtime
+ *
+ * Every weight the evaluator uses is a named f32 here. The names are grouped
+ * into sections and are the same everywhere the weights can be set:
+ *
+ *    TOML   [weights]              JSON  {"weights": {"king_vuln": 220}}
+ *           king_vuln = 220              or flat {"weights.king_vuln": 220}
+ *
+ *    USI    setoption name weights.king_vuln value 220
+ *
+ * A file only needs the weights it changes, everything else keeps its default.
+ * Unknown names are errors, so a typo can't silently leave a weight unchanged.
+ *
+ * The defaults are the values the engine has always played with. The old
+ * constants were integers, so e.g. the lance mobility weight written as 17 / 4
+ * was really 4 (now mobility.lance), and the defenders weight in king vulnerability (0.5 cast to an
+ * integer) was really 0.
+ *
+ * Every weight has an opening and an endgame value (TaperedParams, below):
+ * opening.weights.king_vuln and endgame.weights.king_vuln, while a plain name
+ * sets both.
+ *
+ * The engine evaluates with the active parameters (current / set); tuners can
+ * hand any TaperedParams to eval::evaluate_tapered, or one phase's EvalParams
+ * to eval::evaluate_with, directly.
+ */
+
+use crate::castle::Castle;
+use crate::evalcache;
+use crate::king_safety::DROP_KINDS;
+use crate::position::HAND_KINDS;
+use crate::structure::Structure;
+use shogi_core::PieceKind;
+use std::fmt;
+use std::sync::RwLock;
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EvalParams {
+    // [weights] what each feature is multiplied by
+    pub promoted_pieces: f32,
+    pub pst: f32,                  // the piece square tables (pst.rs)
+    pub king_vuln: f32,
+    // [king_vuln] the parts of the king vulnerability count
+    pub vuln_attacked: f32,        // squares next to the king the attacker hits
+    pub vuln_defenders: f32,       // defenders covering those squares (counts against)
+    pub vuln_king_attackers: f32,  // pieces attacking the king itself
+    pub vuln_escapes: f32,         // safe squares the king can run to (count against)
+    // [mobility] per square a piece can go to, by PieceKind::array_index (the king's is unused)
+    pub mobility: [f32; PieceKind::NUM],
+    // [hand_bonus] extra value of a piece in hand, indexed like HAND_KINDS
+    pub hand_bonus: [f32; 7],
+    // [material] on the board, indexed by PieceKind::array_index (the king's is unused)
+    pub material: [f32; PieceKind::NUM],
+    // [material_hand] in hand, indexed like HAND_KINDS
+    pub material_hand: [f32; 7],
+    // [castle] a complete, unattacked castle of each kind, indexed by Castle::index (see castle.rs)
+    pub castle: [f32; Castle::COUNT],
+    // [king_safety] see king_safety.rs: the weight of the squared attack units and what
+    // zone squares, safe checks and gold/silver/knight drop threats add to the units
+    pub safety_weight: f32,
+    pub safety_zone_squares: f32,
+    pub safety_checks: f32,
+    pub safety_drops: [f32; 3],
+    // [zone_attackers] attack units of a piece hitting the king zone, by PieceKind::array_index
+    pub zone_attackers: [f32; PieceKind::NUM],
+    // [structure] piece relationships, indexed like Structure::NAMES (see structure.rs)
+    pub structure: [f32; Structure::COUNT],
+}
+
+
+pub const DEFAULT_PARAMS: EvalParams = EvalParams {
+    promoted_pieces: 27.0,
+    pst: 1.0,
+    king_vuln: 220.0,
+    vuln_attacked: 1.0,
+    vuln_defenders: 0.0,
+    vuln_king_attackers: 1.0,
+    vuln_escapes: 1.0,
+    //         P    L    N    S    G    B    R    K    +P   +L   +N   +S   +B   +R
+    mobility: [0.0, 4.0, 2.0, 2.0, 2.0, 4.0, 4.0, 0.0, 2.0, 2.0, 2.0, 2.0, 4.0, 4.0],
+    //           P    L     N    S     G     B     R
+    hand_bonus: [1.0, 18.0, 9.0, 15.0, 22.0, 16.0, 18.0],
+    //         P      L      N      S      G      B      R       K    +P     +L     +N     +S     +B      +R
+    material: [100.0, 300.0, 350.0, 500.0, 550.0, 800.0, 1000.0, 0.0, 550.0, 550.0, 550.0, 550.0, 1050.0, 1250.0],
+    //              P      L      N      S      G      B      R
+    material_hand: [100.0, 300.0, 350.0, 500.0, 550.0, 800.0, 1000.0],
+    //      mino   high   ginkan anaguma yagura funa
+    castle: [150.0, 180.0, 200.0, 250.0, 180.0, 100.0],
+    safety_weight: 0.25,
+    safety_zone_squares: 1.0,
+    safety_checks: 3.0,
+    //            G    S    N
+    safety_drops: [4.0, 3.0, 3.0],
+    //               P    L    N    S    G    B    R    K    +P   +L   +N   +S   +B   +R
+    zone_attackers: [1.0, 2.0, 2.0, 3.0, 3.0, 4.0, 5.0, 0.0, 3.0, 3.0, 3.0, 3.0, 5.0, 6.0],
+    //         pawn  rook  bishop guards knight lance promoted
+    structure: [5.0, 20.0, 10.0, 12.0, 30.0, 15.0, 25.0],
+};
+
+
+impl Default for EvalParams {
+    fn default() -> Self {
+        DEFAULT_PARAMS
+    }
+}
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ParamsError {
+    Io(String),
+    Syntax(String),                       // not valid TOML / JSON
+    UnknownName(String),
+    NotANumber { name: String, value: String },
+}
+
+
+impl fmt::Display for ParamsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParamsError::Io(err) => write!(f, "can't read the parameters: {}", err),
+            ParamsError::Syntax(err) => write!(f, "{}", err.trim_end()),
+            ParamsError::UnknownName(name) => write!(f, "unknown parameter {}", name),
+            ParamsError::NotANumber { name, value } => write!(f, "{} must be a number, not {}", name, value),
+        }
+    }
+}
+
+
+impl std::error::Error for ParamsError {}
+
+
+// the name a piece kind has in parameter names
+pub fn kind_name(kind: PieceKind) -> &'static str {
+    match kind {
+        PieceKind::Pawn => "pawn",
+        PieceKind::Lance => "lance",
+        PieceKind::Knight => "knight",
+        PieceKind::Silver => "silver",
+        PieceKind::Gold => "gold",
+        PieceKind::Bishop => "bishop",
+        PieceKind::Rook => "rook",
+        PieceKind::King => "king",
+        PieceKind::ProPawn => "pro_pawn",
+        PieceKind::ProLance => "pro_lance",
+        PieceKind::ProKnight => "pro_knight",
+        PieceKind::ProSilver => "pro_silver",
+        PieceKind::ProBishop => "pro_bishop",
+        PieceKind::ProRook => "pro_rook",
+    }
+}
+
+
+impl EvalParams {
+
+    // every weight with its name, in a fixed order
+    fn fields_mut(&mut self) -> Vec<(String, &mut f32)> {
+
+        let mut fields: Vec<(String, &mut f32)> = vec![
+            ("weights.promoted_pieces".to_string(), &mut self.promoted_pieces),
+            ("weights.pst".to_string(), &mut self.pst),
+            ("weights.king_vuln".to_string(), &mut self.king_vuln),
+            ("king_vuln.attacked".to_string(), &mut self.vuln_attacked),
+            ("king_vuln.defenders".to_string(), &mut self.vuln_defenders),
+            ("king_vuln.king_attackers".to_string(), &mut self.vuln_king_attackers),
+            ("king_vuln.escapes".to_string(), &mut self.vuln_escapes),
+        ];
+        for (kind, value) in PieceKind::all().iter().zip(self.mobility.iter_mut()) {
+            if *kind != PieceKind::King {
+                fields.push((format!("mobility.{}", kind_name(*kind)), value));
+            }
+        }
+        for (kind, value) in HAND_KINDS.iter().zip(self.hand_bonus.iter_mut()) {
+            fields.push((format!("hand_bonus.{}", kind_name(*kind)), value));
+        }
+        for (kind, value) in PieceKind::all().iter().zip(self.material.iter_mut()) {
+            if *kind != PieceKind::King {
+                fields.push((format!("material.{}", kind_name(*kind)), value));
+            }
+        }
+        for (kind, value) in HAND_KINDS.iter().zip(self.material_hand.iter_mut()) {
+            fields.push((format!("material_hand.{}", kind_name(*kind)), value));
+        }
+        for (castle, value) in Castle::ALL.iter().zip(self.castle.iter_mut()) {
+            fields.push((format!("castle.{}", castle.name()), value));
+        }
+        fields.push(("king_safety.weight".to_string(), &mut self.safety_weight));
+        fields.push(("king_safety.zone_squares".to_string(), &mut self.safety_zone_squares));
+        fields.push(("king_safety.safe_checks".to_string(), &mut self.safety_checks));
+        for (kind, value) in DROP_KINDS.iter().zip(self.safety_drops.iter_mut()) {
+            fields.push((format!("king_safety.drop_{}", kind_name(*kind)), value));
+        }
+        for (kind, value) in PieceKind::all().iter().zip(self.zone_attackers.iter_mut()) {
+            if *kind != PieceKind::King {
+                fields.push((format!("zone_attackers.{}", kind_name(*kind)), value));
+            }
+        }
+        for (name, value) in Structure::NAMES.iter().zip(self.structure.iter_mut()) {
+            fields.push((format!("structure.{}", name), value));
+        }
+        fields
+    }
+
+    // (name, value) of every weight
+    pub fn fields(&self) -> Vec<(String, f32)> {
+        let mut copy = *self;
+        copy.fields_mut().into_iter().map(|(name, value)| (name, *value)).collect()
+    }
+
+    pub fn names() -> Vec<String> {
+        DEFAULT_PARAMS.fields().into_iter().map(|(name, _)| name).collect()
+    }
+
+    pub fn set(&mut self, name: &str, value: f32) -> Result<(), ParamsError> {
+        let mut fields = self.fields_mut();
+        let slot = fields.iter_mut().find(|(n, _)| n == name).ok_or(ParamsError::UnknownName(name.to_string()))?;
+        *slot.1 = value;
+        Ok(())
+    }
+
+    // the weights `phase` of the way from `endgame` to `opening` (see TaperedParams)
+    pub fn blend(opening: &EvalParams, endgame: &EvalParams, phase: f32) -> EvalParams {
+        let mix = |opening: f32, endgame: f32| endgame + (opening - endgame) * phase;
+        let mix_all = |opening: &[f32], endgame: &mut [f32]| {
+            for (e, &o) in endgame.iter_mut().zip(opening) {
+                *e = mix(o, *e);
+            }
+        };
+        let (o, mut p) = (opening, *endgame);
+        p.promoted_pieces = mix(o.promoted_pieces, p.promoted_pieces);
+        p.pst = mix(o.pst, p.pst);
+        p.king_vuln = mix(o.king_vuln, p.king_vuln);
+        p.vuln_attacked = mix(o.vuln_attacked, p.vuln_attacked);
+        p.vuln_defenders = mix(o.vuln_defenders, p.vuln_defenders);
+        p.vuln_king_attackers = mix(o.vuln_king_attackers, p.vuln_king_attackers);
+        p.vuln_escapes = mix(o.vuln_escapes, p.vuln_escapes);
+        mix_all(&o.mobility, &mut p.mobility);
+        mix_all(&o.hand_bonus, &mut p.hand_bonus);
+        mix_all(&o.material, &mut p.material);
+        mix_all(&o.material_hand, &mut p.material_hand);
+        mix_all(&o.castle, &mut p.castle);
+        p.safety_weight = mix(o.safety_weight, p.safety_weight);
+        p.safety_zone_squares = mix(o.safety_zone_squares, p.safety_zone_squares);
+        p.safety_checks = mix(o.safety_checks, p.safety_checks);
+        mix_all(&o.safety_drops, &mut p.safety_drops);
+        mix_all(&o.zone_attackers, &mut p.zone_attackers);
+        mix_all(&o.structure, &mut p.structure);
+        p
+    }
+}
+
+
+// ------------------------------------ file formats ------------------------------------
+
+/*
+    TaperedParams are read and written through these, with their set / fields. A section of a TOML document or a nested JSON object adds its name (and a dot) to
+    the names inside it, so [opening.weights] king_vuln = 220 is opening.weights.king_vuln.
+ */
+
+type Setter<'a> = dyn FnMut(&str, f32) -> Result<(), ParamsError> + 'a;
+
+
+fn parse_toml(text: &str) -> Result<toml::Table, ParamsError> {
+    text.parse().map_err(|e: toml::de::Error| ParamsError::Syntax(e.to_string()))
+}
+
+
+fn apply_toml(set: &mut Setter, prefix: &str, table: &toml::Table) -> Result<(), ParamsError> {
+    for (key, value) in table {
+        let name = if prefix.is_empty() { key.clone() } else { format!("{}.{}", prefix, key) };
+        match value {
+            toml::Value::Table(inner) => apply_toml(set, &name, inner)?,
+            toml::Value::Integer(number) => set(&name, *number as f32)?,
+            toml::Value::Float(number) => set(&name, *number as f32)?,
+            other => return Err(ParamsError::NotANumber { name, value: other.to_string() }),
+        }
+    }
+    Ok(())
+}
+
+
+fn parse_json(text: &str) -> Result<serde_json::Value, ParamsError> {
+    serde_json::from_str(text).map_err(|e| ParamsError::Syntax(e.to_string()))
+}
+
+
+fn apply_json(set: &mut Setter, name: &str, value: &serde_json::Value) -> Result<(), ParamsError> {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, inner) in map {
+                let name = if name.is_empty() { key.clone() } else { format!("{}.{}", name, key) };
+                apply_json(set, &name, inner)?;
+            }
+            Ok(())
+        },
+        serde_json::Value::Number(number) => set(name, number.as_f64().unwrap_or(0.0) as f32),
+        other => Err(ParamsError::NotANumber { name: name.to_string(), value: other.to_string() }),
+    }
+}
+
+
+// a section for everything up to the last dot of a name
+fn to_toml(fields: &[(String, f32)]) -> String {
+    let mut text = String::new();
+    let mut section = "";
+    for (name, value) in fields {
+        let (group, key) = name.rsplit_once('.').expect("parameter names have a section");
+        if group != section {
+            if !text.is_empty() {
+                text.push('\n');
+            }
+            text.push_str(&format!("[{}]\n", group));
+            section = group;
+        }
+        text.push_str(&format!("{} = {}\n", key, value));
+    }
+    text
+}
+
+
+fn to_json(fields: &[(String, f32)]) -> serde_json::Value {
+    let mut root = serde_json::Map::new();
+    for (name, value) in fields {
+        let (group, key) = name.rsplit_once('.').expect("parameter names have a section");
+        let section = root.entry(group).or_insert_with(|| serde_json::Value::Object(serde_json::Map::new()));
+        if let serde_json::Value::Object(map) = section {
+            map.insert(key.to_string(), serde_json::json!(value));
+        }
+    }
+    serde_json::Value::Object(root)
+}
+
+
+//   ################################### TAPERED (BY GAME PHASE) ###################################
+
+/*
+    What matters changes over a game: developing and building a castle first, attacking the king
+    in the middle game, speed once the pieces are off and in hand. So every weight comes twice,
+    once for the opening and once for the endgame, and a position is evaluated with the weights
+    its phase (phase.rs: 1 at the start of the game, 0 deep in the endgame) of the way between
+    them: endgame + (opening - endgame) x phase.
+
+    The names are those of EvalParams under "opening." and "endgame.". A plain name, as in the
+    files written before weights had phases, sets the weight for both.
+ */
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TaperedParams {
+    pub opening: EvalParams,
+    pub endgame: EvalParams,
+}
+
+
+// Late in the game castles are broken up or left behind, while attacks on the king and the
+// pieces in hand that drive them decide it. The rest is as in the opening until tuned.
+pub const DEFAULT_ENDGAME: EvalParams = EvalParams {
+    king_vuln: 300.0,
+    //           P    L     N     S     G     B     R
+    hand_bonus: [2.0, 24.0, 12.0, 20.0, 30.0, 22.0, 24.0],
+    //      mino  high  ginkan anaguma yagura funa
+    castle: [60.0, 70.0, 80.0, 100.0, 70.0, 40.0],
+    safety_weight: 0.35,
+    ..DEFAULT_PARAMS
+};
+
+
+pub const DEFAULT_TAPERED: TaperedParams = TaperedParams { opening: DEFAULT_PARAMS, endgame: DEFAULT_ENDGAME };
+
+
+impl Default for TaperedParams {
+    fn default() -> Self {
+        DEFAULT_TAPERED
+    }
+}
+
+
+impl TaperedParams {
+
+    // the weights a position of game phase `phase` is evaluated with
+    pub fn at(&self, phase: f32) -> EvalParams {
+        EvalParams::blend(&self.opening, &self.endgame, phase)
+    }
+
+    // (name, value) of every weight, the opening ones first
+    pub fn fields(&self) -> Vec<(String, f32)> {
+        let phase = |prefix: &str, params: &EvalParams| params.fields().into_iter().map(|(name, value)| (format!("{}.{}", prefix, name), value)).collect::<Vec<_>>();
+        let mut fields = phase("opening", &self.opening);
+        fields.extend(phase("endgame", &self.endgame));
+        fields
+    }
+
+    pub fn names() -> Vec<String> {
+        DEFAULT_TAPERED.fields().into_iter().map(|(name, _)| name).collect()
+    }
+
+    // a name from names(), or a plain EvalParams one for both phases
+    pub fn set(&mut self, name: &str, value: f32) -> Result<(), ParamsError> {
+        let result = match name.split_once('.') {
+            Some(("opening", rest)) => self.opening.set(rest, value),
+            Some(("endgame", rest)) => self.endgame.set(rest, value),
+            _ => self.opening.set(name, value).and_then(|()| self.endgame.set(name, value)),
+        };
+        result.map_err(|_| ParamsError::UnknownName(name.to_string()))
+    }
+
+    // the defaults changed by the `name = value` pairs of a TOML document
+    pub fn from_toml(text: &str) -> Result<TaperedParams, ParamsError> {
+        let mut params = DEFAULT_TAPERED;
+        apply_toml(&mut |name, value| params.set(name, value), "", &parse_toml(text)?)?;
+        Ok(params)
+    }
+
+    // the defaults changed by the values of a JSON object, nested by section or flat
+    pub fn from_json(text: &str) -> Result<TaperedParams, ParamsError> {
+        let mut params = DEFAULT_TAPERED;
+        apply_json(&mut |name, value| params.set(name, value), "", &parse_json(text)?)?;
+        Ok(params)
+    }
+
+    // reads a .json file as JSON, anything else as TOML
+    pub fn load(path: &str) -> Result<TaperedParams, ParamsError> {
+        let text = std::fs::read_to_string(path).map_err(|e| ParamsError::Io(e.to_string()))?;
+        if path.ends_with(".json") { TaperedParams::from_json(&text) } else { TaperedParams::from_toml(&text) }
+    }
+
+    // every weight as a TOML document, one section per group; from_toml reads it back
+    pub fn to_toml(self) -> String {
+        to_toml(&self.fields())
+    }
+
+    // every weight as a nested JSON object; from_json reads it back
+    pub fn to_json(self) -> serde_json::Value {
+        to_json(&self.fields())
+    }
+}
+
+
+static ACTIVE: RwLock<TaperedParams> = RwLock::new(DEFAULT_TAPERED);
+
+
+// the parameters the engine evaluates with
+pub fn current() -> TaperedParams {
+    *ACTIVE.read().unwrap()
+}
+
+
+// Makes `params` the engine's. Evaluations cached under the old ones are dropped.
+pub fn set(params: TaperedParams) {
+    *ACTIVE.write().unwrap() = params;
+    evalcache::global().clear();
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn weights_by_name() {
+        let names = EvalParams::names();
+        assert_eq!(names.len(), 7 + 13 + 7 + 13 + 7 + 6 + 6 + 13 + 7);
+        assert_eq!(DEFAULT_PARAMS.fields().iter().map(|(name, _)| name).collect::<Vec<_>>(), names.iter().collect::<Vec<_>>());
+
+        let mut params = DEFAULT_PARAMS;
+        params.set("material.pro_rook", 1500.0).unwrap();
+        assert_eq!(params.material[PieceKind::ProRook.array_index()], 1500.0);
+        assert_eq!(params.set("material.king", 1.0), Err(ParamsError::UnknownName("material.king".to_string())));
+    }
+
+    #[test]
+    fn reads_toml_and_json() {
+        let toml = "[weights]\nking_vuln = 200\n\n[mobility]\nrook = 4.25\n\n[hand_bonus]\npawn = 2\n";
+        let params = TaperedParams::from_toml(toml).unwrap().opening;
+        assert_eq!((params.king_vuln, params.mobility[PieceKind::Rook.array_index()], params.hand_bonus[0]), (200.0, 4.25, 2.0));
+        assert_eq!(params.mobility[PieceKind::Lance.array_index()], DEFAULT_PARAMS.mobility[PieceKind::Lance.array_index()]);
+
+        let nested = TaperedParams::from_json(r#"{"weights": {"king_vuln": 200}, "hand_bonus.pawn": 2}"#).unwrap().endgame;
+        assert_eq!((nested.king_vuln, nested.hand_bonus[0]), (200.0, 2.0));
+
+        assert_eq!(TaperedParams::from_toml(&DEFAULT_TAPERED.to_toml()), Ok(DEFAULT_TAPERED));
+        assert_eq!(TaperedParams::from_json(&DEFAULT_TAPERED.to_json().to_string()), Ok(DEFAULT_TAPERED));
+
+        assert_eq!(TaperedParams::from_toml("[weights]\nkingvuln = 1\n"), Err(ParamsError::UnknownName("weights.kingvuln".to_string())));
+        assert!(matches!(TaperedParams::from_toml("[weights]\nking_vuln = \"high\"\n"), Err(ParamsError::NotANumber { .. })));
+    }
+
+    #[test]
+    fn weights_by_phase() {
+        let mut params = DEFAULT_TAPERED;
+        params.set("endgame.weights.king_vuln", 300.0).unwrap();
+        params.set("material.pawn", 90.0).unwrap();
+        assert_eq!((params.opening.king_vuln, params.endgame.king_vuln), (220.0, 300.0));
+        assert_eq!((params.opening.material[0], params.endgame.material[0]), (90.0, 90.0));
+        assert_eq!(params.at(0.5).king_vuln, 260.0);
+        assert!(params.set("opening.weights.kingvuln", 1.0).is_err());
+
+        // every weight is blended: with all of them apart, each end of the phase gives back its own
+        let mut apart = DEFAULT_TAPERED;
+        for (name, value) in DEFAULT_PARAMS.fields() {
+            apart.endgame.set(&name, value + 1.0).unwrap();
+        }
+        assert_eq!((apart.at(1.0), apart.at(0.0)), (apart.opening, apart.endgame));
+        assert_eq!((DEFAULT_TAPERED.at(1.0), DEFAULT_TAPERED.at(0.0)), (DEFAULT_PARAMS, DEFAULT_ENDGAME));
+
+        // the shipped phases differ in castles, king attack and pieces in hand
+        let (opening, endgame) = (DEFAULT_TAPERED.at(1.0), DEFAULT_TAPERED.at(0.0));
+        assert!(endgame.castle.iter().zip(&opening.castle).all(|(e, o)| e < o));
+        assert!(endgame.hand_bonus.iter().zip(&opening.hand_bonus).all(|(e, o)| e > o));
+        assert!(endgame.king_vuln > opening.king_vuln && endgame.safety_weight > opening.safety_weight);
+
+        assert_eq!(TaperedParams::names().len(), 2 * EvalParams::names().len());
+        assert_eq!(TaperedParams::from_toml(&params.to_toml()), Ok(params));
+        assert_eq!(TaperedParams::from_json(&params.to_json().to_string()), Ok(params));
+    }
+}
@@ -0,0 +1,166 @@
+/* Perft - move generator correctness and speed
+ *
+ * perft(n) counts the leaf positions of the full legal move tree n plies deep.
+ * Any bug in move generation or make/unmake changes the count, so the counts
+ * below are checked by `cargo test` and by `rusty_engine perft`.
+ *
+ * The last ply is counted in bulk: at depth 1 the number of legal moves is the
+ * number of leaves, nothing has to be made or unmade.
+ *
+ * The start position, matsuri and most-legal-moves counts are the published
+ * ones, the other positions were counted with shogi_legality_lite as a second
+ * opinion.
+ */
+
+use crate::movegen::{self, MoveList};
+use crate::position::Position;
+use shogi_core::{Move, ToUsi};
+use std::time::Instant;
+
+
+pub struct PerftCase {
+    pub name: &'static str,
+    pub sfen: &'static str,
+    pub counts: &'static [u64],  // counts[0] = perft(1), counts[1] = perft(2), ...
+}
+
+
+pub const SUITE: &[PerftCase] = &[
+    PerftCase {
+        name: "start position",
+        sfen: "lnsgkgsnl/1r5b1/ppppppppp/9/9/9/PPPPPPPPP/1B5R1/LNSGKGSNL b - 1",
+        counts: &[30, 900, 25470, 719731],
+    },
+    PerftCase {
+        name: "matsuri (drops everywhere)",
+        sfen: "l6nl/5+P1gk/2np1S3/p1p4Pp/3P2Sp1/1PPb2P1P/P5GS1/R8/LN4bKL w RGgsn5p 1",
+        counts: &[207, 28684, 4809015],
+    },
+    PerftCase {
+        name: "most legal moves",
+        sfen: "R8/2K1S1SSk/4B4/9/9/9/9/9/1L1L1L3 b RBGSNLP3g3n17p 1",
+        counts: &[593, 105677],
+    },
+    PerftCase {
+        name: "pawn drop mate",
+        sfen: "kn7/9/1G7/9/9/9/9/9/K8 b P 1",
+        counts: &[78, 158, 2948, 32309],
+    },
+    PerftCase {
+        name: "pinned pieces",
+        sfen: "4k4/4r4/9/1b7/4S4/9/2G6/9/4K4 b - 1",
+        counts: &[11, 290, 3630, 111938],
+    },
+    PerftCase {
+        name: "forced promotions",
+        sfen: "4k4/P8/2N6/6L2/9/9/9/9/4K4 b - 1",
+        counts: &[13, 49, 658, 4241],
+    },
+    PerftCase {
+        name: "check evasions with drops",
+        sfen: "4k4/9/9/9/9/9/9/9/r3K3b b GSNLP 1",
+        counts: &[18, 766, 204637],
+    },
+];
+
+
+// number of leaves `depth` plies below `pos`
+pub fn perft(pos: &mut Position, depth: u32) -> u64 {
+
+    if depth == 0 {
+        return 1;
+    }
+
+    let mut moves = MoveList::new();
+    movegen::generate_legal(pos, &mut moves);
+
+    // bulk counting: every legal move is a leaf
+    if depth == 1 {
+        return moves.len() as u64;
+    }
+
+    let mut nodes = 0;
+    for mv in moves.iter() {
+        let undo = pos.make_move(mv);
+        nodes += perft(pos, depth - 1);
+        pos.unmake_move(undo);
+    }
+    nodes
+}
+
+
+// perft split by the first move, to find which move a wrong count comes from
+pub fn divide(pos: &mut Position, depth: u32) -> Vec<(Move, u64)> {
+
+    let mut moves = MoveList::new();
+    movegen::generate_legal(pos, &mut moves);
+
+    moves.iter().map(|mv| {
+        let undo = pos.make_move(mv);
+        let nodes = perft(pos, depth.saturating_sub(1));
+        pos.unmake_move(undo);
+        (mv, nodes)
+    }).collect()
+}
+
+
+pub fn print_divide(sfen: &str, depth: u32) {
+
+    let mut pos = Position::from_sfen(sfen);
+    let start = Instant::now();
+    let split = divide(&mut pos, depth);
+
+    for (mv, nodes) in &split {
+        println!(" | {:<6} {}", mv.to_usi_owned(), nodes);
+    }
+    let total: u64 = split.iter().map(|(_, nodes)| nodes).sum();
+    println!(" | moves: {} nodes: {} time: {:?}", split.len(), total, start.elapsed());
+}
+
+
+// Runs the suite up to `max_depth` and prints every count. Returns whether all matched.
+pub fn run_suite(max_depth: u32) -> bool {
+
+    let mut all_ok = true;
+
+    for case in SUITE {
+        println!(" | {}", case.name);
+        let mut pos = Position::from_sfen(case.sfen);
+        for (depth, &expected) in (1..=max_depth).zip(case.counts) {
+            let start = Instant::now();
+            let nodes = perft(&mut pos, depth);
+            let ok = nodes == expected;
+            all_ok &= ok;
+            println!(" |   perft({}) = {:<10} expected {:<10} {} {:?}", depth, nodes, expected, if ok { "ok" } else { "MISMATCH" }, start.elapsed());
+        }
+    }
+
+    all_ok
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn suite_counts_match() {
+        for case in SUITE {
+            let mut pos = Position::from_sfen(case.sfen);
+            for (depth, &expected) in (1..).zip(case.counts) {
+                assert_eq!(perft(&mut pos, depth), expected, "{} perft({})", case.name, depth);
+            }
+            // make/unmake must leave the position exactly as it was
+            assert_eq!(pos, Position::from_sfen(case.sfen), "{} changed by perft", case.name);
+        }
+    }
+
+    #[test]
+    fn divide_adds_up_to_perft() {
+        let case = &SUITE[0];
+        let mut pos = Position::from_sfen(case.sfen);
+        let split = divide(&mut pos, 3);
+        assert_eq!(split.len() as u64, case.counts[0]);
+        assert_eq!(split.iter().map(|(_, nodes)| nodes).sum::<u64>(), case.counts[2]);
+    }
+}
@@ -0,0 +1,87 @@
+/* Game phase: how far a position is from the opening
+ *
+ * Shogi pieces never leave the game, captured ones go to the captor's hand,
+ * so how much material is on the board against how much is in hand says how
+ * many exchanges there have been. The other sign of a late game is the armies
+ * closing in: pieces standing within two squares of the enemy king.
+ *
+ *    hand units      every piece in either hand, by kind (HAND_UNITS)
+ *    proximity units every piece (kings aside) within two files and ranks of
+ *                    the opponent's king, NEAR_KING_UNITS each
+ *
+ * The phase is 1 with no units at all (the start position), falling linearly
+ * to 0 at ENDGAME_UNITS and staying there. TaperedParams (params.rs) blends
+ * the opening and endgame weights by it.
+ */
+
+use crate::bitboard::Bitboard;
+use crate::position::{Position, HAND_KINDS};
+use shogi_core::{Color, PieceKind};
+
+
+// what a piece in hand adds, indexed like HAND_KINDS: P L N S G B R
+const HAND_UNITS: [u32; 7] = [1, 2, 2, 3, 3, 5, 5];
+
+// what a piece close to the opponent's king adds
+const NEAR_KING_UNITS: u32 = 2;
+
+// units from which on a position is all endgame
+pub const ENDGAME_UNITS: u32 = 48;
+
+
+// the squares within two files and ranks of `color`'s king
+pub fn king_area(pos: &Position, color: Color) -> Bitboard {
+    let mut area = Bitboard::EMPTY;
+    let Some(king) = pos.king_square(color) else {
+        return area;
+    };
+    for file_delta in -2..=2 {
+        for rank_delta in -2..=2 {
+            if let Some(square) = king.shift(file_delta, rank_delta) {
+                area.set(square);
+            }
+        }
+    }
+    area
+}
+
+
+// the hand and proximity units of a position
+pub fn units(pos: &Position) -> u32 {
+    let mut units = 0;
+    for color in Color::all() {
+        units += HAND_KINDS.iter().zip(HAND_UNITS).map(|(&kind, value)| pos.hand(color, kind) as u32 * value).sum::<u32>();
+        let attackers = pos.color_bitboard(color.flip()) & !pos.kind_bitboard(PieceKind::King);
+        units += (king_area(pos, color) & attackers).count() * NEAR_KING_UNITS;
+    }
+    units
+}
+
+
+// 1 in the opening down to 0 in the endgame
+pub fn phase(pos: &Position) -> f32 {
+    1.0 - units(pos).min(ENDGAME_UNITS) as f32 / ENDGAME_UNITS as f32
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn phase_falls_with_captures_and_attackers() {
+        assert_eq!(phase(&Position::startpos()), 1.0);
+
+        // a bishop exchange: 5 units a side
+        let traded = Position::from_sfen("lnsgkgsnl/1r7/ppppppppp/9/9/9/PPPPPPPPP/7R1/LNSGKGSNL b Bb 1");
+        assert_eq!(units(&traded), 10);
+        assert!(phase(&traded) < 1.0);
+
+        // a gold next to the white king and both rooks in hand
+        let late = Position::from_sfen("4k4/4G4/9/9/9/9/9/9/4K4 b 2R 1");
+        assert_eq!(units(&late), NEAR_KING_UNITS + 10);
+        assert_eq!(phase(&late), phase(&late.flipped()));
+
+        assert_eq!(phase(&Position::from_sfen("4k4/9/9/9/9/9/9/9/4K4 b 2R2B4G4S4N4L18P 1")), 0.0);
+    }
+}
@@ -1,11 +1,15 @@
 // Russell Kosovsky
 
 use std::io;
+use std::sync::atomic::AtomicBool;
 use crate::book;
 use crate::eval;
+use crate::params;
+use crate::position::Position;
 use crate::view;
-use crate::sfen;
 use crate::search;
+use crate::search::{SearchLimits, SearchResult};
+use crate::ponder::Ponder;
 use crate::tree::Tree;
 use shogi_legality_lite::{normal_from_candidates, is_legal_partial_lite, all_legal_moves_partial, status_partial};
 use shogi_core::{PartialPosition, PositionStatus, Square, Piece, Color, Move, PieceKind};
@@ -232,49 +236,43 @@ pub fn play_book() {
 
 /////////////////////////////////// OG PLAY FUNCTION /////////////////////////////////////////
 
+// search depth (in plies) used by the computer player
+const SEARCH_PLIES: u32 = 2;
+
+
 fn computer_move_OG(root_sfen: &str) -> Move {
 
-    let dep = 3;
-    let color = sfen::get_color(&root_sfen);
-    
-    let root = search::treesearch(&root_sfen, dep, 1, None); // Create the root GameTree node
+    let limits = SearchLimits::depth(SEARCH_PLIES);
+    let result = search::think(root_sfen, &limits, &AtomicBool::new(false));
+
+    report_search(&result)
+
+}
 
-    //let ((white_score, black_score), best_move, best_features) = search::get_best_move(&root, dep, color);
-    let ((white_score, black_score), best_move, best_features, best_sfen) = search::minimax(&root, dep, color);
 
-    let (white_promoted_pieces, black_promoted_pieces) = best_features[0];
-    let (white_pst, black_pst) = best_features[1];
-    let (white_king_vln, black_king_vln) = best_features[2];
-    let (white_rook_mobil, black_rook_mobil) = best_features[3];
-    let (white_lance_mobil, black_lance_mobil) = best_features[4];
-    let (white_bish_mobil, black_bish_mobil) = best_features[5];
-    let (white_hand, black_hand) = best_features[6];
+// prints the result of a search (chosen move, score and features) and returns the chosen move
+fn report_search(result: &SearchResult) -> Move {
+
+    let (white_score, black_score) = result.score;
+    let best_move = result.best_move;
+    let best_sfen: &str = &result.best_sfen;
 
     println!(" | best move: {:?}", best_move);
+    match result.mate_plies {
+        Some(plies) if plies > 0 => println!(" | mate in {} ({} plies)", (plies + 1) / 2, plies),
+        Some(plies) => println!(" | mated in {} ({} plies)", -plies / 2, -plies),
+        None => {},
+    }
+    println!(" | depth: {} plies, nodes: {}, time: {:?}", result.depth, result.nodes, result.time);
+    println!(" | eval cache: {} hits / {} probes ({:.1}%)", result.eval_cache.hits, result.eval_cache.probes, result.eval_cache.hit_rate());
     println!(" | ");
     println!(" | best sfen: {:?}", best_sfen);
     view::display_sfen(best_sfen);
     println!(" | ");
     println!(" | white_score: {:?}", white_score);
     println!(" | black_score: {:?}", black_score);
-    println!(" | feature variate values: ");
-    println!(" |    |WHITE|");
-    println!(" | white_promoted_pieces: {:?}", white_promoted_pieces);
-    println!(" | white_pst: {:?}", white_pst);
-    println!(" | white_king_vln: {:?}", white_king_vln);
-    println!(" | white_rook_mobil: {:?}", white_rook_mobil);
-    println!(" | white_lance_mobil: {:?}", white_lance_mobil);
-    println!(" | white_bish_mobil: {:?}", white_bish_mobil);
-    println!(" | white_hand: {:?}", white_hand);
-    println!(" | ");
-    println!(" |    |BLACK|");
-    println!(" | black_promoted_pieces: {:?}", black_promoted_pieces);
-    println!(" | black_pst: {:?}", black_pst);
-    println!(" | black_king_vln: {:?}", black_king_vln);
-    println!(" | black_rook_mobil: {:?}", black_rook_mobil);
-    println!(" | black_lance_mobil: {:?}", black_lance_mobil);
-    println!(" | black_bish_mobil: {:?}", black_bish_mobil);
-    println!(" | black_hand: {:?}", black_hand);
+    println!(" | evaluation of the best sfen: ");
+    println!("{}", eval::EvalTrace::new(&Position::from_sfen(best_sfen), &params::current()).table());
     println!(" | ");
 
     best_move.unwrap()
@@ -303,6 +301,9 @@ pub fn play_OG() {
     //println!("sfen: {:?}", sfen);
     view::display_sfen(&sfen);
 
+    // background search on the reply we expect from the human (see ponder.rs)
+    let mut ponder: Option<Ponder> = None;
+
     // main game loop
     loop {
         let human_mv = human_move();
@@ -317,9 +318,11 @@ pub fn play_OG() {
             // game end condition
             if shogi_legality_lite::status_partial(&board) == PositionStatus::BlackWins { // check if it's checkmate
                 println!("Congratulations! You won.");
+                if let Some(p) = ponder.take() { p.stop(); }
                 break;
             } else if shogi_legality_lite::status_partial(&board) == PositionStatus::Draw { // check if it's stalemate
                 println!("Game is a draw.");
+                if let Some(p) = ponder.take() { p.stop(); }
                 break;
             }
 
@@ -329,7 +332,20 @@ pub fn play_OG() {
             println!(" | thinking...");
             println!(" | ");
             
-            let computer_mv = computer_move_OG(&sfen);
+            // on a ponderhit the search done while the human was thinking is used as is
+            let limits = SearchLimits::depth(SEARCH_PLIES);
+            let result = match ponder.take().and_then(|p| p.resolve(human_mv)) {
+                Some(result) => {
+                    println!(" | ponderhit! reusing the search done on your time");
+                    println!(" | ");
+                    result
+                },
+                None => search::think(&sfen, &limits, &AtomicBool::new(false)),
+            };
+
+            let computer_mv = report_search(&result);
+            let predicted_mv = result.ponder_move;
+
             board.make_move(computer_mv);
             sfen = board.to_sfen_owned(); 
             view::display_sfen(&sfen);
@@ -344,6 +360,12 @@ pub fn play_OG() {
                 break;
             }
 
+            // start thinking on the human's time
+            if let Some(reply) = predicted_mv {
+                println!(" | pondering on: {:?}", reply);
+                ponder = Ponder::start(&board, reply, &limits);
+            }
+
         } else {
             println!("Illegal move, please try again.");
         }
@@ -0,0 +1,104 @@
+/* Pondering: thinking on the opponent's time.
+ *
+ * After the engine plays its move it guesses the opponent's reply (the second
+ * move of the principal variation) and starts searching the position after
+ * that reply in a background thread.
+ *
+ *  - ponderhit: the opponent played the predicted move, so the background
+ *    search is simply waited on and its result is used as the engine's answer.
+ *  - stop: the prediction was wrong (or the game ended), the search is told to
+ *    stop and its work is thrown away.
+ */
+
+use crate::search;
+use crate::search::{SearchLimits, SearchResult};
+use shogi_core::{Move, PartialPosition};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+
+pub struct Ponder {
+    pub predicted: Move,               // the reply we are pondering on
+    stop: Arc<AtomicBool>,             // raised to abort the background search
+    handle: JoinHandle<SearchResult>,  // background search of the predicted position
+}
+
+
+impl Ponder {
+
+    // Starts pondering on `predicted` played from `pos` (the position after the engine's move).
+    // The background search obeys `limits` exactly like a normal search would.
+    // Returns None if the predicted move can't be played in `pos`.
+    pub fn start(pos: &PartialPosition, predicted: Move, limits: &SearchLimits) -> Option<Ponder> {
+
+        let mut ponder_pos = pos.clone();
+        ponder_pos.make_move(predicted)?;
+        let sfen = ponder_pos.to_sfen_owned();
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = Arc::clone(&stop);
+        let limits = limits.clone();
+        let handle = thread::spawn(move || {
+            search::think(&sfen, &limits, &thread_stop)
+        });
+
+        Some(Ponder { predicted, stop, handle })
+    }
+
+    // The opponent played the predicted move: let the search finish and hand its result over.
+    pub fn ponderhit(self) -> SearchResult {
+        self.handle.join().expect("ponder thread panicked")
+    }
+
+    // The opponent played something else: abort the search and discard it.
+    pub fn stop(self) {
+        self.stop.store(true, Ordering::Relaxed);
+        let _ = self.handle.join();
+    }
+
+    // Handles the opponent's actual move, returning the pondered search on a ponderhit.
+    pub fn resolve(self, actual: Move) -> Option<SearchResult> {
+        if actual == self.predicted {
+            Some(self.ponderhit())
+        } else {
+            self.stop();
+            None
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shogi_core::Square;
+
+    fn square(file: u8, rank: u8) -> Square {
+        Square::new(file, rank).unwrap()
+    }
+
+    #[test]
+    fn resolves_hits_and_misses() {
+        // the engine played 7g7f, we expect 3c3d
+        let mut pos = PartialPosition::startpos();
+        pos.make_move(Move::Normal { from: square(7, 7), to: square(7, 6), promote: false }).unwrap();
+        let predicted = Move::Normal { from: square(3, 3), to: square(3, 4), promote: false };
+        let other = Move::Normal { from: square(8, 3), to: square(8, 4), promote: false };
+
+        // a hit hands over the search of the position after the prediction, a reply for black
+        let ponder = Ponder::start(&pos, predicted, &SearchLimits::depth(1)).unwrap();
+        let result = ponder.resolve(predicted).expect("ponderhit");
+        let mut after = pos.clone();
+        after.make_move(predicted).unwrap();
+        let best = result.best_move.expect("a move");
+        assert!(after.clone().make_move(best).is_some());
+
+        // a miss stops even a search without limits
+        let ponder = Ponder::start(&pos, predicted, &SearchLimits { infinite: true, ..Default::default() }).unwrap();
+        assert!(ponder.resolve(other).is_none());
+
+        // a prediction that can't be played isn't pondered on
+        assert!(Ponder::start(&pos, Move::Normal { from: square(5, 5), to: square(5, 4), promote: false }, &SearchLimits::depth(1)).is_none());
+    }
+}
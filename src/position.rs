@@ -0,0 +1,426 @@
+/* Position - the engine's own board representation
+ *
+ * Search and eval used to go through an SFEN string for every node
+ * (sfen_parse -> generate_pos -> to_sfen_owned). A Position is instead updated
+ * in place: make_move returns an Undo record and unmake_move uses it to put
+ * the board back exactly as it was.
+ *
+ *  - board:     mailbox, what is on each square
+ *  - by_kind:   one bitboard per piece kind (both colours)
+ *  - by_color:  one bitboard per colour (all kinds)
+ *  - hands:     piece counts in hand, indexed [colour][kind] for the 7 hand kinds
+ *  - inc:       the running eval sums (promoted pieces, PSTs, piece counts), see eval::Incremental
+ *  - key:       Zobrist hash of board, hands and side to move, see zobrist.rs
+ *  - nnue:      the network's accumulator while one is active, see nnue.rs
+ *
+ * Conversions to and from shogi_core's PartialPosition keep everything that
+ * still relies on shogi_legality_lite (tsume solver, legacy search) working.
+ */
+
+use crate::attacks;
+use crate::bitboard::Bitboard;
+use crate::eval::Incremental;
+use crate::movegen::{self, MoveList};
+use crate::nnue::{self, Accumulator, Network};
+use crate::sfen;
+use crate::zobrist;
+use shogi_core::{Color, Hand, Move, PartialPosition, Piece, PieceKind, PositionStatus, Square};
+use std::sync::Arc;
+
+
+// kinds that can be held in hand, in PieceKind::array_index order
+pub const HAND_KINDS: [PieceKind; 7] = [
+    PieceKind::Pawn,
+    PieceKind::Lance,
+    PieceKind::Knight,
+    PieceKind::Silver,
+    PieceKind::Gold,
+    PieceKind::Bishop,
+    PieceKind::Rook,
+];
+
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Position {
+    board: [Option<Piece>; 81],
+    by_kind: [Bitboard; PieceKind::NUM],
+    by_color: [Bitboard; 2],
+    hands: [[u8; 7]; 2],
+    side: Color,
+    ply: u16,
+    inc: Incremental,
+    key: u64,
+    nnue: Option<Box<Accumulator>>,
+}
+
+
+// everything unmake_move needs that the move itself doesn't say
+#[derive(Clone, Copy, Debug)]
+pub struct Undo {
+    mv: Move,
+    captured: Option<Piece>,
+}
+
+
+impl Position {
+
+    pub fn empty() -> Position {
+        Position {
+            board: [None; 81],
+            by_kind: [Bitboard::EMPTY; PieceKind::NUM],
+            by_color: [Bitboard::EMPTY; 2],
+            hands: [[0; 7]; 2],
+            side: Color::Black,
+            ply: 1,
+            inc: Incremental::default(),
+            key: 0,
+            nnue: nnue::network().map(|net| Box::new(Accumulator::new(net))),
+        }
+    }
+
+    pub fn startpos() -> Position {
+        Position::from_partial(&PartialPosition::startpos())
+    }
+
+    pub fn from_partial(partial: &PartialPosition) -> Position {
+
+        let mut pos = Position::empty();
+
+        for square in Square::all() {
+            if let Some(piece) = partial.piece_at(square) {
+                pos.put(square, piece);
+            }
+        }
+        for color in Color::all() {
+            let hand = partial.hand_of_a_player(color);
+            for kind in HAND_KINDS {
+                pos.hand_set(color, kind, hand.count(kind).unwrap_or(0));
+            }
+        }
+        pos.side_to_move_set(partial.side_to_move());
+        pos.ply = partial.ply();
+
+        pos
+    }
+
+    pub fn to_partial(&self) -> PartialPosition {
+
+        let mut partial = PartialPosition::empty();
+
+        for square in self.occupied() {
+            partial.piece_set(square, self.piece_at(square));
+        }
+        for color in Color::all() {
+            let hand: &mut Hand = partial.hand_of_a_player_mut(color);
+            for kind in HAND_KINDS {
+                for _ in 0..self.hand(color, kind) {
+                    if let Some(added) = hand.added(kind) {
+                        *hand = added;
+                    }
+                }
+            }
+        }
+        partial.side_to_move_set(self.side);
+        let _ = partial.ply_set(self.ply);
+
+        partial
+    }
+
+    // only used at the edges (command line, USI, display), never per node;
+    // see sfen::parse for input that may be malformed
+    pub fn from_sfen(sfen: &str) -> Position {
+        match sfen::parse(sfen) {
+            Ok(pos) => pos,
+            Err(err) => panic!("invalid sfen \"{}\": {}", sfen, err),
+        }
+    }
+
+    pub fn to_sfen(&self) -> String {
+        self.to_partial().to_sfen_owned()
+    }
+
+    // The same position from the other side of the board: turned around, every piece and
+    // hand changing colour and the other side to move. A fair evaluation scores it the
+    // other way round.
+    pub fn flipped(&self) -> Position {
+
+        let mut flipped = Position::empty();
+
+        for square in self.occupied() {
+            let piece = self.piece_at(square).expect("occupied square");
+            let turned = Square::new(10 - square.file(), 10 - square.rank()).expect("square on the board");
+            flipped.put(turned, Piece::new(piece.piece_kind(), piece.color().flip()));
+        }
+        for color in Color::all() {
+            for kind in HAND_KINDS {
+                flipped.hand_set(color.flip(), kind, self.hand(color, kind));
+            }
+        }
+        flipped.side_to_move_set(self.side.flip());
+        flipped.ply = self.ply;
+
+        flipped
+    }
+
+    // ---------------------------------------- queries ----------------------------------------
+
+    pub fn piece_at(&self, square: Square) -> Option<Piece> {
+        self.board[square.array_index()]
+    }
+
+    pub fn side_to_move(&self) -> Color {
+        self.side
+    }
+
+    pub fn side_to_move_set(&mut self, color: Color) {
+        if color != self.side {
+            self.key ^= zobrist::side_key();
+        }
+        self.side = color;
+    }
+
+    pub fn ply(&self) -> u16 {
+        self.ply
+    }
+
+    pub fn ply_set(&mut self, ply: u16) {
+        self.ply = ply;
+    }
+
+    pub fn hand(&self, color: Color, kind: PieceKind) -> u8 {
+        self.hands[color.array_index()][kind.array_index()]
+    }
+
+    pub fn occupied(&self) -> Bitboard {
+        self.by_color[0] | self.by_color[1]
+    }
+
+    pub fn color_bitboard(&self, color: Color) -> Bitboard {
+        self.by_color[color.array_index()]
+    }
+
+    // running eval sums, always equal to eval::Incremental::compute(self)
+    pub fn incremental(&self) -> Incremental {
+        self.inc
+    }
+
+    // Zobrist hash, always equal to zobrist::compute(self); the ply doesn't take part
+    pub fn key(&self) -> u64 {
+        self.key
+    }
+
+    pub fn kind_bitboard(&self, kind: PieceKind) -> Bitboard {
+        self.by_kind[kind.array_index()]
+    }
+
+    pub fn piece_bitboard(&self, piece: Piece) -> Bitboard {
+        self.kind_bitboard(piece.piece_kind()) & self.color_bitboard(piece.color())
+    }
+
+    pub fn accumulator(&self) -> Option<&Accumulator> {
+        self.nnue.as_deref()
+    }
+
+    // keeps an accumulator for `net` from now on, whichever network is active
+    pub fn attach_accumulator(&mut self, net: Arc<Network>) {
+        self.nnue = Some(Box::new(Accumulator::compute(net, self)));
+    }
+
+    pub fn king_square(&self, color: Color) -> Option<Square> {
+        self.piece_bitboard(Piece::new(PieceKind::King, color)).first()
+    }
+
+    // ----------------------------------- attacks and checks -----------------------------------
+
+    // pieces of `color` attacking `square`
+    pub fn attackers_to(&self, square: Square, color: Color) -> Bitboard {
+        attacks::attackers_to(self, square, color, self.occupied())
+    }
+
+    // enemy pieces giving check to the side to move
+    pub fn checkers(&self) -> Bitboard {
+        match self.king_square(self.side) {
+            Some(king) => self.attackers_to(king, self.side.flip()),
+            None => Bitboard::EMPTY,
+        }
+    }
+
+    pub fn is_in_check(&self) -> bool {
+        !self.checkers().is_empty()
+    }
+
+    // pieces of `color` pinned to their own king
+    pub fn pinned_pieces(&self, color: Color) -> Bitboard {
+        attacks::pinned_pieces(self, color)
+    }
+
+    // every square `color` attacks
+    pub fn attack_map(&self, color: Color) -> Bitboard {
+        attacks::attack_map(self, color)
+    }
+
+    // number of `color` pieces attacking each square, by Square::array_index
+    pub fn attack_counts(&self, color: Color) -> [u8; 81] {
+        attacks::attack_counts(self, color)
+    }
+
+    // all legal moves for the side to move (see movegen for the staged generators)
+    pub fn legal_moves(&self) -> Vec<Move> {
+        let mut list = MoveList::new();
+        movegen::generate_legal(self, &mut list);
+        list.as_slice().to_vec()
+    }
+
+    // the side to move has lost when it has no legal move left
+    pub fn status(&self) -> PositionStatus {
+        if !movegen::is_mated(self) {
+            return PositionStatus::InProgress;
+        }
+        match self.side {
+            Color::Black => PositionStatus::WhiteWins,
+            Color::White => PositionStatus::BlackWins,
+        }
+    }
+
+    // -------------------------------------- board edits --------------------------------------
+
+    fn put(&mut self, square: Square, piece: Piece) {
+        self.board[square.array_index()] = Some(piece);
+        self.by_kind[piece.piece_kind().array_index()].set(square);
+        self.by_color[piece.color().array_index()].set(square);
+        self.inc.add_piece(piece, square);
+        self.key ^= zobrist::piece_key(piece, square);
+        if let Some(mut acc) = self.nnue.take() {
+            acc.piece_added(self, piece, square);
+            self.nnue = Some(acc);
+        }
+    }
+
+    fn remove(&mut self, square: Square) -> Option<Piece> {
+        let piece = self.board[square.array_index()].take()?;
+        self.by_kind[piece.piece_kind().array_index()].clear(square);
+        self.by_color[piece.color().array_index()].clear(square);
+        self.inc.remove_piece(piece, square);
+        self.key ^= zobrist::piece_key(piece, square);
+        if let Some(acc) = &mut self.nnue {
+            acc.piece_removed(piece, square);
+        }
+        Some(piece)
+    }
+
+    // adds `delta` (possibly negative) pieces of `kind` to `color`'s hand
+    fn hand_add(&mut self, color: Color, kind: PieceKind, delta: i8) {
+        let old = self.hand(color, kind);
+        self.hand_set(color, kind, old.wrapping_add_signed(delta));
+    }
+
+    pub fn piece_set(&mut self, square: Square, piece: Option<Piece>) {
+        self.remove(square);
+        if let Some(piece) = piece {
+            self.put(square, piece);
+        }
+    }
+
+    pub fn hand_set(&mut self, color: Color, kind: PieceKind, count: u8) {
+        let old = self.hand(color, kind);
+        self.hands[color.array_index()][kind.array_index()] = count;
+        self.key ^= zobrist::hand_key(color, kind, old) ^ zobrist::hand_key(color, kind, count);
+        if let Some(acc) = &mut self.nnue {
+            acc.hand_changed(color, kind, old, count);
+        }
+    }
+
+    // Plays `mv` for the side to move. The move must be legal (or at least
+    // pseudo-legal: a piece of the side to move on `from`, a piece in hand to drop).
+    pub fn make_move(&mut self, mv: Move) -> Undo {
+
+        let side = self.side;
+        let mut captured = None;
+
+        match mv {
+            Move::Normal { from, to, promote } => {
+                let piece = self.remove(from).expect("make_move: no piece on the from square");
+                captured = self.remove(to);
+                if let Some(taken) = captured {
+                    let kind = taken.piece_kind().unpromote().unwrap_or(taken.piece_kind());
+                    self.hand_add(side, kind, 1);
+                }
+                let moved = if promote { piece.promote().expect("make_move: piece can't promote") } else { piece };
+                self.put(to, moved);
+            },
+            Move::Drop { piece, to } => {
+                self.hand_add(side, piece.piece_kind(), -1);
+                self.put(to, piece);
+            },
+        }
+
+        self.side = side.flip();
+        self.key ^= zobrist::side_key();
+        self.ply += 1;
+
+        Undo { mv, captured }
+    }
+
+    // takes back the move `undo` was returned for (moves must be unmade in reverse order)
+    pub fn unmake_move(&mut self, undo: Undo) {
+
+        self.side = self.side.flip();
+        self.key ^= zobrist::side_key();
+        self.ply -= 1;
+        let side = self.side;
+
+        match undo.mv {
+            Move::Normal { from, to, promote } => {
+                let moved = self.remove(to).expect("unmake_move: no piece on the to square");
+                let piece = if promote { moved.unpromote().expect("unmake_move: piece isn't promoted") } else { moved };
+                self.put(from, piece);
+                if let Some(taken) = undo.captured {
+                    let kind = taken.piece_kind().unpromote().unwrap_or(taken.piece_kind());
+                    self.hand_add(side, kind, -1);
+                    self.put(to, taken);
+                }
+            },
+            Move::Drop { piece, to } => {
+                self.remove(to);
+                self.hand_add(side, piece.piece_kind(), 1);
+            },
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval;
+
+    // random games: the running eval sums and the hash key must match a recomputation
+    // after every make and unmake, and unmaking must give back the same position
+    #[test]
+    fn incremental_eval_follows_make_and_unmake() {
+        for _ in 0..20 {
+            let mut pos = Position::startpos();
+            let mut undos = Vec::new();
+            for _ in 0..200 {
+                let moves = pos.legal_moves();
+                if moves.is_empty() {
+                    break;
+                }
+                let mv = moves[random_number::random!(..moves.len())];
+                let before = pos.clone();
+                let undo = pos.make_move(mv);
+                assert_eq!(pos.incremental(), eval::Incremental::compute(&pos), "{}", pos.to_sfen());
+                assert_eq!(pos.key(), zobrist::compute(&pos), "{}", pos.to_sfen());
+                pos.unmake_move(undo);
+                assert_eq!(pos, before);
+                undos.push(pos.make_move(mv));
+            }
+            while let Some(undo) = undos.pop() {
+                pos.unmake_move(undo);
+                assert_eq!(pos.incremental(), eval::Incremental::compute(&pos));
+                assert_eq!(pos.key(), zobrist::compute(&pos));
+            }
+            assert_eq!(pos, Position::startpos());
+        }
+    }
+}
@@ -0,0 +1,249 @@
+/* Piece square tables
+ *
+ * The tables are text (see pst.txt for the format): the built-in ones are
+ * compiled in from src/pst.txt, others can be loaded from a file at runtime.
+ * A loaded file only needs the tables it changes, the rest stay as they were.
+ *
+ * Each table is written from black's side of the board and white's pieces look
+ * up the square turned around, so a position and the same position with the
+ * colours swapped get exactly opposite scores. symmetric_files additionally
+ * makes every table the same on both wings (file 1 = file 9, 2 = 8...).
+ *
+ * Position keeps its PST sum up to date as pieces move (eval::Incremental), so
+ * the active tables should only be swapped between searches.
+ */
+
+use crate::evalcache;
+use shogi_core::{Color, Piece, PieceKind, Square};
+use std::fmt;
+use std::sync::{OnceLock, RwLock};
+
+
+const DEFAULT_TABLES: &str = include_str!("pst.txt");
+
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PieceSquareTables {
+    tables: [[i32; 81]; PieceKind::NUM],  // indexed [kind][table index], the king's is all zero
+}
+
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PstError {
+    Io(String),
+    UnknownPiece { line: usize, name: String },
+    KingTable(usize),                              // the king has no table
+    Value { line: usize, text: String },
+    RowLength { line: usize, count: usize },
+    MissingRows(String),                           // the file ended inside this table
+    ExtraRows(String),
+    ValuesOutsideTable(usize),
+}
+
+
+impl fmt::Display for PstError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PstError::Io(err) => write!(f, "can't read the tables: {}", err),
+            PstError::UnknownPiece { line, name } => write!(f, "line {}: unknown piece \"{}\"", line, name),
+            PstError::KingTable(line) => write!(f, "line {}: the king has no table", line),
+            PstError::Value { line, text } => write!(f, "line {}: \"{}\" is not a number", line, text),
+            PstError::RowLength { line, count } => write!(f, "line {}: {} values, a row has 9", line, count),
+            PstError::MissingRows(name) => write!(f, "table {} has fewer than 9 rows", name),
+            PstError::ExtraRows(name) => write!(f, "table {} has more than 9 rows", name),
+            PstError::ValuesOutsideTable(line) => write!(f, "line {}: values before any piece letter", line),
+        }
+    }
+}
+
+
+impl std::error::Error for PstError {}
+
+
+// piece kind for an SFEN letter, with + for the promoted ones
+fn kind_of(name: &str) -> Option<PieceKind> {
+    let (promoted, letter) = match name.strip_prefix('+') {
+        Some(letter) => (true, letter),
+        None => (false, name),
+    };
+    let kind = match letter {
+        "P" => PieceKind::Pawn,
+        "L" => PieceKind::Lance,
+        "N" => PieceKind::Knight,
+        "S" => PieceKind::Silver,
+        "G" => PieceKind::Gold,
+        "B" => PieceKind::Bishop,
+        "R" => PieceKind::Rook,
+        "K" => PieceKind::King,
+        _ => return None,
+    };
+    if promoted { kind.promote() } else { Some(kind) }
+}
+
+
+// Table index of a square for a piece of `color`: black reads the board like a diagram,
+// rank a first and file 9 first in every rank; white reads it turned around.
+fn table_index(square: Square, color: Color) -> usize {
+    let index = (square.rank() as usize - 1) * 9 + (9 - square.file() as usize);
+    if color == Color::Black { index } else { 80 - index }
+}
+
+
+// the table being read (if any) must have all its rows by now
+fn check_complete(current: &Option<(PieceKind, String)>, given: &[Option<Vec<i32>>]) -> Result<(), PstError> {
+    match current {
+        Some((kind, name)) if given[kind.array_index()].as_ref().is_some_and(|t| t.len() < 81) => Err(PstError::MissingRows(name.clone())),
+        _ => Ok(()),
+    }
+}
+
+
+impl PieceSquareTables {
+
+    // the compiled in tables
+    pub fn builtin() -> PieceSquareTables {
+        PieceSquareTables::parse(DEFAULT_TABLES, None).expect("built-in piece square tables")
+    }
+
+    // Tables from `text`. Tables it leaves out are taken from `base` (all zero without
+    // one), except that promoted minor pieces fall back on the gold table in `text`.
+    pub fn parse(text: &str, base: Option<&PieceSquareTables>) -> Result<PieceSquareTables, PstError> {
+
+        let mut given: [Option<Vec<i32>>; PieceKind::NUM] = Default::default();
+        let mut current: Option<(PieceKind, String)> = None;
+
+        for (number, line) in text.lines().enumerate() {
+            let line_number = number + 1;
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            // a piece letter opens the next table
+            if !line.starts_with(|c: char| c.is_ascii_digit() || c == '-') {
+                check_complete(&current, &given)?;
+                let kind = kind_of(line).ok_or(PstError::UnknownPiece { line: line_number, name: line.to_string() })?;
+                if kind == PieceKind::King {
+                    return Err(PstError::KingTable(line_number));
+                }
+                given[kind.array_index()] = Some(Vec::with_capacity(81));
+                current = Some((kind, line.to_string()));
+                continue;
+            }
+
+            let Some((kind, name)) = &current else { return Err(PstError::ValuesOutsideTable(line_number)) };
+            let table = given[kind.array_index()].as_mut().expect("open table");
+            let row = line.split_whitespace()
+                .map(|text| text.parse::<i32>().map_err(|_| PstError::Value { line: line_number, text: text.to_string() }))
+                .collect::<Result<Vec<i32>, PstError>>()?;
+            if row.len() != 9 {
+                return Err(PstError::RowLength { line: line_number, count: row.len() });
+            }
+            if table.len() == 81 {
+                return Err(PstError::ExtraRows(name.clone()));
+            }
+            table.extend(row);
+        }
+        check_complete(&current, &given)?;
+
+        let mut result = base.cloned().unwrap_or(PieceSquareTables { tables: [[0; 81]; PieceKind::NUM] });
+        for kind in PieceKind::all() {
+            if let Some(table) = &given[kind.array_index()] {
+                result.tables[kind.array_index()].copy_from_slice(table);
+            }
+        }
+        // promoted pawns, lances, knights and silvers move like a gold
+        if let Some(gold) = &given[PieceKind::Gold.array_index()] {
+            for kind in [PieceKind::ProPawn, PieceKind::ProLance, PieceKind::ProKnight, PieceKind::ProSilver] {
+                if given[kind.array_index()].is_none() {
+                    result.tables[kind.array_index()].copy_from_slice(gold);
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    // the built-in tables with whatever the file at `path` changes
+    pub fn load(path: &str) -> Result<PieceSquareTables, PstError> {
+        let text = std::fs::read_to_string(path).map_err(|err| PstError::Io(err.to_string()))?;
+        PieceSquareTables::parse(&text, Some(&PieceSquareTables::builtin()))
+    }
+
+    pub fn value(&self, piece: Piece, square: Square) -> i32 {
+        self.tables[piece.piece_kind().array_index()][table_index(square, piece.color())]
+    }
+
+    // every table averaged with its left-right mirror image, so both wings score the same
+    pub fn symmetric_files(&self) -> PieceSquareTables {
+        let mut result = self.clone();
+        for (table, original) in result.tables.iter_mut().zip(&self.tables) {
+            for (index, value) in table.iter_mut().enumerate() {
+                let (row, column) = (index / 9, index % 9);
+                let mirror = row * 9 + (8 - column);
+                *value = (original[index] + original[mirror]).div_euclid(2);
+            }
+        }
+        result
+    }
+}
+
+
+fn active() -> &'static RwLock<PieceSquareTables> {
+    static ACTIVE: OnceLock<RwLock<PieceSquareTables>> = OnceLock::new();
+    ACTIVE.get_or_init(|| RwLock::new(PieceSquareTables::builtin()))
+}
+
+
+// PST value of `piece` on `square` in the tables the engine is using
+pub fn value(piece: Piece, square: Square) -> i32 {
+    active().read().unwrap().value(piece, square)
+}
+
+
+// Makes `tables` the ones the engine uses. Positions made before keep their old
+// PST sums, so this belongs between searches.
+pub fn set_tables(tables: PieceSquareTables) {
+    *active().write().unwrap() = tables;
+    evalcache::global().clear();
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_tables_are_complete() {
+        let tables = PieceSquareTables::builtin();
+        // 9a for black is 1i for white
+        assert_eq!(tables.value(Piece::B_P, Square::SQ_9A), tables.value(Piece::W_P, Square::SQ_1I));
+        assert_eq!(tables.tables[PieceKind::ProSilver.array_index()], tables.tables[PieceKind::Gold.array_index()]);
+        assert_eq!(tables.tables[PieceKind::King.array_index()], [0; 81]);
+    }
+
+    #[test]
+    fn files_override_only_what_they_give() {
+        let base = PieceSquareTables::builtin();
+        let text = format!("# rook only\nR\n{}", "1 2 3 4 5 6 7 8 9\n".repeat(9));
+        let tables = PieceSquareTables::parse(&text, Some(&base)).unwrap();
+        assert_eq!(tables.value(Piece::B_R, Square::SQ_9C), 1);
+        assert_eq!(tables.value(Piece::B_R, Square::SQ_1C), 9);
+        assert_eq!(tables.value(Piece::W_R, Square::SQ_1C), 1);
+        assert_eq!(tables.tables[PieceKind::Pawn.array_index()], base.tables[PieceKind::Pawn.array_index()]);
+
+        let symmetric = tables.symmetric_files();
+        assert_eq!(symmetric.value(Piece::B_R, Square::SQ_9C), 5);
+        assert_eq!(symmetric.value(Piece::B_R, Square::SQ_1C), 5);
+    }
+
+    #[test]
+    fn reports_bad_files() {
+        assert_eq!(PieceSquareTables::parse("Q\n", None), Err(PstError::UnknownPiece { line: 1, name: "Q".to_string() }));
+        assert_eq!(PieceSquareTables::parse("K\n", None), Err(PstError::KingTable(1)));
+        assert_eq!(PieceSquareTables::parse("P\n1 2 3\n", None), Err(PstError::RowLength { line: 2, count: 3 }));
+        assert_eq!(PieceSquareTables::parse("P\n1 2 3 4 5 6 7 8 x\n", None), Err(PstError::Value { line: 2, text: "x".to_string() }));
+        assert_eq!(PieceSquareTables::parse("P\n1 2 3 4 5 6 7 8 9\nL\n", None), Err(PstError::MissingRows("P".to_string())));
+        assert_eq!(PieceSquareTables::parse("1 2 3 4 5 6 7 8 9\n", None), Err(PstError::ValuesOutsideTable(1)));
+        assert_eq!(PieceSquareTables::parse(&format!("P\n{}", "0 0 0 0 0 0 0 0 0\n".repeat(10)), None), Err(PstError::ExtraRows("P".to_string())));
+    }
+}
@@ -1,20 +1,217 @@
 // Russell Kosovsky
 
 use crate::eval;
+use crate::evalcache::{self, CacheStats, Evaluation};
+use crate::params::TaperedParams;
 use crate::sfen;
 use crate::book;
-use shogi_core::{Move, Color};
+use crate::perft;
+use crate::position::Position;
+use crate::tsume::{Solver, TsumeOptions, TsumeResult};
+use shogi_core::{Move, Color, PositionStatus};
 use shogi_legality_lite::all_legal_moves_partial;
+use std::cell::Cell;
 use std::collections::HashSet;
 use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+
+// depth (in plies) searched when no other limit is given
+pub const DEFAULT_PLIES: u32 = 2;
+// hard cap for iterative deepening when only nodes/time/stop can end the search
+pub const MAX_PLIES: u32 = 64;
+// most nodes a single game tree may hold. Every node keeps its own Position (about half
+// a kilobyte), so without it `go infinite` or a long ponder would deepen until out of memory
+pub const MAX_TREE_NODES: u64 = 1_000_000;
+
+// score of a mated side (and minus the score of the side that mated it). Every ply between
+// the root and the mate takes one point off, so faster mates and slower losses score better.
+pub const MATE_SCORE: f32 = 1_000_000.0;
+// anything at least this big is a mate score rather than an evaluation
+const MATE_BOUND: f32 = MATE_SCORE - MAX_PLIES as f32 - 1.0;
+
+
+/*  SearchLimits - everything that can end a search. 
+ *
+ *  Every field is optional and the first limit that is hit ends the search:
+ *    depth    - maximum depth in plies
+ *    nodes    - maximum number of game tree nodes generated
+ *    movetime - fixed time to think
+ *    infinite - keep deepening until told to stop (USI `go infinite`) or the
+ *               game tree would outgrow MAX_TREE_NODES
+ *    mate     - look only for a mate in at most this many moves of the side to move
+ *               (USI `go mate <ms>` / `go mate infinite` look for one of any length,
+ *               for that long or until found; `mate <moves>` on the command line)
+ *
+ *  A node limit makes the search fully reproducible, which is what the 
+ *  regression checks rely on.    */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SearchLimits {
+    pub depth: Option<u32>,
+    pub nodes: Option<u64>,
+    pub movetime: Option<Duration>,
+    pub infinite: bool,
+    pub mate: Option<u32>,
+}
+
+
+// the longest mate a USI `go mate` looks for, in moves of the attacker
+pub const MAX_MATE_MOVES: u32 = 32;
+
+
+impl SearchLimits {
+
+    pub fn depth(depth: u32) -> Self {
+        SearchLimits { depth: Some(depth), ..Default::default() }
+    }
+
+    // Parses the arguments of a USI `go` command, e.g. ["depth", "3", "nodes", "20000"]
+    // or ["mate", "30000"]. Unknown tokens such as `ponder` or the clock fields are skipped.
+    pub fn parse(args: &[&str]) -> Result<Self, String> {
+        SearchLimits::parse_with(args, false)
+    }
+
+    // The same for the command line, where `mate <moves>` is a mate in at most that many moves.
+    pub fn parse_cli(args: &[&str]) -> Result<Self, String> {
+        SearchLimits::parse_with(args, true)
+    }
+
+    fn parse_with(args: &[&str], mate_in_moves: bool) -> Result<Self, String> {
+
+        let mut limits = SearchLimits::default();
+        let mut iter = args.iter().peekable();
+
+        while let Some(&token) = iter.next() {
+            if token == "mate" && !mate_in_moves && iter.peek() == Some(&&"infinite") {
+                iter.next();
+                limits.mate = Some(MAX_MATE_MOVES);
+                continue;
+            }
+            let mut value = |name: &str| -> Result<u64, String> {
+                let text = iter.next().ok_or(format!("missing value for `{}`", name))?;
+                text.parse::<u64>().map_err(|_| format!("invalid value for `{}`: {}", name, text))
+            };
+            match token {
+                "depth" => limits.depth = Some(value("depth")? as u32),
+                "nodes" => limits.nodes = Some(value("nodes")?),
+                "movetime" => limits.movetime = Some(Duration::from_millis(value("movetime")?)),
+                "mate" if mate_in_moves => limits.mate = Some(value("mate")? as u32),
+                "mate" => {
+                    limits.movetime = Some(Duration::from_millis(value("mate")?));
+                    limits.mate = Some(MAX_MATE_MOVES);
+                },
+                "infinite" => limits.infinite = true,
+                _ => {},
+            }
+        }
+
+        Ok(limits)
+    }
+}
+
+
+// Keeps track of the limits while a search is running.
+pub struct SearchControl<'a> {
+    limits: &'a SearchLimits,
+    stop: &'a AtomicBool,  // raised from outside (USI `stop`, pondering miss...)
+    pondering: Option<&'a AtomicBool>,  // while raised the movetime clock doesn't run
+    start: Instant,
+    clock: Cell<Option<Instant>>,  // when the movetime started counting: at once, or at ponderhit
+    nodes: Cell<u64>,
+    tree_start: Cell<u64>,  // node count when the current game tree was started
+    max_tree: u64,          // MAX_TREE_NODES unless told otherwise
+    aborted: Cell<bool>,   // set once the search had to give up on part of the tree
+}
+
+
+impl<'a> SearchControl<'a> {
+
+    pub fn new(limits: &'a SearchLimits, stop: &'a AtomicBool) -> Self {
+        let start = Instant::now();
+        SearchControl { limits, stop, pondering: None, start, clock: Cell::new(Some(start)), nodes: Cell::new(0), tree_start: Cell::new(0), max_tree: MAX_TREE_NODES, aborted: Cell::new(false) }
+    }
+
+    // a ponder search: its movetime only starts once `pondering` is lowered (ponderhit)
+    pub fn pondering(self, pondering: &'a AtomicBool) -> Self {
+        SearchControl { pondering: Some(pondering), clock: Cell::new(None), ..self }
+    }
+
+    // the time the movetime counts from, None while still pondering
+    fn clock(&self) -> Option<Instant> {
+        if self.clock.get().is_none() && !self.pondering.is_some_and(|p| p.load(Ordering::Relaxed)) {
+            self.clock.set(Some(Instant::now()));
+        }
+        self.clock.get()
+    }
+
+    // counts one more node
+    pub fn node(&self) {
+        self.nodes.set(self.nodes.get() + 1);
+    }
+
+    pub fn nodes(&self) -> u64 {
+        self.nodes.get()
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    // true as soon as any of the limits (or the stop flag) says the search is over
+    pub fn should_stop(&self) -> bool {
+        let over = self.stop.load(Ordering::Relaxed)
+            || self.limits.nodes.is_some_and(|max| self.nodes.get() >= max)
+            || self.limits.movetime.is_some_and(|max| self.clock().is_some_and(|clock| clock.elapsed() >= max));
+        if over {
+            self.aborted.set(true);
+        }
+        over
+    }
+
+    // a new game tree is being built: it gets max_tree nodes of its own
+    pub fn new_tree(&self) {
+        self.tree_start.set(self.nodes.get());
+    }
+
+    // should_stop, or the current game tree has grown as big as it may
+    pub fn tree_full(&self) -> bool {
+        let full = self.nodes.get() - self.tree_start.get() >= self.max_tree;
+        if full {
+            self.aborted.set(true);
+        }
+        full || self.should_stop()
+    }
+
+    pub fn aborted(&self) -> bool {
+        self.aborted.get()
+    }
+}
+
+
+// What a search hands back to its caller.
+#[derive(Debug, Default)]
+pub struct SearchResult {
+    pub best_move: Option<Move>,
+    pub ponder_move: Option<Move>,    // expected reply, see predicted_reply
+    pub score: (f32, f32),            // (white, black) evaluation of the principal variation
+    pub best_sfen: String,            // principal variation leaf (eval::EvalTrace explains its score)
+    pub depth: u32,                   // plies of the last completed iteration
+    pub nodes: u64,
+    pub time: Duration,
+    pub mate: Option<Vec<Move>>,      // mating line when searching with a `mate` limit
+    pub mate_plies: Option<i32>,      // forced mate found: plies to mate, negative when the side to move gets mated
+    pub mate_timeout: bool,           // the `mate` search ran out of time or nodes without an answer
+    pub eval_cache: CacheStats,       // eval cache hits/probes during this search
+}
 
 
 #[derive(Debug)]
 pub struct GameTree {
     
-    pub sfen: String,
-/*    sfen: String - This field contains a string representation of 
- *    a game state in the Shogi Forsyth-Edwards Notation (SFEN). 
+    pub pos: Position,
+/*    pos: Position - This field contains the game state itself (see position.rs), 
+ *    Position::to_sfen gives its SFEN when it has to be shown. 
  *
  *    The root of the tree represents the initial game state
  *
@@ -40,9 +237,9 @@ pub struct GameTree {
 
 
 impl GameTree { // GameTree struct implementation
-    pub fn new(sfen: String, game_move: Option<Move>) -> Self {
+    pub fn new(pos: Position, game_move: Option<Move>) -> Self {
         GameTree {
-            sfen,
+            pos,
             game_move,
             children: vec![],
         }
@@ -97,25 +294,25 @@ impl<'a> IntoIterator for &'a GameTree {
 // all possible game states up to a specified depth.
 pub fn treesearch(sfen: &str, depth: u32, current_depth: u32, game_move: Option<Move>) -> GameTree {
 
-    // Parse the SFEN string and generate a PartialPosition from it
-    let positions = sfen::sfen_parse(sfen);
-    let mut pos = sfen::generate_pos(positions); 
-    pos.side_to_move_set(sfen::get_color(sfen));
+    // Parse the SFEN string once, the tree itself is built by making and unmaking moves
+    let mut pos = Position::from_sfen(sfen);
+    expand(&mut pos, depth, current_depth, game_move)
+}
+
+
+fn expand(pos: &mut Position, depth: u32, current_depth: u32, game_move: Option<Move>) -> GameTree {
 
-    // Create a new GameTree node with the current SFEN and move
-    let mut game_tree = GameTree::new(sfen.to_string(), game_move);
+    // Create a new GameTree node with the current position and move
+    let mut game_tree = GameTree::new(pos.clone(), game_move);
 
     // If we haven't reached the maximum depth, generate all legal moves from the current position
     if current_depth < depth {
-        let next_moves = all_legal_moves_partial(&pos);
 
         // For each legal move, create a new game state and add it as a child to the current node
-        for move_item in next_moves {
-            let mut temp_pos = pos.clone();
-            temp_pos.make_move(move_item.clone());
-            let sfen = temp_pos.to_sfen_owned();
-
-            let child_tree = treesearch(&sfen, depth, current_depth + 1, Some(move_item));
+        for move_item in pos.legal_moves() {
+            let undo = pos.make_move(move_item);
+            let child_tree = expand(pos, depth, current_depth + 1, Some(move_item));
+            pos.unmake_move(undo);
             game_tree.children.push(child_tree);
         }
     }
@@ -125,18 +322,275 @@ pub fn treesearch(sfen: &str, depth: u32, current_depth: u32, game_move: Option<
 }
 
 
-pub fn minimax(tree: &GameTree, depth: u32, maximizing_player: Color) -> ((f32, f32), Option<Move>, Vec<(u32, u32)>, &str) {
+// Same as treesearch, but counts nodes and stops expanding them as soon as the 
+// search limits say so or the tree reaches MAX_TREE_NODES (whatever the limits, 
+// `go infinite` included): below the root no more children are added after that,
+// so the returned tree is always a valid (possibly shallower) tree. The root 
+// is always expanded so there is a move to play even after an immediate stop,
+// which is all a search can go past a node limit by.
+pub fn treesearch_limited(pos: &mut Position, depth: u32, current_depth: u32, game_move: Option<Move>, ctl: &SearchControl) -> GameTree {
+
+    let mut game_tree = GameTree::new(pos.clone(), game_move);
+    let is_root = game_move.is_none();
+    if is_root {
+        ctl.new_tree();
+    }
+    ctl.node();
+
+    if current_depth < depth && (is_root || !ctl.tree_full()) {
+        for move_item in pos.legal_moves() {
+            if !is_root && ctl.tree_full() {
+                break;
+            }
+            let undo = pos.make_move(move_item);
+            let child_tree = treesearch_limited(pos, depth, current_depth + 1, Some(move_item), ctl);
+            pos.unmake_move(undo);
+            game_tree.children.push(child_tree);
+        }
+    }
+
+    game_tree
+}
+
+
+/*  think - iterative deepening driver used by every front end (play, ponder, usi, command line).
+ *
+ *  Builds the game tree one ply deeper on every iteration and runs minimax on it until 
+ *  one of the limits is hit. An iteration cut short by the limits is only used when no 
+ *  earlier iteration finished, so the reported move always comes from a complete search 
+ *  whenever possible.   */
+pub fn think(sfen: &str, limits: &SearchLimits, stop: &AtomicBool) -> SearchResult {
+    think_with(sfen, limits, stop, None)
+}
+
+
+// think with other weights than the engine's (`None`: the engine's own), so several
+// sets of weights can be searched with side by side without touching params::set
+pub fn think_with(sfen: &str, limits: &SearchLimits, stop: &AtomicBool, params: Option<&TaperedParams>) -> SearchResult {
+    run(sfen, &SearchControl::new(limits, stop), params)
+}
+
+
+// think for `go ponder`: the movetime counts from the moment `pondering` is lowered (ponderhit)
+pub fn think_pondering(sfen: &str, limits: &SearchLimits, stop: &AtomicBool, pondering: &AtomicBool) -> SearchResult {
+    run(sfen, &SearchControl::new(limits, stop).pondering(pondering), None)
+}
+
+
+fn run(sfen: &str, ctl: &SearchControl, params: Option<&TaperedParams>) -> SearchResult {
+
+    let limits = ctl.limits;
+    let cache_before = evalcache::global().stats();
+
+    if let Some(moves) = limits.mate {
+        let pos = sfen::sfen_to_pos(sfen);
+        let options = TsumeOptions { max_ply: (2 * moves).saturating_sub(1), defender_has_remaining: false };
+        let result = Solver::new(ctl).solve(&pos, options);
+        let mate_timeout = result == TsumeResult::Unknown;
+        let mate = match result {
+            TsumeResult::Mate(line) => Some(line),
+            _ => None,
+        };
+        return SearchResult {
+            best_move: mate.as_ref().and_then(|line| line.first().copied()),
+            mate,
+            mate_timeout,
+            nodes: ctl.nodes(),
+            time: ctl.elapsed(),
+            ..Default::default()
+        };
+    }
+
+    let mut pos = Position::from_sfen(sfen);
+    let color = pos.side_to_move();
+    let open_ended = limits.infinite || limits.nodes.is_some() || limits.movetime.is_some();
+    let max_plies = limits.depth.unwrap_or(if open_ended { MAX_PLIES } else { DEFAULT_PLIES }).max(1);
+
+    let mut result = SearchResult::default();
+
+    for plies in 1..=max_plies {
+
+        // minimax/treesearch count the root as depth 1
+        let root = treesearch_limited(&mut pos, plies + 1, 1, None, ctl);
+        let complete = !ctl.aborted();
+
+        if !complete && result.best_move.is_some() {
+            break;
+        }
+
+        let (score, best_move, _, best_pos) = minimax(&root, plies + 1, color, params);
+        
+        result = SearchResult {
+            best_move,
+            ponder_move: best_move.and_then(|mv| predicted_reply(&root, mv, plies + 1, color, params)),
+            score,
+            best_sfen: best_pos.to_sfen(),
+            depth: plies,
+            nodes: ctl.nodes(),
+            time: ctl.elapsed(),
+            mate: None,
+            mate_plies: mate_distance(score, color),
+            mate_timeout: false,
+            eval_cache: evalcache::global().stats().since(cache_before),
+        };
+
+        // a mate found by a complete (full width) iteration can't be improved by searching deeper
+        if !complete || best_move.is_none() || result.mate_plies.is_some() {
+            break;
+        }
+    }
+
+    result.nodes = ctl.nodes();
+    result.time = ctl.elapsed();
+    result.eval_cache = evalcache::global().stats().since(cache_before);
+    result
+}
+
+
+// the side that has been checkmated in this position, if any
+fn mated_side(pos: &Position) -> Option<Color> {
+    match pos.status() {
+        PositionStatus::BlackWins => Some(Color::White),
+        PositionStatus::WhiteWins => Some(Color::Black),
+        _ => None,
+    }
+}
+
+
+// (white, black) score of a position where `loser` is checkmated
+fn mate_score(loser: Color) -> (f32, f32) {
+    mate_score_in(loser, 0)
+}
+
+
+// (white, black) score of a position where `loser` gets checkmated `plies` plies later
+fn mate_score_in(loser: Color, plies: u32) -> (f32, f32) {
+    let mate = MATE_SCORE - plies as f32;
+    if loser == Color::White { (-mate, mate) } else { (mate, -mate) }
+}
+
+
+fn is_mate_score(score: f32) -> bool {
+    score.abs() >= MATE_BOUND
+}
+
+
+// the score as seen by the parent node: a mate is one ply further away from there
+fn one_ply_further(score: (f32, f32)) -> (f32, f32) {
+    let further = |x: f32| if is_mate_score(x) { x - x.signum() } else { x };
+    (further(score.0), further(score.1))
+}
+
+
+// the component of a (white, black) score belonging to `color`
+fn score_of(score: (f32, f32), color: Color) -> f32 {
+    if color == Color::White { score.0 } else { score.1 }
+}
+
+
+// Plies to mate for the side to move (`color`) if `score` is a mate score: positive 
+// when it mates, negative when it gets mated. None for an ordinary evaluation.
+pub fn mate_distance(score: (f32, f32), color: Color) -> Option<i32> {
+    let own = score_of(score, color);
+    if !is_mate_score(own) {
+        return None;
+    }
+    let plies = (MATE_SCORE - own.abs()) as i32;
+    Some(if own > 0.0 { plies } else { -plies })
+}
+
+
+// a leaf's evaluation: the engine's through the shared cache, other weights directly
+// (the cache only knows positions, not the weights they were evaluated with)
+fn evaluate_leaf(pos: &Position, params: Option<&TaperedParams>) -> Evaluation {
+    match params {
+        Some(params) => eval::evaluate_tapered(pos, params),
+        None => evalcache::evaluate(pos),
+    }
+}
+
+
+// best (white, black) score, best move, features of the leaf reached and that leaf
+pub type MinimaxResult<'a> = ((f32, f32), Option<Move>, Vec<(u32, u32)>, &'a Position);
+
+
+pub fn minimax<'a>(tree: &'a GameTree, depth: u32, maximizing_player: Color, params: Option<&TaperedParams>) -> MinimaxResult<'a> {
+    minimax_within(tree, depth, maximizing_player, params, MateWindow::root(true), &mut 0)
+}
+
+
+// The mates each side has already made sure of higher up the tree, as the ply (counted
+// from the root) of the mated position, along with the ply of the node being searched. 
+// A side can't mate sooner than on its next move, nor be mated sooner than right now, 
+// so a node that can't beat either mate in hand is cut without being searched.
+#[derive(Clone, Copy)]
+struct MateWindow {
+    ply: u32,
+    white: Option<u32>,
+    black: Option<u32>,
+    pruning: bool,
+}
+
+impl MateWindow {
+    fn root(pruning: bool) -> MateWindow {
+        MateWindow { ply: 0, white: None, black: None, pruning }
+    }
+
+    fn secured(&self, color: Color) -> Option<u32> {
+        if color == Color::White { self.white } else { self.black }
+    }
+
+    fn secure(&mut self, color: Color, ply: u32) {
+        let mate = if color == Color::White { &mut self.white } else { &mut self.black };
+        *mate = Some(mate.map_or(ply, |known| known.min(ply)));
+    }
+
+    fn child(&self) -> MateWindow {
+        MateWindow { ply: self.ply + 1, ..*self }
+    }
+
+    // the bound to return when the node can't improve on a mate in hand, None when it has to be searched
+    fn cutoff(&self, to_move: Color) -> Option<(f32, f32)> {
+        if !self.pruning {
+            return None;
+        }
+        // mating on the next move at the earliest is no quicker than the mate already found
+        if let Some(ply) = self.secured(to_move).filter(|&ply| ply <= self.ply + 1) {
+            return Some(mate_score_in(to_move.flip(), ply.saturating_sub(self.ply).max(1)));
+        }
+        // getting mated right now at the earliest is no quicker for the opponent either
+        if self.secured(to_move.flip()).is_some_and(|ply| ply <= self.ply) {
+            return Some(mate_score(to_move));
+        }
+        None
+    }
+}
+
+
+// minimax with mate-distance pruning against `window`, counting the nodes it visits
+fn minimax_within<'a>(tree: &'a GameTree, depth: u32, maximizing_player: Color, params: Option<&TaperedParams>, window: MateWindow, visited: &mut u64) -> MinimaxResult<'a> {
     
-    let curr_color = sfen::get_color(&tree.sfen);
-    let mut best_sfen: &str = &"";
+    let curr_color = tree.pos.side_to_move();
+    let mut best_pos: &Position = &tree.pos;
     let mut best_move = None;
     let mut best_features: Vec<(u32, u32)> = Vec::new();
+    let mut window = window;
+    *visited += 1;
+
+    if let Some(bound) = window.cutoff(curr_color) {
+        return (bound, best_move, best_features, best_pos);
+    }
 
     // BASE CASE if reaches max depth or if current node in game tree has no children (it's a terminal node) 
     // then evaluate current game state and return white/black scores
+    // a checkmated position is scored as a mate instead of being evaluated (the features are still 
+    // filled in so the leaf can be displayed like any other)
     if depth == 1 || tree.children.is_empty() {
-        let eval = eval::evaluate3(&tree.sfen);
-        return (eval.0, best_move, eval.1, eval.2);
+        let eval = evaluate_leaf(&tree.pos, params);
+        if let Some(loser) = mated_side(&tree.pos) {
+            return (mate_score(loser), best_move, eval.1, &tree.pos);
+        }
+        return (eval.0, best_move, eval.1, &tree.pos);
     }
 
     // initialize best_eval, to store best evaluation value found so far. 
@@ -147,33 +601,60 @@ pub fn minimax(tree: &GameTree, depth: u32, maximizing_player: Color) -> ((f32,
     // explore each child of the current node (each possible next state of the game)
     for child in &tree.children {
 
-        // for each child, recursively call minimax3 to evaluate that child node. 
+        // for each child, recursively call minimax_within to evaluate that child node. 
         // returns the pair of evaluation values, best move, and best features vector for the child node 
         // but here we are only interested in the evaluation and features, so we ignore the best move with _
-        let (eval, _, features, sfen) = minimax(child, depth - 1, maximizing_player);
+        let (eval, _, features, leaf) = minimax_within(child, depth - 1, maximizing_player, params, window.child(), visited);
+        let eval = one_ply_further(eval);
+
+        // once a mate is involved every side simply goes for its own best score: 
+        // the fastest mate when winning, the slowest one when losing
+        let mate_involved = best_move.is_some() && (is_mate_score(score_of(eval, curr_color)) || is_mate_score(score_of(best_eval, curr_color)));
+        let better_mate = mate_involved && score_of(eval, curr_color) > score_of(best_eval, curr_color);
 
         // apply the maximization/minimization logic depending on whose turn it is. 
         // if current player is maximizing and current child's eval score is higher than the current best
         // or if current player is minimizing and current child's eval score is lower than the current best 
         // then update best evaluation, best move, and best features with the child's eval score, move, and features.
-        if (maximizing_player == Color::Black && curr_color != maximizing_player && eval.0 < best_eval.0) ||
+        if better_mate || (!mate_involved && (
+           (maximizing_player == Color::Black && curr_color != maximizing_player && eval.0 < best_eval.0) ||
            (maximizing_player == Color::White && curr_color == maximizing_player && eval.0 > best_eval.0) ||
            (maximizing_player == Color::Black && curr_color == maximizing_player && eval.1 > best_eval.1) ||
-           (maximizing_player == Color::White && curr_color != maximizing_player && eval.1 < best_eval.1) {
-            best_sfen = sfen;
+           (maximizing_player == Color::White && curr_color != maximizing_player && eval.1 < best_eval.1))) {
+            best_pos = leaf;
             best_eval = eval;
             best_move = child.game_move.clone();
             best_features.clear();
             best_features.extend(features);
         }
+
+        // a mate found here is in hand for the rest of the children
+        if let Some(plies) = mate_distance(best_eval, curr_color).filter(|&plies| plies > 0) {
+            window.secure(curr_color, window.ply + plies as u32);
+        }
     }
 
     // after considering all the child nodes, return best evaluation, move, and features that were found
-    return (best_eval, best_move, best_features, best_sfen);
+    (best_eval, best_move, best_features, best_pos)
 
 }
 
 
+// Returns the reply the opponent is expected to play after `best_move`, 
+// i.e. the second move of the principal variation. Used for pondering.
+pub fn predicted_reply(tree: &GameTree, best_move: Move, depth: u32, maximizing_player: Color, params: Option<&TaperedParams>) -> Option<Move> {
+    
+    if depth <= 1 {
+        return None;
+    }
+
+    let child = tree.children.iter().find(|child| child.game_move == Some(best_move))?;
+    let (_, reply, _, _) = minimax(child, depth - 1, maximizing_player, params);
+    
+    reply
+}
+
+
 pub fn get_book_move(tree: &GameTree, prev_moves: Vec<Move>) -> (Move, Vec<(u32, u32)>, &str) {
     
     let curr_color = Color::Black;
@@ -288,26 +769,117 @@ pub fn has_duplicates<T: std::cmp::Eq + std::hash::Hash>(vec: &[T]) -> bool {
 
 
 pub fn perft(sfen: &str, depth: u32) -> u64 {
-    let positions = sfen::sfen_parse(sfen); // Parse the SFEN string into a list of positions
-    let mut pos = sfen::generate_pos(positions); // Generate a "partial position" from the positions
-    pos.side_to_move_set(sfen::get_color(sfen));
+    let mut pos = Position::from_sfen(sfen); // Parse the SFEN string once
+    perft::perft(&mut pos, depth) // bulk-counting perft, see perft.rs
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shogi_core::ToUsi;
+
+    const START: &str = "lnsgkgsnl/1r5b1/ppppppppp/9/9/9/PPPPPPPPP/1B5R1/LNSGKGSNL b - 1";
+
+    #[test]
+    fn parses_usi_and_command_line_limits() {
+        let limits = SearchLimits::parse(&["btime", "0", "depth", "3", "nodes", "20000"]).unwrap();
+        assert_eq!((limits.depth, limits.nodes, limits.mate), (Some(3), Some(20000), None));
 
-    if depth == 0 {
-        return 1; // End of search, return 1 as a leaf node
+        // USI: `mate` is a time or infinite, never a number of moves
+        let timed = SearchLimits::parse(&["mate", "30000"]).unwrap();
+        assert_eq!((timed.mate, timed.movetime), (Some(MAX_MATE_MOVES), Some(Duration::from_millis(30000))));
+        let unlimited = SearchLimits::parse(&["mate", "infinite"]).unwrap();
+        assert_eq!((unlimited.mate, unlimited.movetime, unlimited.infinite), (Some(MAX_MATE_MOVES), None, false));
+
+        // the command line: mate in at most 3 moves
+        assert_eq!(SearchLimits::parse_cli(&["mate", "3"]).unwrap().mate, Some(3));
+        assert!(SearchLimits::parse_cli(&["mate", "infinite"]).is_err());
     }
 
-    let next_moves = all_legal_moves_partial(&pos); // Get all possible moves for the current position
-    let mut node_count = 0; // Counter for the number of nodes
+    // a node limit gives the same search every time: the regression checks rely on it
+    #[test]
+    fn node_limited_search_is_reproducible() {
+        let stop = AtomicBool::new(false);
+        let limits = SearchLimits { nodes: Some(3000), ..Default::default() };
+        let first = think(START, &limits, &stop);
+        let second = think(START, &limits, &stop);
+        assert!(first.best_move.is_some());
+        assert_eq!((first.best_move, first.score, first.depth, first.nodes), (second.best_move, second.score, second.depth, second.nodes));
+        assert_eq!(first.best_sfen, second.best_sfen);
+
+        // only the root's moves can go past the limit
+        let root_moves = Position::from_sfen(START).legal_moves().len() as u64;
+        assert!(first.nodes <= 3000 + root_moves, "{} nodes", first.nodes);
+    }
 
-    for move_item in next_moves {
-        let mut temp_pos = pos.clone(); // Create a temporary copy of the position
-        temp_pos.make_move(move_item); // Make the move on the temporary position
-        let sfen = temp_pos.to_sfen_owned(); // Convert the updated position to an SFEN string
+    // black mates at once with G*5b: once that is found the other root moves are cut 
+    // instead of searched, and the mate reported stays the same
+    #[test]
+    fn mate_distance_pruning_searches_fewer_nodes() {
+        let root = treesearch("4k4/9/4G4/9/9/9/9/9/4K4 b G 1", 3, 1, None);
+        let search = |pruning: bool| {
+            let mut visited = 0;
+            let (score, best, _, _) = minimax_within(&root, 3, Color::Black, None, MateWindow::root(pruning), &mut visited);
+            (mate_distance(score, Color::Black), best.map(|mv| mv.to_usi_owned()), visited)
+        };
+        let (pruned_mate, pruned_best, pruned_nodes) = search(true);
+        let (full_mate, full_best, full_nodes) = search(false);
+        assert_eq!(pruned_mate, Some(1));
+        assert_eq!((pruned_mate, pruned_best), (full_mate, full_best));
+        assert!(pruned_nodes < full_nodes, "{} nodes with pruning, {} without", pruned_nodes, full_nodes);
+    }
 
-        let child_count = perft(&sfen, depth - 1); // Recursively calculate child nodes count
-        node_count += child_count; // Add child count to the node count
+    // nothing but `stop` ends `go infinite`, yet the game tree can't grow past its cap:
+    // the search gives up deepening instead of running out of memory
+    #[test]
+    fn infinite_search_stays_within_the_tree_cap() {
+        let stop = AtomicBool::new(false);
+        let limits = SearchLimits { infinite: true, ..Default::default() };
+        let ctl = SearchControl { max_tree: 2000, ..SearchControl::new(&limits, &stop) };
+        let result = run(START, &ctl, None);
+        assert!(result.best_move.is_some());
+        assert!(ctl.aborted());
+
+        // every iteration builds a new tree, the last one was cut at the cap
+        let root_moves = Position::from_sfen(START).legal_moves().len() as u64;
+        assert!(result.nodes <= (result.depth as u64 + 1) * (2000 + root_moves), "{} nodes", result.nodes);
     }
 
-    node_count // Return the total node count
-}
+    // a node of a hand-made tree, reached by `mv` (an opening move of either side, or none)
+    fn node(sfen: &str, mv: &str, children: Vec<GameTree>) -> GameTree {
+        let openings = [Position::from_sfen(START).legal_moves(), Position::from_sfen(WHITE).legal_moves()].concat();
+        let game_move = openings.into_iter().find(|m| m.to_usi_owned() == mv);
+        GameTree { pos: Position::from_sfen(sfen), game_move, children }
+    }
 
+    // white to move and checkmated / white to move in the starting position
+    const MATED: &str = "4k4/4G4/4G4/9/9/9/9/9/4K4 w - 1";
+    const WHITE: &str = "lnsgkgsnl/1r5b1/ppppppppp/9/9/9/PPPPPPPPP/1B5R1/LNSGKGSNL w - 1";
+
+    // black can mate at once (7g7f) or in three plies (2g2f): the quicker mate is taken
+    // even though the slower one comes first
+    #[test]
+    fn a_faster_mate_beats_a_slower_one() {
+        let slow = node(WHITE, "2g2f", vec![node(START, "", vec![node(MATED, "", vec![])])]);
+        let fast = node(MATED, "7g7f", vec![]);
+        let root = node(START, "", vec![slow, fast]);
+
+        let (score, best, _, _) = minimax(&root, 4, Color::Black, None);
+        assert_eq!(best.map(|mv| mv.to_usi_owned()).as_deref(), Some("7g7f"));
+        assert_eq!(mate_distance(score, Color::Black), Some(1));
+    }
+
+    // white gets mated after either move: it picks the one that holds out longer
+    #[test]
+    fn a_slower_loss_is_preferred() {
+        let fast = node(START, "3c3d", vec![node(MATED, "", vec![])]);
+        let slow = node(START, "8c8d", vec![node(WHITE, "", vec![node(START, "", vec![node(MATED, "", vec![])])])]);
+        let root = node(WHITE, "", vec![fast, slow]);
+
+        let (score, best, _, _) = minimax(&root, 5, Color::Black, None);
+        assert_eq!(best.map(|mv| mv.to_usi_owned()).as_deref(), Some("8c8d"));
+        assert_eq!(mate_distance(score, Color::White), Some(-4));
+    }
+}
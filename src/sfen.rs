@@ -2,37 +2,11 @@
  * Converts an sfen string into a board position that can be used for the search function
  */
 
+use crate::position::Position;
 use shogi_core::PartialPosition;
 use shogi_core::{Square, Piece, Color, PieceKind};
 use colored::Colorize;
-
-pub fn flip(sfen: &str) -> String {
-    
-    let mut split: Vec<&str> = sfen.split_whitespace().collect();
-    let board = split[0];
-    let flip: String = board.chars().rev().collect();
-    split[0] = &flip;
-    let joined = split.join(" ");
-    
-    joined
-}
-
-
-pub fn flip_case(sfen: &str) -> String {
-    let flipped_chars: String = sfen.chars()
-        .map(|c| {
-            if c.is_ascii_uppercase() {
-                c.to_ascii_lowercase()
-            } else if c.is_ascii_lowercase() {
-                c.to_ascii_uppercase()
-            } else {
-                c
-            }
-        })
-        .collect();
-
-    flipped_chars
-}
+use std::fmt;
 
 
 pub fn convert_promoted_pieces(sfen: &str) -> String {
@@ -322,33 +296,18 @@ pub fn generate_pos(board: Vec<(String, String)>) -> PartialPosition {
 }
 
 
+// side to move of an sfen the engine made itself (panics on anything else)
 pub fn get_color(sfen: &str) -> Color {
-
-    let split: Vec<&str> = sfen.split_whitespace().collect();
-    let curr_side = split[1];
-    let mut side: Color = Color::White;
-    if curr_side == "b" {
-        side = Color::Black;
-    } else {
-        side = Color::White;
+    let side = sfen.split_whitespace().nth(1).ok_or(SfenError::MissingField("side to move")).and_then(parse_side);
+    match side {
+        Ok(color) => color,
+        Err(err) => panic!("invalid sfen \"{}\": {}", sfen, err),
     }
-    
-    side
 }
 
 
 pub fn get_enemy_color(sfen: &str) -> Color {
-
-    let split: Vec<&str> = sfen.split_whitespace().collect();
-    let curr_side = split[1];
-    let mut side: Color = Color::White;
-    if curr_side == "w" {
-        side = Color::Black;
-    } else {
-        side = Color::White;
-    }
-    
-    side
+    get_color(sfen).flip()
 }
 
 pub fn set_sfen_turn(sfen: &str, turn: &str) -> String {
@@ -364,3 +323,247 @@ pub fn set_sfen_turn(sfen: &str, turn: &str) -> String {
 
 
 
+
+// Builds a complete PartialPosition (board, side to move, pieces in hand and ply)
+// out of an sfen string the engine made itself. Anything typed in by a user or a
+// GUI should go through `parse`, which reports what is wrong instead of panicking.
+pub fn sfen_to_pos(sfen: &str) -> PartialPosition {
+    match parse(sfen) {
+        Ok(pos) => pos.to_partial(),
+        Err(err) => panic!("invalid sfen \"{}\": {}", sfen, err),
+    }
+}
+
+
+// ------------------------------------------ parser ------------------------------------------
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SfenError {
+    MissingField(&'static str),                          // board, side to move or hand not there
+    ExtraField(String),                                  // something after the move number
+    RankCount(usize),                                    // the board doesn't have 9 ranks
+    RankLength { rank: char, squares: usize },           // a rank doesn't add up to 9 squares
+    PieceLetter { rank: char, letter: char },            // not one of PLNSGBRK / plnsgbrk
+    Promotion { rank: char, letter: char },              // '+' in front of a piece that doesn't promote
+    DanglingPromotion(char),                             // '+' at the end of a rank
+    SideToMove(String),                                  // not "b" or "w"
+    HandLetter(char),                                    // not a piece that can be held in hand
+    HandCount { piece: char, count: u32 },               // zero, or more than the set has
+    DanglingHandCount(String),                           // digits with no piece after them
+    MoveNumber(String),                                  // not a number from 1 to 65535
+}
+
+
+impl fmt::Display for SfenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SfenError::MissingField(field) => write!(f, "missing {}", field),
+            SfenError::ExtraField(field) => write!(f, "unexpected \"{}\" after the move number", field),
+            SfenError::RankCount(count) => write!(f, "board has {} ranks, expected 9", count),
+            SfenError::RankLength { rank, squares } => write!(f, "rank {} has {} squares, expected 9", rank, squares),
+            SfenError::PieceLetter { rank, letter } => write!(f, "rank {}: '{}' is not a piece", rank, letter),
+            SfenError::Promotion { rank, letter } => write!(f, "rank {}: '{}' can't be promoted", rank, letter),
+            SfenError::DanglingPromotion(rank) => write!(f, "rank {}: '+' without a piece", rank),
+            SfenError::SideToMove(side) => write!(f, "side to move is \"{}\", expected b or w", side),
+            SfenError::HandLetter(letter) => write!(f, "'{}' can't be held in hand", letter),
+            SfenError::HandCount { piece, count } => write!(f, "{} of '{}' in hand is impossible", count, piece),
+            SfenError::DanglingHandCount(count) => write!(f, "hand count {} without a piece", count),
+            SfenError::MoveNumber(number) => write!(f, "move number \"{}\" is not a number from 1 to 65535", number),
+        }
+    }
+}
+
+
+impl std::error::Error for SfenError {}
+
+
+// the letters SFEN uses, upper case for black
+fn piece_kind_of(letter: char) -> Option<PieceKind> {
+    match letter.to_ascii_uppercase() {
+        'P' => Some(PieceKind::Pawn),
+        'L' => Some(PieceKind::Lance),
+        'N' => Some(PieceKind::Knight),
+        'S' => Some(PieceKind::Silver),
+        'G' => Some(PieceKind::Gold),
+        'B' => Some(PieceKind::Bishop),
+        'R' => Some(PieceKind::Rook),
+        'K' => Some(PieceKind::King),
+        _ => None,
+    }
+}
+
+
+// most of each kind anyone can hold (the whole set)
+fn hand_limit(kind: PieceKind) -> u32 {
+    match kind {
+        PieceKind::Pawn => 18,
+        PieceKind::Bishop | PieceKind::Rook => 2,
+        _ => 4,
+    }
+}
+
+
+fn parse_board(board: &str, pos: &mut Position) -> Result<(), SfenError> {
+
+    let ranks: Vec<&str> = board.split('/').collect();
+    if ranks.len() != 9 {
+        return Err(SfenError::RankCount(ranks.len()));
+    }
+
+    for (rank_index, rank_text) in ranks.iter().enumerate() {
+        let rank = (b'a' + rank_index as u8) as char;
+        let mut file: usize = 9;   // next file to fill, counting down
+        let mut squares = 0;
+        let mut promoted = false;
+
+        for c in rank_text.chars() {
+            if c == '+' && !promoted {
+                promoted = true;
+                continue;
+            }
+            if let Some(empty) = c.to_digit(10).filter(|&d| d > 0 && !promoted) {
+                squares += empty as usize;
+                file = file.saturating_sub(empty as usize);
+                continue;
+            }
+
+            let kind = piece_kind_of(c).ok_or(SfenError::PieceLetter { rank, letter: c })?;
+            let kind = if promoted { kind.promote().ok_or(SfenError::Promotion { rank, letter: c })? } else { kind };
+            promoted = false;
+            squares += 1;
+            if squares > 9 {
+                break;
+            }
+
+            let color = if c.is_ascii_uppercase() { Color::Black } else { Color::White };
+            let square = Square::new(file as u8, rank_index as u8 + 1).expect("file and rank are in 1..=9");
+            pos.piece_set(square, Some(Piece::new(kind, color)));
+            file -= 1;
+        }
+
+        if promoted {
+            return Err(SfenError::DanglingPromotion(rank));
+        }
+        if squares != 9 {
+            return Err(SfenError::RankLength { rank, squares });
+        }
+    }
+
+    Ok(())
+}
+
+
+fn parse_side(side: &str) -> Result<Color, SfenError> {
+    match side {
+        "b" => Ok(Color::Black),
+        "w" => Ok(Color::White),
+        _ => Err(SfenError::SideToMove(side.to_string())),
+    }
+}
+
+
+// "-" or pieces with optional counts in front, e.g. "S2Pb10p" (counts can have two digits)
+fn parse_hand(hand: &str, pos: &mut Position) -> Result<(), SfenError> {
+
+    if hand == "-" {
+        return Ok(());
+    }
+
+    let mut digits = String::new();
+    for c in hand.chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            continue;
+        }
+
+        let kind = piece_kind_of(c).filter(|&kind| kind != PieceKind::King).ok_or(SfenError::HandLetter(c))?;
+        let color = if c.is_ascii_uppercase() { Color::Black } else { Color::White };
+        let count = if digits.is_empty() { 1 } else { digits.parse::<u32>().unwrap_or(u32::MAX) };
+        digits.clear();
+
+        // a piece may appear more than once ("PP"), what counts is the total
+        let total = (pos.hand(color, kind) as u32).saturating_add(count);
+        if count == 0 || total > hand_limit(kind) {
+            return Err(SfenError::HandCount { piece: c, count: if count == 0 { 0 } else { total } });
+        }
+        pos.hand_set(color, kind, total as u8);
+    }
+
+    if !digits.is_empty() {
+        return Err(SfenError::DanglingHandCount(digits));
+    }
+    Ok(())
+}
+
+
+// Parses "<board> <side to move> <hand> [<move number>]". The move number may be
+// left out (it is then 1), everything else has to be there and make sense.
+pub fn parse(sfen: &str) -> Result<Position, SfenError> {
+
+    let mut fields = sfen.split_whitespace();
+    let mut pos = Position::empty();
+
+    parse_board(fields.next().ok_or(SfenError::MissingField("board"))?, &mut pos)?;
+    pos.side_to_move_set(parse_side(fields.next().ok_or(SfenError::MissingField("side to move"))?)?);
+    parse_hand(fields.next().ok_or(SfenError::MissingField("hand"))?, &mut pos)?;
+
+    if let Some(number) = fields.next() {
+        match number.parse::<u16>() {
+            Ok(ply) if ply > 0 => pos.ply_set(ply),
+            _ => return Err(SfenError::MoveNumber(number.to_string())),
+        }
+    }
+    if let Some(extra) = fields.next() {
+        return Err(SfenError::ExtraField(extra.to_string()));
+    }
+
+    Ok(pos)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const START: &str = "lnsgkgsnl/1r5b1/ppppppppp/9/9/9/PPPPPPPPP/1B5R1/LNSGKGSNL b - 1";
+
+    #[test]
+    fn parses_start_position() {
+        assert_eq!(parse(START), Ok(Position::startpos()));
+    }
+
+    #[test]
+    fn round_trips_through_to_sfen() {
+        let sfen = "l6nl/5+P1gk/2np1S3/p1p4Pp/3P2Sp1/1PPb2P1P/P5GS1/R8/LN4bKL w RGgsn5p 1";
+        let pos = parse(sfen).unwrap();
+        assert_eq!(parse(&pos.to_sfen()), Ok(pos));
+    }
+
+    #[test]
+    fn reads_multi_digit_hand_counts() {
+        let pos = parse("4k4/9/9/9/9/9/9/9/4K4 w S10pr 12").unwrap();
+        assert_eq!(pos.hand(Color::White, PieceKind::Pawn), 10);
+        assert_eq!(pos.hand(Color::White, PieceKind::Rook), 1);
+        assert_eq!(pos.hand(Color::Black, PieceKind::Silver), 1);
+        assert_eq!(pos.ply(), 12);
+    }
+
+    #[test]
+    fn reports_what_is_wrong() {
+        let cases = [
+            ("9/9/9/9/9/9/9/9 b - 1", SfenError::RankCount(8)),
+            ("4k4/9/9/9/9/9/9/8/4K4 b - 1", SfenError::RankLength { rank: 'h', squares: 8 }),
+            ("4k4/9/9/9/9/9/9/9/4K3X b - 1", SfenError::PieceLetter { rank: 'i', letter: 'X' }),
+            ("4k4/9/9/9/9/9/9/9/4K3+G b - 1", SfenError::Promotion { rank: 'i', letter: 'G' }),
+            ("4k4/9/9/9/9/9/9/9/4K4 x - 1", SfenError::SideToMove("x".to_string())),
+            ("4k4/9/9/9/9/9/9/9/4K4 b 19P 1", SfenError::HandCount { piece: 'P', count: 19 }),
+            ("4k4/9/9/9/9/9/9/9/4K4 b 2K 1", SfenError::HandLetter('K')),
+            ("4k4/9/9/9/9/9/9/9/4K4 b P2 1", SfenError::DanglingHandCount("2".to_string())),
+            ("4k4/9/9/9/9/9/9/9/4K4 b - 0", SfenError::MoveNumber("0".to_string())),
+            ("4k4/9/9/9/9/9/9/9/4K4 b", SfenError::MissingField("hand")),
+        ];
+        for (sfen, err) in cases {
+            assert_eq!(parse(sfen), Err(err), "{}", sfen);
+        }
+    }
+}
@@ -0,0 +1,155 @@
+/* Piece relationships: pawn structure, gold/silver coordination, knight and lance safety
+ *
+ * Counted for one side, everything that is good for it:
+ *
+ *    pawn_files          files with one of its pawns
+ *    rook_open_files     its rooks and dragons on a file without a pawn of its own
+ *    blocked_bishops     the opponent's pawns standing on the diagonals of the
+ *                        opponent's bishops and horses, where they stop them
+ *    connected_guards    its golds and silvers next to its king, or next to one
+ *                        that is (so the walls of a castle hold together)
+ *    exposed_knights     the opponent's knights one of its pawns attacks, or
+ *                        could by dropping a pawn in front of them (the "high
+ *                        knight" that can't retreat)
+ *    backed_lances       its lances with a rook or dragon behind them on the file
+ *    promoted_near_king  its promoted pieces within two squares of the opponent's
+ *                        king (phase::king_area)
+ *
+ * The opponent's weaknesses count for the side that can play on them, so no
+ * count is ever against anyone. Each is weighted by its [structure] parameter.
+ */
+
+use crate::attacks;
+use crate::bitboard::Bitboard;
+use crate::phase;
+use crate::position::Position;
+use shogi_core::{Color, Piece, PieceKind, Square};
+
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Structure {
+    pub counts: [u32; Structure::COUNT],  // like Structure::NAMES
+}
+
+
+// the files of the squares in `squares`, bit n for file n
+fn files(squares: Bitboard) -> u16 {
+    squares.fold(0, |files, square| files | 1 << square.file())
+}
+
+
+// `side`'s pawn would attack the knight on `square` from the square in front of it:
+// there already, or droppable there
+fn knight_exposed(pos: &Position, square: Square, side: Color, pawn_files: u16) -> bool {
+    let behind = if side == Color::Black { 1 } else { -1 };
+    let Some(front) = square.shift(0, behind) else {
+        return false;
+    };
+    match pos.piece_at(front) {
+        Some(piece) => piece == Piece::new(PieceKind::Pawn, side),
+        None => pos.hand(side, PieceKind::Pawn) > 0 && pawn_files & 1 << front.file() == 0,
+    }
+}
+
+
+// the first piece behind `side`'s lance on `square` is one of its rooks or dragons
+fn lance_backed(pos: &Position, square: Square, side: Color) -> bool {
+    let behind = if side == Color::Black { 1 } else { -1 };
+    let mut current = square;
+    while let Some(next) = current.shift(0, behind) {
+        if let Some(piece) = pos.piece_at(next) {
+            return piece.color() == side && matches!(piece.piece_kind(), PieceKind::Rook | PieceKind::ProRook);
+        }
+        current = next;
+    }
+    false
+}
+
+
+impl Structure {
+
+    pub const COUNT: usize = 7;
+    pub const NAMES: [&'static str; Structure::COUNT] = [
+        "pawn_files",
+        "rook_open_files",
+        "blocked_bishops",
+        "connected_guards",
+        "exposed_knights",
+        "backed_lances",
+        "promoted_near_king",
+    ];
+
+    pub fn compute(pos: &Position, side: Color) -> Structure {
+
+        let enemy = side.flip();
+        let occupied = pos.occupied();
+        let pieces = |kinds: &[PieceKind], color: Color| kinds.iter().fold(Bitboard::EMPTY, |bb, &kind| bb | pos.piece_bitboard(Piece::new(kind, color)));
+        let pawn_files = files(pieces(&[PieceKind::Pawn], side));
+
+        let rook_open_files = pieces(&[PieceKind::Rook, PieceKind::ProRook], side)
+            .filter(|square| pawn_files & 1 << square.file() == 0)
+            .count() as u32;
+
+        let enemy_pawns = pieces(&[PieceKind::Pawn], enemy);
+        let blocked_bishops = pieces(&[PieceKind::Bishop, PieceKind::ProBishop], enemy)
+            .map(|square| (attacks::piece_attacks(Piece::new(PieceKind::Bishop, enemy), square, occupied) & enemy_pawns).count())
+            .sum();
+
+        // out from the king through golds and silvers next to each other
+        let guards = pieces(&[PieceKind::Gold, PieceKind::Silver], side);
+        let neighbours = |squares: Bitboard| squares.fold(Bitboard::EMPTY, |bb, square| bb | attacks::piece_attacks(Piece::new(PieceKind::King, side), square, Bitboard::EMPTY));
+        let mut connected = pos.king_square(side).map_or(Bitboard::EMPTY, |king| neighbours(Bitboard::from_square(king)) & guards);
+        loop {
+            let grown = connected | (neighbours(connected) & guards);
+            if grown == connected {
+                break;
+            }
+            connected = grown;
+        }
+
+        let exposed_knights = pieces(&[PieceKind::Knight], enemy)
+            .filter(|&square| knight_exposed(pos, square, side, pawn_files))
+            .count() as u32;
+
+        let backed_lances = pieces(&[PieceKind::Lance], side)
+            .filter(|&square| lance_backed(pos, square, side))
+            .count() as u32;
+
+        let promoted = pieces(&[PieceKind::ProPawn, PieceKind::ProLance, PieceKind::ProKnight, PieceKind::ProSilver, PieceKind::ProBishop, PieceKind::ProRook], side);
+        let promoted_near_king = (promoted & phase::king_area(pos, enemy)).count();
+
+        Structure { counts: [
+            pawn_files.count_ones(),
+            rook_open_files,
+            blocked_bishops,
+            connected.count(),
+            exposed_knights,
+            backed_lances,
+            promoted_near_king,
+        ] }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_relationships() {
+        let start = Structure::compute(&Position::startpos(), Color::Black);
+        // every file has a pawn, white's bishop has pawns on 1c and 3c in its way, the
+        // 4i and 6i golds stand next to the king and the 3i and 7i silvers next to them
+        assert_eq!(start.counts, [9, 0, 2, 4, 0, 0, 0]);
+        assert_eq!(Structure::compute(&Position::startpos(), Color::White), start);
+
+        // black: a rook without a pawn on its file behind its lance, a dragon next to the white king
+        // and a pawn in hand to drop on white's knight; white: a bishop stuck behind its own pawn
+        let pos = Position::from_sfen("4k4/3+R5/9/9/4n4/9/b8/1pL6/2R1K4 b P 1");
+        let black = Structure::compute(&pos, Color::Black);
+        assert_eq!(black.counts, [0, 2, 1, 0, 1, 1, 1]);
+        // white has a pawn file and nothing else
+        assert_eq!(Structure::compute(&pos, Color::White).counts, [1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(Structure::compute(&pos.flipped(), Color::White), black);
+    }
+}
@@ -0,0 +1,481 @@
+/* Texel tuning: fitting the evaluation weights to game results
+ *
+ * Input is a text file of labelled positions, one per line:
+ *
+ *    <sfen> <result>       e.g.  lnsgkgsnl/1r5b1/ppppppppp/9/9/9/PPPPPPPPP/1B5R1/LNSGKGSNL b - 1 0.5
+ *
+ * The result is the game's outcome for black: 1 (or 1-0), 0 (0-1), 0.5
+ * (1/2-1/2), or any number in between. Empty lines and # comments are skipped.
+ *
+ * The evaluation (black's fitness minus white's) is turned into a predicted
+ * result with sigmoid(s) = 1 / (1 + 10^(-k s / 400)) and every weight, of
+ * both phases (params::TaperedParams), is fitted with Adam to minimise the logistic loss (cross entropy)
+ * against the real results. k is fitted first with the starting weights
+ * unless it is given.
+ *
+ * Each position is taken apart into eval::EvalTerms once, on loading. Apart
+ * from king vulnerability (whose weights multiply each other) and king safety
+ * (squared attack units) the evaluation is linear in the weights, so a
+ * position is kept as one coefficient per weight plus the counts of those two
+ * terms, and the loss and its gradient never touch a board again. Weights stay zero or above, as the evaluation clamps every term there.
+ *
+ * A position's weights are the opening and endgame ones blended by its game
+ * phase, so each phase's gradient is the blended weight's times phase (opening)
+ * or 1 - phase (endgame).
+ */
+
+use crate::castle::Castle;
+use crate::eval::{EvalTerms, SideTerms};
+use crate::king_safety::{KingAttack, MAX_UNITS};
+use crate::params::{EvalParams, TaperedParams};
+use crate::position::HAND_KINDS;
+use crate::sfen;
+use crate::structure::Structure;
+use shogi_core::PieceKind;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TexelConfig {
+    pub epochs: usize,
+    pub rate: f64,            // Adam step size, in centipawns
+    pub k: Option<f64>,       // sigmoid scale, fitted when not given
+    pub out: PathBuf,         // where the tuned weights are written (TOML)
+}
+
+
+impl Default for TexelConfig {
+    fn default() -> Self {
+        TexelConfig { epochs: 300, rate: 1.0, k: None, out: PathBuf::from("texel.toml") }
+    }
+}
+
+
+impl TexelConfig {
+
+    // Parses e.g. ["epochs", "500", "rate", "2", "k", "1.1", "out", "tuned.toml"].
+    pub fn parse(args: &[&str]) -> Result<Self, String> {
+        let mut config = TexelConfig::default();
+        for pair in args.chunks(2) {
+            let [name, value] = pair else { return Err(format!("{} needs a value", pair[0])) };
+            let number = || value.parse::<f64>().map_err(|_| format!("invalid value for {}: {}", name, value));
+            match *name {
+                "epochs" => config.epochs = number()? as usize,
+                "rate" => config.rate = number()?,
+                "k" => config.k = Some(number()?),
+                "out" => config.out = PathBuf::from(value),
+                other => return Err(format!("unknown tuner option: {}", other)),
+            }
+        }
+        Ok(config)
+    }
+}
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TexelError {
+    Io(String),
+    Line { line: usize, message: String },
+    NoPositions,
+}
+
+
+impl fmt::Display for TexelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TexelError::Io(err) => write!(f, "{}", err),
+            TexelError::Line { line, message } => write!(f, "line {}: {}", line, message),
+            TexelError::NoPositions => write!(f, "no positions to tune on"),
+        }
+    }
+}
+
+
+impl std::error::Error for TexelError {}
+
+
+// --------------------------------------- positions ---------------------------------------
+
+
+// one labelled position, reduced to what the loss needs
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sample {
+    result: f64,                 // for black
+    phase: f64,                  // phase::phase, how much of the opening weights apply
+    coefficients: Vec<f32>,      // per weight (names() order): black's count minus white's, 0 for the king vulnerability and safety ones
+    vuln: [[f32; 4]; 2],         // king_vuln_counts for (white, black)
+    safety: [Vec<f32>; 2],       // KingAttack::counts for (white, black)
+}
+
+
+// the weights as an EvalParams with every field zero, to be filled in as coefficients
+fn zero_params() -> EvalParams {
+    EvalParams {
+        promoted_pieces: 0.0,
+        pst: 0.0,
+        king_vuln: 0.0,
+        vuln_attacked: 0.0,
+        vuln_defenders: 0.0,
+        vuln_king_attackers: 0.0,
+        vuln_escapes: 0.0,
+        mobility: [0.0; PieceKind::NUM],
+        hand_bonus: [0.0; 7],
+        material: [0.0; PieceKind::NUM],
+        material_hand: [0.0; 7],
+        castle: [0.0; Castle::COUNT],
+        safety_weight: 0.0,
+        safety_zone_squares: 0.0,
+        safety_checks: 0.0,
+        safety_drops: [0.0; 3],
+        zone_attackers: [0.0; PieceKind::NUM],
+        structure: [0.0; Structure::COUNT],
+    }
+}
+
+
+impl Sample {
+
+    pub fn new(terms: &EvalTerms, result: f64) -> Sample {
+
+        let diff = |f: fn(&SideTerms) -> u32| f(&terms.black) as f32 - f(&terms.white) as f32;
+        let mut c = zero_params();
+        c.promoted_pieces = diff(|t| t.promoted);
+        c.pst = terms.black.pst as f32 - terms.white.pst as f32;
+        for (i, kind) in HAND_KINDS.iter().enumerate() {
+            let in_hand = terms.black.hand[i] as f32 - terms.white.hand[i] as f32;
+            c.hand_bonus[kind.array_index()] = in_hand;
+            c.material_hand[kind.array_index()] = in_hand;
+        }
+        for kind in PieceKind::all() {
+            let i = kind.array_index();
+            c.material[i] = terms.black.board[i] as f32 - terms.white.board[i] as f32;
+            c.mobility[i] = terms.black.mobility[i] as f32 - terms.white.mobility[i] as f32;
+        }
+        for i in 0..Castle::COUNT {
+            c.castle[i] = terms.black.castle[i] - terms.white.castle[i];
+        }
+        for i in 0..Structure::COUNT {
+            c.structure[i] = terms.black.structure.counts[i] as f32 - terms.white.structure.counts[i] as f32;
+        }
+
+        Sample {
+            result,
+            phase: terms.phase as f64,
+            coefficients: c.fields().into_iter().map(|(_, value)| value).collect(),
+            vuln: [terms.white.king_vuln, terms.black.king_vuln],
+            safety: [&terms.white, &terms.black].map(|side| side.king_safety.counts().into_iter().map(|(_, count)| count).collect()),
+        }
+    }
+}
+
+
+// a result for black: 1, 0, 0.5 (or anything in between), 1-0, 0-1, 1/2-1/2
+pub fn parse_result(text: &str) -> Option<f64> {
+    match text {
+        "1-0" => Some(1.0),
+        "0-1" => Some(0.0),
+        "1/2-1/2" => Some(0.5),
+        _ => text.parse::<f64>().ok().filter(|r| (0.0..=1.0).contains(r)),
+    }
+}
+
+
+// the labelled positions in `text`, in the format at the top of this file
+pub fn parse_positions(text: &str) -> Result<Vec<Sample>, TexelError> {
+
+    let mut samples = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let error = |message: String| TexelError::Line { line: number + 1, message };
+        let (sfen_text, result) = line.rsplit_once(char::is_whitespace).ok_or_else(|| error("expected <sfen> <result>".to_string()))?;
+        let result = parse_result(result).ok_or_else(|| error(format!("bad result \"{}\"", result)))?;
+        let pos = sfen::parse(sfen_text).map_err(|e| error(format!("invalid sfen: {}", e)))?;
+        samples.push(Sample::new(&EvalTerms::compute(&pos), result));
+    }
+    Ok(samples)
+}
+
+
+pub fn load_positions(path: &str) -> Result<Vec<Sample>, TexelError> {
+    let text = std::fs::read_to_string(path).map_err(|e| TexelError::Io(format!("{}: {}", path, e)))?;
+    parse_positions(&text)
+}
+
+
+// ------------------------------------------ model ------------------------------------------
+
+
+// where the weights that aren't plain coefficients sit in EvalParams::names() order
+struct Layout {
+    king_vuln: usize,
+    vuln: [usize; 4],   // attacked, defenders, king attackers, escapes
+    safety: usize,      // king_safety.weight
+    units: Vec<usize>,  // the weights of KingAttack::counts
+}
+
+
+impl Layout {
+    fn new() -> Layout {
+        let names = EvalParams::names();
+        let at = |name: &str| names.iter().position(|n| n == name).expect("known weight");
+        Layout {
+            king_vuln: at("weights.king_vuln"),
+            vuln: [at("king_vuln.attacked"), at("king_vuln.defenders"), at("king_vuln.king_attackers"), at("king_vuln.escapes")],
+            safety: at("king_safety.weight"),
+            units: KingAttack::default().counts().iter().map(|(name, _)| at(name)).collect(),
+        }
+    }
+}
+
+
+// signs of the king vulnerability counts (defenders and escapes count against)
+const VULN_SIGNS: [f64; 4] = [1.0, -1.0, 1.0, -1.0];
+
+
+// king vulnerability of one side before the king_vuln weight, as eval::king_vuln_weighted
+fn vulnerability(counts: &[f32; 4], weights: &[f64], layout: &Layout) -> f64 {
+    let sum: f64 = (0..4).map(|i| VULN_SIGNS[i] * counts[i] as f64 * weights[layout.vuln[i]]).sum();
+    sum.max(0.0)
+}
+
+
+// attack units of one side, as KingAttack::units
+fn units(counts: &[f32], weights: &[f64], layout: &Layout) -> f64 {
+    let sum: f64 = counts.iter().zip(&layout.units).map(|(&count, &i)| count as f64 * weights[i]).sum();
+    sum.clamp(0.0, MAX_UNITS as f64)
+}
+
+
+// The weights of one phase a sample is evaluated with. `weights` are those of TaperedParams::names(),
+// the opening ones and then the endgame ones.
+fn blend(weights: &[f64], phase: f64) -> Vec<f64> {
+    let (opening, endgame) = weights.split_at(weights.len() / 2);
+    opening.iter().zip(endgame).map(|(&o, &e)| e + (o - e) * phase).collect()
+}
+
+
+// black's fitness minus white's under `weights`, without the evaluation's rounding
+fn score(sample: &Sample, weights: &[f64], layout: &Layout) -> f64 {
+    blended_score(sample, &blend(weights, sample.phase), layout)
+}
+
+
+fn blended_score(sample: &Sample, weights: &[f64], layout: &Layout) -> f64 {
+    let linear: f64 = sample.coefficients.iter().zip(weights).map(|(&c, &w)| c as f64 * w).sum();
+    let [white, black] = &sample.vuln;
+    let vuln = vulnerability(black, weights, layout) - vulnerability(white, weights, layout);
+    let [white, black] = &sample.safety;
+    let danger = units(black, weights, layout).powi(2) - units(white, weights, layout).powi(2);
+    linear + weights[layout.king_vuln] * vuln + weights[layout.safety] * danger
+}
+
+
+fn sigmoid(score: f64, k: f64) -> f64 {
+    1.0 / (1.0 + 10f64.powf(-k * score / 400.0))
+}
+
+
+// mean cross entropy between the predicted and the real results
+fn loss(samples: &[Sample], weights: &[f64], k: f64, layout: &Layout) -> f64 {
+    let total: f64 = samples.iter().map(|sample| {
+        let p = sigmoid(score(sample, weights, layout), k).clamp(1e-12, 1.0 - 1e-12);
+        -(sample.result * p.ln() + (1.0 - sample.result) * (1.0 - p).ln())
+    }).sum();
+    total / samples.len() as f64
+}
+
+
+// gradient of the loss with respect to every weight
+fn gradient(samples: &[Sample], weights: &[f64], k: f64, layout: &Layout) -> Vec<f64> {
+
+    let mut total = vec![0.0; weights.len()];
+    let (opening, endgame) = total.split_at_mut(weights.len() / 2);
+    for sample in samples {
+        // with respect to the sample's blended weights first
+        let weights = blend(weights, sample.phase);
+        let (king_vuln, safety) = (weights[layout.king_vuln], weights[layout.safety]);
+        let mut grad = vec![0.0; weights.len()];
+        // d loss / d score of the cross entropy of a sigmoid
+        let d = (sigmoid(blended_score(sample, &weights, layout), k) - sample.result) * k * std::f64::consts::LN_10 / 400.0;
+        for (g, &c) in grad.iter_mut().zip(&sample.coefficients) {
+            *g += d * c as f64;
+        }
+        for (side, counts) in sample.vuln.iter().enumerate() {
+            let sign = if side == 1 { 1.0 } else { -1.0 };
+            let vuln = vulnerability(counts, &weights, layout);
+            grad[layout.king_vuln] += d * sign * vuln;
+            if vuln > 0.0 {
+                for i in 0..4 {
+                    grad[layout.vuln[i]] += d * sign * king_vuln * VULN_SIGNS[i] * counts[i] as f64;
+                }
+            }
+        }
+        for (side, counts) in sample.safety.iter().enumerate() {
+            let sign = if side == 1 { 1.0 } else { -1.0 };
+            let units = units(counts, &weights, layout);
+            grad[layout.safety] += d * sign * units * units;
+            if units > 0.0 && units < MAX_UNITS as f64 {
+                for (&count, &i) in counts.iter().zip(&layout.units) {
+                    grad[i] += d * sign * safety * 2.0 * units * count as f64;
+                }
+            }
+        }
+        // then shared out between the phases
+        for ((g, o), e) in grad.iter().zip(opening.iter_mut()).zip(endgame.iter_mut()) {
+            *o += g * sample.phase;
+            *e += g * (1.0 - sample.phase);
+        }
+    }
+    let n = samples.len() as f64;
+    total.iter().map(|g| g / n).collect()
+}
+
+
+// the k that fits the results best with these weights (golden section search)
+fn fit_k(samples: &[Sample], weights: &[f64], layout: &Layout) -> f64 {
+    let ratio = (5f64.sqrt() - 1.0) / 2.0;
+    let (mut low, mut high) = (0.01, 10.0);
+    while high - low > 1e-4 {
+        let a = high - ratio * (high - low);
+        let b = low + ratio * (high - low);
+        if loss(samples, weights, a, layout) < loss(samples, weights, b, layout) {
+            high = b;
+        } else {
+            low = a;
+        }
+    }
+    (low + high) / 2.0
+}
+
+
+fn to_weights(params: &TaperedParams) -> Vec<f64> {
+    params.fields().into_iter().map(|(_, value)| value as f64).collect()
+}
+
+
+fn to_params(weights: &[f64], base: &TaperedParams) -> TaperedParams {
+    let mut params = *base;
+    for (name, &value) in TaperedParams::names().iter().zip(weights) {
+        params.set(name, value as f32).expect("names from fields()");
+    }
+    params
+}
+
+
+// ------------------------------------------- run -------------------------------------------
+
+
+pub struct TexelResult {
+    pub params: TaperedParams,
+    pub k: f64,
+    pub start_loss: f64,
+    pub end_loss: f64,
+}
+
+
+// Tunes `start` on `samples` with Adam and returns the fitted weights.
+pub fn tune(samples: &[Sample], start: &TaperedParams, config: &TexelConfig) -> Result<TexelResult, TexelError> {
+
+    if samples.is_empty() {
+        return Err(TexelError::NoPositions);
+    }
+    let layout = Layout::new();
+    let mut weights = to_weights(start);
+    let k = config.k.unwrap_or_else(|| fit_k(samples, &weights, &layout));
+    let start_loss = loss(samples, &weights, k, &layout);
+    println!(" | {} positions, k {:.4}, loss {:.6}", samples.len(), k, start_loss);
+
+    let (beta1, beta2, epsilon) = (0.9, 0.999, 1e-8);
+    let mut m = vec![0.0; weights.len()];
+    let mut v = vec![0.0; weights.len()];
+
+    for epoch in 1..=config.epochs {
+        let grad = gradient(samples, &weights, k, &layout);
+        for i in 0..weights.len() {
+            m[i] = beta1 * m[i] + (1.0 - beta1) * grad[i];
+            v[i] = beta2 * v[i] + (1.0 - beta2) * grad[i] * grad[i];
+            let m_hat = m[i] / (1.0 - beta1.powi(epoch as i32));
+            let v_hat = v[i] / (1.0 - beta2.powi(epoch as i32));
+            weights[i] = (weights[i] - config.rate * m_hat / (v_hat.sqrt() + epsilon)).max(0.0);
+        }
+        if epoch % 50 == 0 || epoch == config.epochs {
+            println!(" | epoch {:>5}  loss {:.6}", epoch, loss(samples, &weights, k, &layout));
+        }
+    }
+
+    Ok(TexelResult {
+        params: to_params(&weights, start),
+        k,
+        start_loss,
+        end_loss: loss(samples, &weights, k, &layout),
+    })
+}
+
+
+// the fitted weights next to the ones tuning started from
+pub fn report(result: &TexelResult, start: &TaperedParams) {
+    println!(" | ");
+    println!(" |-----------------------------TUNED WEIGHTS-------------------------------|");
+    println!(" | k: {:.4}  loss: {:.6} -> {:.6}", result.k, result.start_loss, result.end_loss);
+    let before: HashMap<String, f32> = start.fields().into_iter().collect();
+    for (name, value) in result.params.fields() {
+        println!(" | {:<36} {:>9.2}  (was {:.2})", name, value, before[&name]);
+    }
+    println!(" |-------------------------------------------------------------------------|");
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval;
+    use crate::params::DEFAULT_TAPERED;
+    use crate::position::Position;
+
+    const START: &str = "lnsgkgsnl/1r5b1/ppppppppp/9/9/9/PPPPPPPPP/1B5R1/LNSGKGSNL b - 1";
+    // black has taken a rook (white's is in black's hand)
+    const AHEAD: &str = "lnsgkgsnl/7b1/ppppppppp/9/9/9/PPPPPPPPP/1B5R1/LNSGKGSNL w R 1";
+
+    #[test]
+    fn reads_labelled_positions() {
+        let text = format!("# test\n{} 0.5\n{} 1-0\n\n{} 1/2-1/2\n", START, AHEAD, START);
+        let samples = parse_positions(&text).unwrap();
+        assert_eq!(samples.iter().map(|s| s.result).collect::<Vec<_>>(), vec![0.5, 1.0, 0.5]);
+        assert!(matches!(parse_positions(&format!("{} 2", START)), Err(TexelError::Line { line: 1, .. })));
+        assert!(matches!(parse_positions("9/9 b - 1 1"), Err(TexelError::Line { line: 1, .. })));
+
+        // the model is the evaluation without its rounding
+        let layout = Layout::new();
+        let pos = Position::from_sfen(AHEAD);
+        let ((white, black), _) = eval::evaluate_tapered(&pos, &DEFAULT_TAPERED);
+        let modelled = score(&samples[1], &to_weights(&DEFAULT_TAPERED), &layout);
+        assert!((modelled - (black - white) as f64).abs() < 10.0, "{} vs {}", modelled, black - white);
+    }
+
+    #[test]
+    fn gradient_matches_the_loss() {
+        let text = format!("{} 0.5\n{} 1\n{} 0\n", START, AHEAD, AHEAD);
+        let samples = parse_positions(&text).unwrap();
+        let layout = Layout::new();
+        let weights = to_weights(&DEFAULT_TAPERED);
+        let grad = gradient(&samples, &weights, 1.0, &layout);
+
+        for i in 0..weights.len() {
+            let mut up = weights.clone();
+            let mut down = weights.clone();
+            up[i] += 0.01;
+            down[i] -= 0.01;
+            let numeric = (loss(&samples, &up, 1.0, &layout) - loss(&samples, &down, 1.0, &layout)) / 0.02;
+            assert!((numeric - grad[i]).abs() < 1e-5, "{}: {} vs {}", TaperedParams::names()[i], numeric, grad[i]);
+        }
+
+        let config = TexelConfig { epochs: 20, k: Some(1.0), ..Default::default() };
+        let result = tune(&samples, &DEFAULT_TAPERED, &config).unwrap();
+        assert!(result.end_loss < result.start_loss);
+    }
+}
@@ -0,0 +1,403 @@
+/* Tsume-shogi (checkmate problem) solver using df-pn
+ *
+ * df-pn (depth-first proof-number search, Nagai 2002) keeps for every position
+ * a proof number (how many positions still have to be shown to be mates) and
+ * a disproof number (how many have to be shown to escape) and always expands
+ * the most promising position first, within thresholds, so it only needs a
+ * transposition table instead of an explicit tree.
+ *
+ *  - OR nodes  (attacker to move): only checking moves are tried.
+ *  - AND nodes (defender to move): every legal evasion is tried.
+ *
+ * Proof and disproof numbers are always from the attacker's point of view
+ * (proved = mate). Once a mate is proved, the solver keeps searching with a
+ * tighter ply budget until no shorter mate exists, so the reported line is a
+ * shortest mate. Following tsume conventions:
+ *
+ *  - the defender may hold every piece that is neither on the board nor in
+ *    the attacker's hand (`TsumeOptions::defender_has_remaining`),
+ *  - useless interpositions (muda-ai: a piece dropped between the king and a
+ *    checking piece only to be captured without changing the outcome) are not
+ *    counted as defences when measuring the length of the mate,
+ *  - mate by dropping a pawn is illegal (handled by the move generator).
+ */
+
+use crate::movegen::{self, MoveList};
+use crate::position::Position;
+use crate::search::SearchControl;
+use shogi_core::{Color, Hand, Move, PartialPosition, PieceKind, Square};
+use std::collections::{HashMap, HashSet};
+
+
+// "infinite" proof/disproof number
+const INF: u32 = u32::MAX / 4;
+
+// longest mate searched for when no other limit is given (in plies)
+pub const DEFAULT_MAX_PLY: u32 = 31;
+
+// nodes the command line solver gives a problem unless told otherwise
+pub const DEFAULT_NODES: u64 = 1_000_000;
+
+// total number of each piece kind in a shogi set
+const PIECE_SET: [(PieceKind, u8); 7] = [
+    (PieceKind::Pawn, 18),
+    (PieceKind::Lance, 4),
+    (PieceKind::Knight, 4),
+    (PieceKind::Silver, 4),
+    (PieceKind::Gold, 4),
+    (PieceKind::Bishop, 2),
+    (PieceKind::Rook, 2),
+];
+
+
+#[derive(Clone, Copy, Debug)]
+pub struct TsumeOptions {
+    pub max_ply: u32,                  // longest mate looked for, in plies (odd: attacker moves last)
+    pub defender_has_remaining: bool,  // give the defender every piece not on the board or in the attacker's hand
+}
+
+
+impl Default for TsumeOptions {
+    fn default() -> Self {
+        TsumeOptions { max_ply: DEFAULT_MAX_PLY, defender_has_remaining: true }
+    }
+}
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TsumeResult {
+    Mate(Vec<Move>),  // shortest mating line found, attacker's move first
+    NoMate,           // proved that there is no mate within the ply budget
+    Unknown,          // the search limits ran out first
+}
+
+
+// transposition table entry
+#[derive(Clone, Copy)]
+struct Entry {
+    pn: u32,
+    dn: u32,
+    depth: u32,  // plies that were left when this entry was computed
+}
+
+
+// gives `defender` all the pieces that are neither on the board nor in the attacker's hand
+pub fn give_remaining_pieces(pos: &mut PartialPosition, defender: Color) {
+
+    for (kind, total) in PIECE_SET {
+        let mut used = 0;
+        for square in Square::all() {
+            if let Some(piece) = pos.piece_at(square) {
+                let base = piece.piece_kind().unpromote().unwrap_or(piece.piece_kind());
+                if base == kind {
+                    used += 1;
+                }
+            }
+        }
+        used += pos.hand_of_a_player(defender.flip()).count(kind).unwrap_or(0);
+
+        let hand: &mut Hand = pos.hand_of_a_player_mut(defender);
+        let owned = hand.count(kind).unwrap_or(0);
+        for _ in (used + owned)..total {
+            if let Some(added) = hand.added(kind) {
+                *hand = added;
+            }
+        }
+    }
+}
+
+
+pub struct Solver<'a, 'b> {
+    ctl: &'a SearchControl<'b>,
+    table: HashMap<u64, Entry>,
+    path: HashSet<u64>,  // positions on the current line, a repetition is never a mate
+}
+
+
+impl<'a, 'b> Solver<'a, 'b> {
+
+    pub fn new(ctl: &'a SearchControl<'b>) -> Self {
+        Solver { ctl, table: HashMap::new(), path: HashSet::new() }
+    }
+
+    // Solves the position for the side to move.
+    pub fn solve(&mut self, pos: &PartialPosition, options: TsumeOptions) -> TsumeResult {
+
+        let mut pos = pos.clone();
+        if options.defender_has_remaining {
+            let defender = pos.side_to_move().flip();
+            give_remaining_pieces(&mut pos, defender);
+        }
+        let mut board = Position::from_partial(&pos);
+
+        let mut best: Option<Vec<Move>> = None;
+        let mut max_ply = options.max_ply;
+
+        // prove a mate, then keep asking for a shorter one until there is none
+        loop {
+            let (pn, dn) = self.mid(&mut board, true, INF - 1, INF - 1, max_ply);
+
+            if pn == 0 {
+                match self.principal_variation(&mut board, true, max_ply, &mut HashMap::new()) {
+                    Some((_, line)) => {
+                        let length = line.len() as u32;
+                        best = Some(line);
+                        if length < 3 {
+                            break;
+                        }
+                        max_ply = length - 2;
+                    },
+                    None => break,
+                }
+            } else if dn == 0 {
+                break;
+            } else {
+                // limits ran out
+                return match best {
+                    Some(line) => TsumeResult::Mate(line),
+                    None => TsumeResult::Unknown,
+                };
+            }
+        }
+
+        match best {
+            Some(line) => TsumeResult::Mate(line),
+            None => TsumeResult::NoMate,
+        }
+    }
+
+    // proof/disproof numbers of a position as currently known
+    fn lookup(&self, key: u64, or_node: bool, depth: u32) -> (u32, u32) {
+
+        if self.path.contains(&key) || (or_node && depth == 0) {
+            return (INF, 0);
+        }
+
+        match self.table.get(&key) {
+            // a proof found with fewer plies left still fits, a disproof with more plies left still holds
+            Some(e) if e.pn == 0 && e.depth <= depth => (0, INF),
+            Some(e) if e.dn == 0 && e.depth >= depth => (INF, 0),
+            Some(e) if e.pn == 0 || e.dn == 0 => (1, 1),
+            Some(e) => (e.pn, e.dn),
+            None => (1, 1),
+        }
+    }
+
+    fn store(&mut self, key: u64, pn: u32, dn: u32, depth: u32) {
+        self.table.insert(key, Entry { pn, dn, depth });
+    }
+
+    // the checks (OR node) or evasions (AND node, the defender is always in check) of `pos`
+    fn moves(pos: &Position, or_node: bool) -> MoveList {
+        let mut pseudo = MoveList::new();
+        if or_node {
+            movegen::generate_checks(pos, &mut pseudo);
+        } else {
+            movegen::generate_evasions(pos, &mut pseudo);
+        }
+        let mut moves = MoveList::new();
+        for mv in pseudo.iter().filter(|&mv| movegen::is_legal(pos, mv)) {
+            moves.push(mv);
+        }
+        moves
+    }
+
+    // the key of the position after `mv`
+    fn child_key(pos: &mut Position, mv: Move) -> u64 {
+        let undo = pos.make_move(mv);
+        let key = pos.key();
+        pos.unmake_move(undo);
+        key
+    }
+
+    // multiple iterative deepening: expands `pos` until its numbers reach the thresholds
+    fn mid(&mut self, pos: &mut Position, or_node: bool, thpn: u32, thdn: u32, depth: u32) -> (u32, u32) {
+
+        let key = pos.key();
+        self.ctl.node();
+
+        if or_node && depth == 0 {
+            return (INF, 0);
+        }
+
+        let moves = Solver::moves(pos, or_node);
+
+        if moves.is_empty() || depth == 0 {
+            // no checks: the attacker failed, no evasions: the defender is mated,
+            // an escape with no plies left: the mate didn't come in time
+            let (pn, dn) = if or_node || !moves.is_empty() { (INF, 0) } else { (0, INF) };
+            self.store(key, pn, dn, depth);
+            return (pn, dn);
+        }
+
+        let children: Vec<(Move, u64)> = moves.iter().map(|mv| (mv, Solver::child_key(pos, mv))).collect();
+
+        self.path.insert(key);
+
+        loop {
+            let values: Vec<(u32, u32)> = children.iter()
+                .map(|(_, key)| self.lookup(*key, !or_node, depth - 1))
+                .collect();
+
+            // from the point of view of the side to move: (numbers to minimise, numbers to sum)
+            let (own, other): (Vec<u32>, Vec<u32>) = if or_node {
+                values.iter().map(|&(pn, dn)| (pn, dn)).unzip()
+            } else {
+                values.iter().map(|&(pn, dn)| (dn, pn)).unzip()
+            };
+
+            let min_own = *own.iter().min().unwrap();
+            let sum_other = other.iter().fold(0, |acc, &x| (acc + x).min(INF));
+            let (pn, dn) = if or_node { (min_own, sum_other) } else { (sum_other, min_own) };
+
+            if pn >= thpn || dn >= thdn || self.ctl.should_stop() {
+                self.path.remove(&key);
+                self.store(key, pn, dn, depth);
+                return (pn, dn);
+            }
+
+            // most promising child and the runner-up value
+            let best = (0..own.len()).min_by_key(|&i| own[i]).unwrap();
+            let second = (0..own.len()).filter(|&i| i != best).map(|i| own[i]).min().unwrap_or(INF);
+
+            let (th_own, th_other) = if or_node { (thpn, thdn) } else { (thdn, thpn) };
+            let child_own = th_own.min(second.saturating_add(1)).min(INF - 1);
+            let child_other = (th_other - sum_other.min(th_other)).saturating_add(other[best]).min(INF - 1);
+            let (child_thpn, child_thdn) = if or_node { (child_own, child_other) } else { (child_other, child_own) };
+
+            let undo = pos.make_move(children[best].0);
+            self.mid(pos, !or_node, child_thpn, child_thdn, depth - 1);
+            pos.unmake_move(undo);
+        }
+    }
+
+    // Walks the proof to build the mating line: the attacker takes the shortest proved
+    // mate, the defender the longest defence that is not a useless interposition.
+    // Returns (length in plies, moves).
+    fn principal_variation(&self, pos: &mut Position, or_node: bool, depth: u32, memo: &mut HashMap<(u64, u32), (u32, Vec<Move>)>) -> Option<(u32, Vec<Move>)> {
+
+        let key = pos.key();
+        if let Some(found) = memo.get(&(key, depth)) {
+            return Some(found.clone());
+        }
+
+        if or_node && depth == 0 {
+            return None;
+        }
+
+        let moves = Solver::moves(pos, or_node);
+
+        let result = if or_node {
+            let mut best: Option<(u32, Vec<Move>)> = None;
+            for mv in moves.iter() {
+                if self.lookup(Solver::child_key(pos, mv), false, depth - 1).0 != 0 {
+                    continue;
+                }
+                let undo = pos.make_move(mv);
+                let found = self.principal_variation(pos, false, depth - 1, memo);
+                pos.unmake_move(undo);
+                if let Some((length, line)) = found {
+                    if best.as_ref().is_none_or(|(l, _)| length + 1 < *l) {
+                        best = Some((length + 1, [vec![mv], line].concat()));
+                    }
+                }
+            }
+            best?
+        } else {
+            if depth == 0 && !moves.is_empty() {
+                return None;
+            }
+            let mut lines: Vec<(Move, u32, Vec<Move>)> = Vec::new();
+            for mv in moves.iter() {
+                let undo = pos.make_move(mv);
+                let found = self.principal_variation(pos, true, depth - 1, memo);
+                pos.unmake_move(undo);
+                let (length, line) = found?;
+                lines.push((mv, length + 1, [vec![mv], line].concat()));
+            }
+
+            // the longest defence that doesn't drop a piece
+            let main = lines.iter().filter(|(mv, _, _)| !mv.is_drop()).map(|(_, l, _)| *l).max().unwrap_or(0);
+
+            // a drop answered by capturing the dropped piece, which mates no later than the
+            // other defences would, is a useless interposition
+            let all = lines.clone();
+            let useful = lines.into_iter().filter(|(mv, length, line)| {
+                let captured = line.get(1).is_some_and(|reply| reply.to() == mv.to());
+                !(mv.is_drop() && captured && *length <= main + 2)
+            });
+
+            // when every defence is such a drop, the longest of them still stands (no evasions at all: mated)
+            let useful: Vec<(Move, u32, Vec<Move>)> = useful.collect();
+            let defences = if useful.is_empty() { &all } else { &useful };
+            defences.iter().max_by_key(|(_, length, _)| *length)
+                .map(|(_, length, line)| (*length, line.clone()))
+                .unwrap_or((0, Vec::new()))
+        };
+
+        memo.insert((key, depth), result.clone());
+        Some(result)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::search::SearchLimits;
+    use shogi_core::ToUsi;
+    use std::sync::atomic::AtomicBool;
+
+    fn solve(sfen: &str, options: TsumeOptions) -> Option<Vec<String>> {
+        let stop = AtomicBool::new(false);
+        let limits = SearchLimits { nodes: Some(200_000), ..Default::default() };
+        let ctl = SearchControl::new(&limits, &stop);
+        let pos = crate::sfen::sfen_to_pos(sfen);
+        match Solver::new(&ctl).solve(&pos, options) {
+            TsumeResult::Mate(line) => Some(line.iter().map(|mv| mv.to_usi_owned()).collect()),
+            TsumeResult::NoMate => None,
+            TsumeResult::Unknown => Some(vec!["UNKNOWN".to_string()]),
+        }
+    }
+
+    fn options(max_ply: u32, defender_has_remaining: bool) -> TsumeOptions {
+        TsumeOptions { max_ply, defender_has_remaining }
+    }
+
+    #[test]
+    fn finds_the_shortest_mate() {
+        assert_eq!(solve("4k4/9/4G4/9/9/9/9/9/9 b G 1", TsumeOptions::default()).unwrap(), ["G*5b"]);
+
+        let sfen = "8k/7S1/9/9/9/9/9/9/9 b RG 1";
+        assert_eq!(solve(sfen, options(7, false)).unwrap(), ["G*2a", "1a1b", "R*1c"]);
+        assert_eq!(solve(sfen, options(1, false)), None);
+    }
+
+    #[test]
+    fn proves_there_is_no_mate() {
+        assert_eq!(solve("4k4/9/9/9/9/9/9/9/9 b P 1", TsumeOptions::default()), None);
+    }
+
+    // P*1b would mate, which makes it illegal; a gold does the same legally
+    #[test]
+    fn mate_by_pawn_drop_is_refuted() {
+        assert_eq!(solve("8k/9/9/7N1/9/9/9/9/7R1 b P 1", options(5, false)), None);
+        assert_eq!(solve("8k/9/9/7N1/9/9/9/9/7R1 b G 1", options(5, false)).unwrap(), ["G*1b"]);
+    }
+
+    // the defender could drop a piece on 1b, which only gets captured: the mate stays 3 plies
+    #[test]
+    fn useless_interpositions_are_not_defences() {
+        let sfen = "5R2k/9/9/9/9/9/9/9/9 b RG 1";
+        assert_eq!(solve(sfen, options(5, false)).unwrap(), ["R*1c", "1a2b", "G*2c"]);
+        assert_eq!(solve(sfen, options(5, true)).unwrap(), ["R*1c", "1a2b", "G*2c"]);
+    }
+
+    // when every defence is an interposition the longest one is still the line
+    #[test]
+    fn interpositions_count_when_they_are_the_only_defences() {
+        let line = solve("8k/6G2/9/7N1/9/9/9/9/4R4 b - 1", options(7, true)).unwrap();
+        assert_eq!(line.len(), 7);
+        assert!(line[1].contains('*'));
+    }
+}
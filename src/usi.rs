@@ -0,0 +1,440 @@
+/* USI (Universal Shogi Interface) front end
+ *
+ * Lets the engine be driven by a shogi GUI. Supported commands:
+ *
+ *    usi, isready, usinewgame, quit
+ *    setoption name <id> [value <x>]
+ *    position [startpos | sfen <sfen>] [moves <m1> <m2> ...]
+ *    go [ponder] [depth <plies>] [nodes <n>] [movetime <ms>] [infinite] [mate <ms> | mate infinite]
+ *    ponderhit, stop, gameover (ends a search without a bestmove)
+ *
+ * A position without the side to move's king (a tsume problem) is accepted,
+ * but only `go mate` searches it. After a rejected `position`, `go` answers
+ * `bestmove resign` until a valid one arrives.
+ *
+ * Searches run in a background thread so `stop` and `ponderhit` can be read
+ * while the engine is thinking. During `go ponder` the search runs on the
+ * position that includes the predicted move; on `ponderhit` that same search
+ * simply carries on as a normal search, so nothing is thrown away. Its
+ * movetime is counted from the `ponderhit`, not from the `go ponder`.
+ *
+ * Options: USI_Ponder, the evaluation weights (see params.rs) one by one under
+ * their own names, e.g. `setoption name material.rook value 1100`, or all at
+ * once from a TOML/JSON file with EvalFile, the piece square tables:
+ * PstFile (see src/pst.txt for the format, empty for the built-in tables) and
+ * PstFileSymmetry (same values on both wings), SafeMobility (mobility
+ * counts only squares no cheaper enemy piece attacks, see eval.rs), and
+ * NnueFile (a network to evaluate with instead, empty for eval.rs, see nnue.rs).
+ */
+
+use crate::eval;
+use crate::nnue::{self, Network};
+use crate::params::{self, EvalParams, TaperedParams};
+use crate::position::Position;
+use crate::pst::{self, PieceSquareTables};
+use crate::search;
+use crate::search::SearchLimits;
+use crate::sfen;
+use crate::validate::{self, ValidateOptions};
+use shogi_core::{Color, Move, PartialPosition, Piece, PieceKind, Square, ToUsi};
+use std::io::{self, BufRead};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+
+const ENGINE_NAME: &str = "rusty-shogi-engine";
+const ENGINE_AUTHOR: &str = "Russell Kosovsky";
+
+
+// a search running in the background
+struct SearchThread {
+    stop: Arc<AtomicBool>,      // raised by `stop`
+    pondering: Arc<AtomicBool>, // true while in `go ponder`, cleared by `ponderhit`
+    silent: Arc<AtomicBool>,    // raised by `gameover`: the game is over, nothing to answer
+    infinite: bool,             // `go infinite` only ends with `stop`
+    handle: JoinHandle<bool>,   // whether the search answered
+}
+
+
+impl SearchThread {
+
+    fn start(pos: &PartialPosition, limits: SearchLimits, ponder: bool) -> SearchThread {
+
+        let sfen = pos.to_sfen_owned();
+        let infinite = limits.infinite;
+        let stop = Arc::new(AtomicBool::new(false));
+        let pondering = Arc::new(AtomicBool::new(ponder));
+        let silent = Arc::new(AtomicBool::new(false));
+        let (thread_stop, thread_pondering, thread_silent) = (Arc::clone(&stop), Arc::clone(&pondering), Arc::clone(&silent));
+
+        let handle = thread::spawn(move || {
+            // a ponder search's movetime only starts at ponderhit
+            let result = search::think_pondering(&sfen, &limits, &thread_stop, &thread_pondering);
+
+            // the protocol forbids answering `go ponder` or `go infinite` before `ponderhit`/`stop`
+            while (thread_pondering.load(Ordering::Relaxed) || limits.infinite) && !thread_stop.load(Ordering::Relaxed) {
+                thread::sleep(Duration::from_millis(5));
+            }
+            if thread_silent.load(Ordering::Relaxed) {
+                return false;
+            }
+
+            if limits.mate.is_some() {
+                match result.mate {
+                    Some(line) => {
+                        let moves: Vec<String> = line.iter().map(|mv| mv.to_usi_owned()).collect();
+                        println!("checkmate {}", moves.join(" "));
+                    },
+                    None if result.mate_timeout => println!("checkmate timeout"),
+                    None => println!("checkmate nomate"),
+                }
+                return true;
+            }
+
+            let (white, black) = result.score;
+            let score = match result.mate_plies {
+                Some(plies) => format!("mate {}", plies),
+                None if sfen::get_color(&sfen) == Color::Black => format!("cp {}", (black - white) as i32),
+                None => format!("cp {}", (white - black) as i32),
+            };
+            println!("info depth {} nodes {} time {} score {}", result.depth, result.nodes, result.time.as_millis(), score);
+            let cache = result.eval_cache;
+            println!("info string evalcache hits {} probes {} hitrate {:.1}%", cache.hits, cache.probes, cache.hit_rate());
+
+            match (result.best_move, result.ponder_move) {
+                (Some(mv), Some(reply)) => println!("bestmove {} ponder {}", mv.to_usi_owned(), reply.to_usi_owned()),
+                (Some(mv), None) => println!("bestmove {}", mv.to_usi_owned()),
+                _ => println!("bestmove resign"),
+            }
+            true
+        });
+
+        SearchThread { stop, pondering, silent, infinite, handle }
+    }
+
+    fn ponderhit(&self) {
+        self.pondering.store(false, Ordering::Relaxed);
+    }
+
+    // ends the search, returns whether it answered
+    fn stop(self) -> bool {
+        self.stop.store(true, Ordering::Relaxed);
+        self.handle.join().unwrap_or(false)
+    }
+
+    // ends the search without an answer (after `gameover` nobody is waiting for one)
+    fn abandon(self) {
+        self.silent.store(true, Ordering::Relaxed);
+        self.stop();
+    }
+}
+
+
+// where the piece square tables come from, set by PstFile and PstFileSymmetry
+#[derive(Default)]
+struct PstOptions {
+    file: String,     // empty for the built-in tables
+    symmetric: bool,
+}
+
+
+impl PstOptions {
+
+    // loads the tables these options describe and makes them the engine's
+    fn apply(&self) -> Result<(), String> {
+        let tables = if self.file.is_empty() {
+            PieceSquareTables::builtin()
+        } else {
+            PieceSquareTables::load(&self.file).map_err(|e| format!("PstFile {}: {}", self.file, e))?
+        };
+        pst::set_tables(if self.symmetric { tables.symmetric_files() } else { tables });
+        Ok(())
+    }
+}
+
+
+// Handles `setoption name <id> value <x>`. Options we don't know (USI_Ponder
+// included, pondering is driven by `go ponder`) are ignored.
+fn set_option(args: &[&str], pst_options: &mut PstOptions) -> Result<(), String> {
+
+    let value_at = args.iter().position(|&t| t == "value").unwrap_or(args.len());
+    if args.first() != Some(&"name") {
+        return Err("expected setoption name <id> [value <x>]".to_string());
+    }
+    let name = args[1..value_at].join(" ");
+    let value = args.get(value_at + 1..).unwrap_or_default().join(" ");
+
+    // the table options are only kept once the tables they describe have loaded
+    let pst_change = match name.as_str() {
+        "PstFile" => Some(PstOptions { file: if value == "<empty>" { String::new() } else { value.clone() }, ..*pst_options }),
+        "PstFileSymmetry" => Some(PstOptions { symmetric: value == "true", file: pst_options.file.clone() }),
+        _ => None,
+    };
+    if let Some(change) = pst_change {
+        change.apply()?;
+        *pst_options = change;
+        return Ok(());
+    }
+
+    if name == "SafeMobility" {
+        eval::set_safe_mobility(value == "true");
+        return Ok(());
+    }
+
+    if name == "NnueFile" {
+        let loaded = if value == "<empty>" { None } else { Some(Network::load(&value).map_err(|e| format!("NnueFile {}: {}", value, e))?) };
+        nnue::set_network(loaded);
+        return Ok(());
+    }
+
+    // a whole weights file, or one weight by its name
+    if name == "EvalFile" {
+        let loaded = if value == "<empty>" { Ok(TaperedParams::default()) } else { TaperedParams::load(&value) };
+        params::set(loaded.map_err(|e| format!("EvalFile {}: {}", value, e))?);
+        return Ok(());
+    }
+    // a plain weight name sets both phases
+    if !TaperedParams::names().contains(&name) && !EvalParams::names().contains(&name) {
+        return Ok(());
+    }
+    let weight = value.parse::<f32>().map_err(|_| format!("invalid value for {}: {}", name, value))?;
+    let mut changed = params::current();
+    changed.set(&name, weight).map_err(|e| e.to_string())?;
+    params::set(changed);
+    Ok(())
+}
+
+
+// converts a USI square like "7g" into a Square
+fn parse_square(file: char, rank: char) -> Option<Square> {
+    let file = file.to_digit(10)? as u8;
+    if !('a'..='i').contains(&rank) {
+        return None;
+    }
+    let rank = rank as u8 - b'a' + 1;
+    Square::new(file, rank)
+}
+
+
+// converts a USI move like "7g7f", "8h2b+" or "P*5e" into a Move for the side to move in `pos`
+pub fn parse_usi_move(pos: &PartialPosition, text: &str) -> Option<Move> {
+
+    let chars: Vec<char> = text.chars().collect();
+    if chars.len() < 4 {
+        return None;
+    }
+
+    if chars[1] == '*' {
+        let kind = match chars[0] {
+            'P' => PieceKind::Pawn,
+            'L' => PieceKind::Lance,
+            'N' => PieceKind::Knight,
+            'S' => PieceKind::Silver,
+            'G' => PieceKind::Gold,
+            'B' => PieceKind::Bishop,
+            'R' => PieceKind::Rook,
+            _ => return None,
+        };
+        let to = parse_square(chars[2], chars[3])?;
+        return Some(Move::Drop { piece: Piece::new(kind, pos.side_to_move()), to });
+    }
+
+    let from = parse_square(chars[0], chars[1])?;
+    let to = parse_square(chars[2], chars[3])?;
+    let promote = chars.get(4) == Some(&'+');
+
+    Some(Move::Normal { from, to, promote })
+}
+
+
+// handles "position [startpos | sfen <sfen>] [moves ...]". A position without the
+// attacker's king is let through here: only a mate search can use it, see check_position.
+fn parse_position(args: &[&str]) -> Result<PartialPosition, String> {
+
+    let moves_at = args.iter().position(|&a| a == "moves").unwrap_or(args.len());
+
+    let mut pos = match args.first() {
+        Some(&"startpos") => PartialPosition::startpos(),
+        Some(&"sfen") => match sfen::parse(&args[1..moves_at].join(" ")) {
+            Ok(pos) => match validate::validate(&pos, ValidateOptions { tsume: true }) {
+                Ok(()) => pos.to_partial(),
+                Err(violations) => return Err(format!("invalid position: {}", validate::describe(&violations))),
+            },
+            Err(err) => return Err(format!("invalid sfen: {}", err)),
+        },
+        _ => return Err("expected startpos or sfen".to_string()),
+    };
+
+    for text in args.iter().skip(moves_at + 1) {
+        let played = parse_usi_move(&pos, text).and_then(|mv| pos.make_move(mv));
+        if played.is_none() {
+            return Err(format!("invalid move: {}", text));
+        }
+    }
+
+    Ok(pos)
+}
+
+
+// can the search `limits` ask for run on `pos`? A tsume position only suits `go mate`.
+fn check_position(pos: &PartialPosition, limits: &SearchLimits) -> Result<(), String> {
+    let options = ValidateOptions { tsume: limits.mate.is_some() };
+    validate::validate(&Position::from_partial(pos), options)
+        .map_err(|violations| format!("invalid position: {}", validate::describe(&violations)))
+}
+
+
+pub fn run() {
+
+    // None after a rejected `position`, so `go` can't search the previous one by mistake
+    let mut pos = Some(PartialPosition::startpos());
+    let mut search: Option<SearchThread> = None;
+    let mut pst_options = PstOptions::default();
+
+    for line in io::stdin().lock().lines() {
+
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+
+        match tokens.first().copied() {
+            Some("usi") => {
+                println!("id name {}", ENGINE_NAME);
+                println!("id author {}", ENGINE_AUTHOR);
+                println!("option name USI_Ponder type check default true");
+                println!("option name EvalFile type string default <empty>");
+                for (name, default) in TaperedParams::default().fields() {
+                    println!("option name {} type string default {}", name, default);
+                }
+                println!("option name PstFile type string default <empty>");
+                println!("option name PstFileSymmetry type check default false");
+                println!("option name SafeMobility type check default false");
+                println!("option name NnueFile type string default <empty>");
+                println!("usiok");
+            },
+            Some("isready") => println!("readyok"),
+            Some("setoption") => {
+                if let Err(e) = set_option(&tokens[1..], &mut pst_options) {
+                    println!("info string {}", e);
+                }
+            },
+            Some("usinewgame") => pos = Some(PartialPosition::startpos()),
+            Some("position") => {
+                pos = match parse_position(&tokens[1..]) {
+                    Ok(new_pos) => Some(new_pos),
+                    Err(e) => {
+                        println!("info string {}", e);
+                        None
+                    },
+                };
+            },
+            Some("go") => {
+                if let Some(old) = search.take() {
+                    old.stop();
+                }
+                let ponder = tokens.contains(&"ponder");
+                let limits = SearchLimits::parse(&tokens[1..]);
+                let checked = limits.and_then(|limits| match &pos {
+                    Some(pos) => check_position(pos, &limits).map(|()| (pos, limits)),
+                    None => Err("no position to search, the last one was rejected".to_string()),
+                });
+                match checked {
+                    Ok((pos, limits)) => search = Some(SearchThread::start(pos, limits, ponder)),
+                    Err(e) => {
+                        // the GUI still waits for an answer
+                        println!("info string {}", e);
+                        println!("bestmove resign");
+                    },
+                }
+            },
+            Some("ponderhit") => {
+                if let Some(s) = &search {
+                    s.ponderhit();
+                }
+            },
+            Some("stop") => {
+                if let Some(s) = search.take() {
+                    s.stop();
+                }
+            },
+            Some("gameover") => {
+                if let Some(s) = search.take() {
+                    s.abandon();
+                }
+            },
+            Some("quit") => {
+                if let Some(s) = search.take() {
+                    s.stop();
+                }
+                break;
+            },
+            _ => {},
+        }
+    }
+
+    // input closed: let a running search finish and report its move
+    if let Some(s) = search.take() {
+        if s.infinite {
+            s.stop();
+        } else {
+            s.ponderhit();
+            let _ = s.handle.join();
+        }
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the pondering state machine: no answer before ponderhit or stop, none at all after gameover
+    #[test]
+    fn ponder_answers_after_ponderhit_or_stop_only() {
+        let pos = PartialPosition::startpos();
+
+        let search = SearchThread::start(&pos, SearchLimits::depth(1), true);
+        thread::sleep(Duration::from_millis(200));
+        assert!(!search.handle.is_finished(), "answered while pondering");
+        search.ponderhit();
+        assert!(search.handle.join().unwrap());
+
+        // a missed prediction: the GUI stops the ponder search, which still answers
+        let search = SearchThread::start(&pos, SearchLimits { infinite: true, ..Default::default() }, true);
+        thread::sleep(Duration::from_millis(50));
+        assert!(search.stop());
+
+        let search = SearchThread::start(&pos, SearchLimits::depth(1), true);
+        search.abandon();
+    }
+
+    // the time spent pondering isn't taken off the movetime
+    #[test]
+    fn movetime_starts_at_ponderhit() {
+        let movetime = Duration::from_millis(300);
+        let limits = SearchLimits { movetime: Some(movetime), ..Default::default() };
+        let search = SearchThread::start(&PartialPosition::startpos(), limits, true);
+        thread::sleep(Duration::from_millis(400));
+
+        let hit = std::time::Instant::now();
+        search.ponderhit();
+        assert!(search.handle.join().unwrap());
+        assert!(hit.elapsed() >= movetime * 9 / 10, "answered {:?} after ponderhit", hit.elapsed());
+    }
+
+    // a tsume problem is only good for `go mate`, a broken position for nothing
+    #[test]
+    fn positions_are_checked_against_the_search() {
+        let tsume = parse_position(&["sfen", "4k4/9/4G4/9/9/9/9/9/9", "b", "G", "1"]).unwrap();
+        assert!(check_position(&tsume, &SearchLimits::parse(&["mate", "infinite"]).unwrap()).is_ok());
+        assert!(check_position(&tsume, &SearchLimits::depth(1)).is_err());
+
+        assert!(parse_position(&["sfen", "4k4/9/4G4/9/9/9/9/9/9", "w", "G", "1"]).is_err());
+        assert!(parse_position(&["startpos", "moves", "7g7f", "7g7f"]).is_err());
+    }
+}
@@ -0,0 +1,184 @@
+/* Position sanity checks
+ *
+ * The SFEN parser only checks that a position is well formed. A well formed
+ * position can still be impossible: two black kings, 19 pawns, two unpromoted
+ * pawns on one file, a lance that can never move again, or the side that just
+ * moved standing in check. validate lists every such problem at once, so a
+ * bad position can be fixed in one go instead of one complaint at a time.
+ *
+ * Tsume problems leave out the attacker's king (the attacker is the side to
+ * move), which ValidateOptions::tsume allows.
+ */
+
+use crate::attacks::attackers_to;
+use crate::position::{Position, HAND_KINDS};
+use shogi_core::{Color, Piece, PieceKind, Square, ToUsi};
+use std::fmt;
+
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ValidateOptions {
+    pub tsume: bool,  // the side to move may have no king
+}
+
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Violation {
+    TooManyPieces { kind: PieceKind, count: u32, limit: u32 },  // board and hands together
+    MissingKing(Color),
+    ExtraKings { color: Color, count: u32 },
+    DoublePawn { color: Color, file: u8, count: u32 },         // nifu
+    DeadPiece { square: Square, piece: Piece },                // can never move again
+    OpponentInCheck,                                           // the side not to move is in check
+}
+
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Violation::TooManyPieces { kind, count, limit } => write!(f, "{} {:?} pieces, a set has {}", count, kind, limit),
+            Violation::MissingKing(color) => write!(f, "{:?} has no king", color),
+            Violation::ExtraKings { color, count } => write!(f, "{:?} has {} kings", color, count),
+            Violation::DoublePawn { color, file, count } => write!(f, "{:?} has {} pawns on file {}", color, count, file),
+            Violation::DeadPiece { square, piece } => write!(f, "{:?} {:?} on {} can never move", piece.color(), piece.piece_kind(), square.to_usi_owned()),
+            Violation::OpponentInCheck => write!(f, "the side not to move is in check"),
+        }
+    }
+}
+
+
+// pieces of each (unpromoted) kind in a shogi set
+fn set_count(kind: PieceKind) -> u32 {
+    match kind {
+        PieceKind::Pawn => 18,
+        PieceKind::Bishop | PieceKind::Rook => 2,
+        PieceKind::King => 2,
+        _ => 4,
+    }
+}
+
+
+// can a piece of this kind on this square still move (as far as the rules allow it to stand there)?
+fn is_dead(piece: Piece, square: Square) -> bool {
+    let rank = square.relative_rank(piece.color());
+    match piece.piece_kind() {
+        PieceKind::Pawn | PieceKind::Lance => rank == 1,
+        PieceKind::Knight => rank <= 2,
+        _ => false,
+    }
+}
+
+
+// Every rule `pos` breaks, grouped by rule. Ok when there are none.
+pub fn validate(pos: &Position, options: ValidateOptions) -> Result<(), Vec<Violation>> {
+
+    let mut violations = Vec::new();
+
+    // piece counts: board (promoted pieces count as what they promoted from) plus hands
+    for kind in HAND_KINDS.iter().copied().chain([PieceKind::King]) {
+        let mut count = 0;
+        for square in pos.occupied() {
+            let on_board = pos.piece_at(square).expect("occupied square").piece_kind();
+            if on_board.unpromote().unwrap_or(on_board) == kind {
+                count += 1;
+            }
+        }
+        if kind != PieceKind::King {
+            count += Color::all().iter().map(|&color| pos.hand(color, kind) as u32).sum::<u32>();
+        }
+        if count > set_count(kind) {
+            violations.push(Violation::TooManyPieces { kind, count, limit: set_count(kind) });
+        }
+    }
+
+    // kings
+    for color in Color::all() {
+        let count = pos.piece_bitboard(Piece::new(PieceKind::King, color)).count();
+        let may_be_missing = options.tsume && color == pos.side_to_move();
+        if count == 0 && !may_be_missing {
+            violations.push(Violation::MissingKing(color));
+        }
+        if count > 1 {
+            violations.push(Violation::ExtraKings { color, count });
+        }
+    }
+
+    // nifu
+    for color in Color::all() {
+        let mut pawns = [0; 10];
+        for square in pos.piece_bitboard(Piece::new(PieceKind::Pawn, color)) {
+            pawns[square.file() as usize] += 1;
+        }
+        for file in 1..=9 {
+            let count = pawns[file as usize];
+            if count > 1 {
+                violations.push(Violation::DoublePawn { color, file, count });
+            }
+        }
+    }
+
+    // pieces that can't move any more
+    for square in pos.occupied() {
+        let piece = pos.piece_at(square).expect("occupied square");
+        if is_dead(piece, square) {
+            violations.push(Violation::DeadPiece { square, piece });
+        }
+    }
+
+    // the side that just moved can't have left its king in check
+    let side = pos.side_to_move();
+    if let Some(king) = pos.king_square(side.flip()) {
+        if !attackers_to(pos, king, side, pos.occupied()).is_empty() {
+            violations.push(Violation::OpponentInCheck);
+        }
+    }
+
+    if violations.is_empty() { Ok(()) } else { Err(violations) }
+}
+
+
+// one line listing all violations, for error messages
+pub fn describe(violations: &[Violation]) -> String {
+    violations.iter().map(|v| v.to_string()).collect::<Vec<String>>().join("; ")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sfen;
+
+    fn violations(sfen: &str, options: ValidateOptions) -> Vec<Violation> {
+        validate(&sfen::parse(sfen).unwrap(), options).err().unwrap_or_default()
+    }
+
+    #[test]
+    fn start_position_is_valid() {
+        assert_eq!(violations("lnsgkgsnl/1r5b1/ppppppppp/9/9/9/PPPPPPPPP/1B5R1/LNSGKGSNL b - 1", ValidateOptions::default()), vec![]);
+    }
+
+    #[test]
+    fn reports_every_violation() {
+        // two black kings, no white king, nifu on file 5, a lance on the last rank, 19 pawns
+        let found = violations("L8/9/9/9/4P4/9/4P4/9/3KK4 b 17p 1", ValidateOptions::default());
+        assert_eq!(found, vec![
+            Violation::TooManyPieces { kind: PieceKind::Pawn, count: 19, limit: 18 },
+            Violation::ExtraKings { color: Color::Black, count: 2 },
+            Violation::MissingKing(Color::White),
+            Violation::DoublePawn { color: Color::Black, file: 5, count: 2 },
+            Violation::DeadPiece { square: Square::SQ_9A, piece: Piece::B_L },
+        ]);
+    }
+
+    #[test]
+    fn side_not_to_move_in_check() {
+        assert_eq!(violations("4k4/9/9/9/4R4/9/9/9/4K4 b - 1", ValidateOptions::default()), vec![Violation::OpponentInCheck]);
+    }
+
+    #[test]
+    fn tsume_attacker_needs_no_king() {
+        let sfen = "3g1ks2/6g2/4S4/7B1/9/9/9/9/9 b G 1";
+        assert_eq!(violations(sfen, ValidateOptions::default()), vec![Violation::MissingKing(Color::Black)]);
+        assert_eq!(violations(sfen, ValidateOptions { tsume: true }), vec![]);
+    }
+}
@@ -1,6 +1,12 @@
+use crate::sfen;
 use colored::Colorize;
 
 pub fn display_sfen(sfen: &str) {
+    // everything below relies on the board being well formed
+    if let Err(err) = sfen::parse(sfen) {
+        println!(" | invalid sfen: {}", err);
+        return;
+    }
     let parts: Vec<&str> = sfen.split(' ').collect();
     let board_layout = parts[0];
     //let ranks = [" |            A", " |            B", " |            C", " |            D", " |            E", " |            F", " |            G", " |            H", " |            I"];
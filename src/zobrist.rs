@@ -0,0 +1,94 @@
+/* Zobrist hashing
+ *
+ * A position's key is the XOR of one random number per (piece, square) on the
+ * board, one per (colour, kind, count) in hand and one more when white is to
+ * move. Every board or hand edit changes the key by XORing the old and the new
+ * number in and out, so Position keeps it up to date as it goes, the same way
+ * it keeps the running eval sums.
+ *
+ * The numbers come from a fixed seed, keys are the same on every run.
+ */
+
+use crate::position::{Position, HAND_KINDS};
+use shogi_core::{Color, Piece, PieceKind, Square};
+use std::sync::OnceLock;
+
+
+// Piece::as_u8 runs 1..=14 (black) and 17..=30 (white)
+const PIECE_SLOTS: usize = 31;
+// no kind can have more than 18 pieces in one hand
+const MAX_HAND: usize = 18;
+
+
+struct Keys {
+    pieces: [[u64; 81]; PIECE_SLOTS],
+    hands: [[[u64; MAX_HAND + 1]; 7]; 2],
+    white_to_move: u64,
+}
+
+
+// splitmix64, good enough to spread the keys and needs no crate
+fn next_random(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+
+fn keys() -> &'static Keys {
+    static KEYS: OnceLock<Keys> = OnceLock::new();
+    KEYS.get_or_init(|| {
+        let mut state = 0x5eed_5040_6153_0001;
+        let mut keys = Keys { pieces: [[0; 81]; PIECE_SLOTS], hands: [[[0; MAX_HAND + 1]; 7]; 2], white_to_move: 0 };
+        for piece in keys.pieces.iter_mut() {
+            for key in piece.iter_mut() {
+                *key = next_random(&mut state);
+            }
+        }
+        // an empty hand adds nothing, so a position without pieces in hand hashes like the board alone
+        for color in keys.hands.iter_mut() {
+            for kind in color.iter_mut() {
+                for key in kind.iter_mut().skip(1) {
+                    *key = next_random(&mut state);
+                }
+            }
+        }
+        keys.white_to_move = next_random(&mut state);
+        keys
+    })
+}
+
+
+pub fn piece_key(piece: Piece, square: Square) -> u64 {
+    keys().pieces[piece.as_u8() as usize][square.array_index()]
+}
+
+pub fn hand_key(color: Color, kind: PieceKind, count: u8) -> u64 {
+    keys().hands[color.array_index()][kind.array_index()][count as usize]
+}
+
+pub fn side_key() -> u64 {
+    keys().white_to_move
+}
+
+
+// the key of `pos` from scratch, what Position::key has to agree with
+pub fn compute(pos: &Position) -> u64 {
+    let mut key = 0;
+    for square in pos.occupied() {
+        if let Some(piece) = pos.piece_at(square) {
+            key ^= piece_key(piece, square);
+        }
+    }
+    for color in Color::all() {
+        for kind in HAND_KINDS {
+            key ^= hand_key(color, kind, pos.hand(color, kind));
+        }
+    }
+    if pos.side_to_move() == Color::White {
+        key ^= side_key();
+    }
+    key
+}